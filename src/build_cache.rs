@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::config::VortexConfig;
+use crate::error::*;
+use crate::export_data::*;
+use crate::export_types::FileMetadata;
+use crate::ordering::CollectionOrder;
+
+// Written next to a collection's toolpath, records what it was built from so unchanged collections can be reused
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildRecord {
+    pub hash: String,
+    pub toolpath: bool, // which files were written by the build
+    pub preview: bool,
+    pub metadata: FileMetadata,
+    pub order: CollectionOrder,
+}
+
+impl BuildRecord {
+    pub fn load(path: &Path) -> Option<BuildRecord> {
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn write(&self, path: &Path) -> VortexResult<()> {
+        export_json(path, self)
+    }
+
+    // The stored metadata, marked as reused, with the order restored for the next frame to follow
+    pub fn into_metadata(self) -> FileMetadata {
+        FileMetadata {
+            reused: true,
+            order: self.order,
+            ..self.metadata
+        }
+    }
+}
+
+// Hash everything which affects a collection's output: the tool version, effective settings,
+// the Blender JSON and SVG files, the UV maps they reference and the previous frame's order when it's followed.
+pub fn collection_hash(
+    folder: &Path,
+    config: &VortexConfig,
+    previous: Option<&CollectionOrder>,
+) -> VortexResult<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hash_json(&mut hasher, config)?;
+
+    // Sorted so the hash doesn't depend on the order the filesystem lists files in
+    let inputs: Vec<_> = WalkDir::new(folder)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|v| v.ok())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy();
+            name.ends_with(".json") || name.ends_with(".svg")
+        })
+        .map(|e| e.into_path())
+        .collect();
+
+    for path in inputs {
+        let contents = fs::read(&path).map_err(|e| VortexError::io(&path, e))?;
+        hash_file(&mut hasher, &path, Some(&contents));
+
+        if let Some(uv_path) = referenced_uv(&contents) {
+            let uv_path = folder.join(uv_path);
+            hash_file(&mut hasher, &uv_path, fs::read(&uv_path).ok().as_deref());
+        }
+    }
+
+    if let Some(order) = previous {
+        hash_json(&mut hasher, order)?;
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_json<T: Serialize>(hasher: &mut Sha256, value: &T) -> VortexResult<()> {
+    let bytes = serde_json::to_vec(value)
+        .map_err(|e| VortexError::Config(format!("Couldn't hash settings: {}", e)))?;
+    hasher.update(&bytes);
+
+    Ok(())
+}
+
+// Missing files are hashed too, so a UV map appearing later triggers a rebuild
+fn hash_file(hasher: &mut Sha256, path: &Path, contents: Option<&[u8]>) {
+    hasher.update(
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .as_bytes(),
+    );
+    match contents {
+        Some(bytes) => {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+        None => hasher.update(b"missing"),
+    }
+}
+
+fn referenced_uv(contents: &[u8]) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_slice(contents).ok()?;
+    json.get("uv")?.as_str().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A collection folder with one spline and its UV map
+    fn collection(name: &str) -> std::path::PathBuf {
+        let folder = std::env::temp_dir().join(format!("vortex-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(
+            folder.join("line.json"),
+            r#"{"type": "poly", "uv": "line.png"}"#,
+        )
+        .unwrap();
+        fs::write(folder.join("line.png"), b"uv").unwrap();
+        folder
+    }
+
+    #[test]
+    fn hashes_change_with_settings_and_inputs() {
+        let folder = collection("hash");
+        let config = VortexConfig::default();
+        let hash = || collection_hash(&folder, &config, None).unwrap();
+        let original = hash();
+        assert_eq!(hash(), original);
+
+        let mut faster = VortexConfig::default();
+        faster.planner.movement_speed *= 2.0;
+        assert_ne!(collection_hash(&folder, &faster, None).unwrap(), original);
+
+        fs::write(folder.join("line.png"), b"new uv").unwrap();
+        let new_uv = hash();
+        assert_ne!(new_uv, original);
+
+        fs::write(
+            folder.join("line.json"),
+            r#"{"type": "poly", "uv": "line.png"} "#,
+        )
+        .unwrap();
+        assert_ne!(hash(), new_uv);
+
+        // Files the planner doesn't read are ignored
+        let edited = hash();
+        fs::write(folder.join("notes.txt"), b"notes").unwrap();
+        assert_eq!(hash(), edited);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn hashes_change_with_the_order_followed() {
+        let folder = collection("hash-order");
+        let config = VortexConfig::default();
+        let order = CollectionOrder::default();
+
+        assert_ne!(
+            collection_hash(&folder, &config, Some(&order)).unwrap(),
+            collection_hash(&folder, &config, None).unwrap()
+        );
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
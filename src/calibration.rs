@@ -0,0 +1,327 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use colorsys::{Hsl, Rgb};
+use serde::{Deserialize, Serialize};
+
+use crate::color_utils::linear_rgb_from_hsl;
+use crate::config::VortexConfig;
+use crate::delta_utils::distance_3d;
+use crate::error::*;
+use crate::export_data::export_json;
+use crate::import_types::*;
+use crate::pipeline::{plan_collection, write_toolpath};
+
+// Drive levels of the white patches used to find the dimmest light the LED visibly makes
+const CUTOFF_LEVELS: [f64; 6] = [0.005, 0.01, 0.02, 0.03, 0.05, 0.08];
+
+// Patches are filled in with this many passes of the effector
+const PATCH_PASSES: usize = 6;
+
+/// How the LED responds to the colours it's asked for, measured from photos of the test patches.
+///
+/// Colours are corrected in linear RGB by the colour matrix, then each channel's drive level is
+/// raised to the inverse of its gamma. Colours whose brightest channel would be driven below
+/// `min_brightness` are switched off, as the LED can't show them reliably.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LedProfile {
+    pub gamma: [f64; 3], // red, green and blue light is the drive level to this power
+    pub color_matrix: [[f64; 3]; 3], // rows give each channel's drive from the wanted linear RGB
+    pub min_brightness: f64, // drive level between 0 and 1
+}
+
+impl Default for LedProfile {
+    fn default() -> Self {
+        LedProfile {
+            gamma: [2.2; 3],
+            color_matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            min_brightness: 0.0,
+        }
+    }
+}
+
+impl LedProfile {
+    pub fn validate(&self) -> VortexResult<()> {
+        if self.gamma.iter().any(|&g| g <= 0.0) {
+            return Err(VortexError::Config(
+                "LED profile gammas must be positive".to_string(),
+            ));
+        }
+
+        if !(0.0..1.0).contains(&self.min_brightness) {
+            return Err(VortexError::Config(
+                "LED profile min_brightness must be at least 0 and below 1".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The colour to ask the LED for, so it shows the wanted colour.
+    pub fn drive(&self, color: &Hsl) -> Hsl {
+        let wanted = linear_rgb_from_hsl(color);
+        let corrected = self.color_matrix.map(|row| {
+            row.iter()
+                .zip(&wanted)
+                .map(|(weight, channel)| weight * channel)
+                .sum::<f64>()
+        });
+
+        let drive = [0, 1, 2].map(|c| corrected[c].clamp(0.0, 1.0).powf(1.0 / self.gamma[c]));
+
+        // Keep the hue when switching off, so fades to and from black don't change colour
+        if drive.iter().all(|&d| d < self.min_brightness) {
+            return Hsl::new(
+                color.get_hue(),
+                color.get_saturation(),
+                0.0,
+                Option::from(1.0),
+            );
+        }
+
+        Hsl::from(&Rgb::new(
+            drive[0] * 255.0,
+            drive[1] * 255.0,
+            drive[2] * 255.0,
+            None,
+        ))
+    }
+}
+
+/// A square of the test toolpath, lit with a known drive level on each channel.
+#[derive(Serialize, Debug, Clone)]
+pub struct Patch {
+    pub name: String,
+    pub drive: [f64; 3], // red, green and blue between 0 and 1, as sent to the LED
+    pub center: [f32; 3],
+}
+
+/// The files written by [`write_test_patches`].
+pub struct TestPatches {
+    pub toolpath: PathBuf,
+    pub patches: PathBuf,
+}
+
+/// Write a toolpath which paints a grid of colour patches, for measuring an [`LedProfile`].
+///
+/// Each channel and white get a row of patches ramping up over `levels` steps to measure the gamma
+/// curves, followed by a row of full secondary colours for the colour matrix and a row of very dim
+/// whites for the brightness cutoff. Patches are drawn with the LED colours as they are, ignoring any profile
+/// already set or speed compensation. `patches.json` lists each patch's drive levels and where its center is.
+pub fn write_test_patches(
+    folder: &Path,
+    config: &VortexConfig,
+    levels: usize,
+    size: f32,
+) -> VortexResult<TestPatches> {
+    config.validate()?;
+    if levels == 0 || size <= 0.0 {
+        return Err(VortexError::Config(
+            "Test patches need at least one level and a positive size".to_string(),
+        ));
+    }
+
+    let mut rows: Vec<Vec<(String, [f64; 3])>> = vec![];
+    for (name, channels) in [
+        ("red", [1.0, 0.0, 0.0]),
+        ("green", [0.0, 1.0, 0.0]),
+        ("blue", [0.0, 0.0, 1.0]),
+        ("white", [1.0, 1.0, 1.0]),
+    ] {
+        rows.push(
+            (1..=levels)
+                .map(|level| {
+                    let drive = level as f64 / levels as f64;
+                    (
+                        format!("{} {}/{}", name, level, levels),
+                        channels.map(|c| c * drive),
+                    )
+                })
+                .collect(),
+        );
+    }
+    rows.push(vec![
+        ("cyan".to_string(), [0.0, 1.0, 1.0]),
+        ("magenta".to_string(), [1.0, 0.0, 1.0]),
+        ("yellow".to_string(), [1.0, 1.0, 0.0]),
+    ]);
+    rows.push(
+        CUTOFF_LEVELS
+            .iter()
+            .map(|&drive| (format!("white {}", drive), [drive; 3]))
+            .collect(),
+    );
+
+    // Lay the rows out top to bottom, centred in the workspace halfway up
+    let pitch = size * 1.5;
+    let columns = rows.iter().map(|row| row.len()).max().unwrap_or(1);
+    let row_count = rows.len();
+    let workspace = &config.workspace;
+    let z = (workspace.z_min + workspace.z_max) / 2.0;
+
+    let mut patches = vec![];
+    let mut splines = vec![];
+    for (r, row) in rows.into_iter().enumerate() {
+        for (c, (name, drive)) in row.into_iter().enumerate() {
+            let center = [
+                workspace.center_x + (c as f32 - (columns - 1) as f32 / 2.0) * pitch,
+                workspace.center_y - (r as f32 - (row_count - 1) as f32 / 2.0) * pitch,
+                z,
+            ];
+
+            splines.push(patch_spline(center, size, drive));
+            patches.push(Patch {
+                name,
+                drive,
+                center,
+            });
+        }
+    }
+
+    // The patches are measured as they are, so the planner mustn't correct or dim their colours
+    let mut config = config.clone();
+    config.planner.led_profile = None;
+    config.planner.speed_compensation = false;
+    let events = plan_collection(&splines, &config)?;
+
+    fs::create_dir_all(folder).map_err(|e| VortexError::io(folder, e))?;
+    let files = TestPatches {
+        toolpath: folder.join("patches_toolpath.json"),
+        patches: folder.join("patches.json"),
+    };
+    write_toolpath(&files.toolpath, events)?;
+    export_json(&files.patches, &patches)?;
+
+    Ok(files)
+}
+
+// Zigzag across the square, so the exposure fills it in
+fn patch_spline(center: [f32; 3], size: f32, drive: [f64; 3]) -> BlenderData {
+    let half = size / 2.0;
+    let mut points = vec![];
+    for pass in 0..=PATCH_PASSES {
+        let y = center[1] - half + size * pass as f32 / PATCH_PASSES as f32;
+        let (from, to) = if pass % 2 == 0 {
+            (-half, half)
+        } else {
+            (half, -half)
+        };
+
+        for x in [from, to] {
+            points.push(BlenderPoint4 {
+                x: center[0] + x,
+                y,
+                z: center[2],
+                w: 1.0,
+            });
+        }
+    }
+
+    let curve_length = points
+        .windows(2)
+        .map(|p| distance_3d(&p[0].into_bp3(), &p[1].into_bp3()))
+        .sum();
+    let color = Hsl::from(&Rgb::new(
+        drive[0] * 255.0,
+        drive[1] * 255.0,
+        drive[2] * 255.0,
+        None,
+    ));
+
+    BlenderData::PolySpline(BlenderPoly {
+        curve_length,
+        points,
+        cyclic: false,
+        uv_path: String::new(),
+        color: vec![color.clone(), color],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_utils::hsl_from_linear_rgb;
+
+    fn drive_levels(color: &Hsl) -> [f64; 3] {
+        let rgb = Rgb::from(color);
+        [rgb.get_red(), rgb.get_green(), rgb.get_blue()].map(|c| c / 255.0)
+    }
+
+    fn close(a: [f64; 3], b: [f64; 3]) -> bool {
+        a.iter().zip(&b).all(|(x, y)| (x - y).abs() < 0.01)
+    }
+
+    #[test]
+    fn primaries_and_white_are_unchanged_by_default() {
+        let profile = LedProfile::default();
+
+        for drive in [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0; 3]] {
+            let color = Hsl::from(&Rgb::new(
+                drive[0] * 255.0,
+                drive[1] * 255.0,
+                drive[2] * 255.0,
+                None,
+            ));
+            assert!(close(drive_levels(&profile.drive(&color)), drive));
+        }
+    }
+
+    #[test]
+    fn drive_is_the_inverse_of_each_gamma() {
+        let profile = LedProfile {
+            gamma: [2.0, 1.0, 4.0],
+            ..LedProfile::default()
+        };
+
+        let quarter_light = hsl_from_linear_rgb([0.25; 3]);
+        let drive = drive_levels(&profile.drive(&quarter_light));
+
+        assert!(close(drive, [0.5, 0.25, 0.25_f64.powf(0.25)]));
+    }
+
+    #[test]
+    fn color_matrix_corrects_the_white_point() {
+        let profile = LedProfile {
+            gamma: [1.0; 3],
+            color_matrix: [[1.0, 0.0, 0.0], [0.0, 0.5, 0.0], [0.0, 0.0, 1.0]],
+            ..LedProfile::default()
+        };
+
+        let white = Hsl::new(0.0, 0.0, 100.0, None);
+        assert!(close(drive_levels(&profile.drive(&white)), [1.0, 0.5, 1.0]));
+    }
+
+    #[test]
+    fn colours_below_the_cutoff_are_switched_off() {
+        let profile = LedProfile {
+            min_brightness: 0.1,
+            ..LedProfile::default()
+        };
+
+        let dim_blue = hsl_from_linear_rgb([0.0, 0.0, 0.001]);
+        let off = profile.drive(&dim_blue);
+        assert_eq!(off.get_lightness(), 0.0);
+        assert!((off.get_hue() - dim_blue.get_hue()).abs() < 1e-9);
+
+        let bright_blue = hsl_from_linear_rgb([0.0, 0.0, 0.5]);
+        assert!(profile.drive(&bright_blue).get_lightness() > 0.0);
+    }
+
+    #[test]
+    fn invalid_profiles_are_rejected() {
+        assert!(LedProfile::default().validate().is_ok());
+
+        let flat = LedProfile {
+            gamma: [2.2, 0.0, 2.2],
+            ..LedProfile::default()
+        };
+        assert!(flat.validate().is_err());
+
+        let always_off = LedProfile {
+            min_brightness: 1.0,
+            ..LedProfile::default()
+        };
+        assert!(always_off.validate().is_err());
+    }
+}
@@ -0,0 +1,446 @@
+use std::path::{Component, Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use toml::value::{Table, Value};
+
+use total_perspective_vortex::{VortexError, VortexResult};
+
+#[derive(Parser, Debug)]
+#[command(name = "total-perspective-vortex", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Plan toolpaths, write preview data and the summary.json
+    Plan(RunArgs),
+    /// Only write the delta-ui preview vertices and UV maps
+    Preview(RunArgs),
+    /// Plan every collection and report problems without writing anything
+    Validate(RunArgs),
+    /// Plan every collection and print the summary without writing anything
+    Summary(RunArgs),
+    /// Plan, then keep replanning collections as Blender re-exports them
+    Watch(WatchArgs),
+    /// Plan, then render each frame as a long exposure photo of the robot drawing it
+    Render(RenderArgs),
+    /// Plan, then sketch every frame into an image sequence, contact sheet and gif
+    Flipbook(FlipbookArgs),
+    /// Write a toolpath of colour patches to photograph for an LED profile
+    Patches(PatchArgs),
+}
+
+impl Command {
+    // The commands which plan an animation
+    pub fn run_args(&self) -> Option<&RunArgs> {
+        match self {
+            Command::Plan(args)
+            | Command::Preview(args)
+            | Command::Validate(args)
+            | Command::Summary(args) => Some(args),
+            Command::Watch(args) => Some(&args.run),
+            Command::Render(args) => Some(&args.run),
+            Command::Flipbook(args) => Some(&args.run),
+            Command::Patches(_) => None,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Folder containing the numbered frame folders exported from Blender
+    #[arg(short, long, default_value = ".")]
+    pub input: PathBuf,
+
+    /// Folder to write generated files into, outside the input folder [default: <input>_vortex next to the input folder]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Project settings file [default: vortex.toml in the working directory, if present]
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// LED profile file, applied to every fade's colours
+    #[arg(long)]
+    pub led_profile: Option<PathBuf>,
+
+    /// Number of collections to plan at once [default: one per CPU]
+    #[arg(short, long, default_value_t = 0, hide_default_value = true)]
+    pub jobs: usize,
+
+    /// Plan every collection again, even when its inputs and settings haven't changed
+    #[arg(long)]
+    pub force: bool,
+
+    #[command(flatten)]
+    pub tuning: TuningArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Milliseconds without changes to wait for an export to finish before replanning
+    #[arg(long, default_value_t = 500)]
+    pub debounce: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct RenderArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Also write a render of each collection next to its toolpath
+    #[arg(long)]
+    pub per_collection: bool,
+
+    /// Image width in pixels [default: 1280]
+    #[arg(long)]
+    pub width: Option<u32>,
+
+    /// Image height in pixels [default: 720]
+    #[arg(long)]
+    pub height: Option<u32>,
+
+    /// Brightness added for each ms the lit LED spends over a pixel [default: 0.5]
+    #[arg(long)]
+    pub exposure: Option<f32>,
+
+    /// Strength of the glow around bright light, 0 disables it [default: 0]
+    #[arg(long)]
+    pub bloom: Option<f32>,
+}
+
+impl RenderArgs {
+    // Express the flags as a [render] table, layered over the vortex.toml
+    pub fn to_settings(&self) -> Table {
+        let mut render = Table::new();
+
+        if let Some(width) = self.width {
+            render.insert("width".into(), Value::Integer(width.into()));
+        }
+        if let Some(height) = self.height {
+            render.insert("height".into(), Value::Integer(height.into()));
+        }
+        if let Some(exposure) = self.exposure {
+            render.insert("exposure".into(), Value::Float(exposure.into()));
+        }
+        if let Some(bloom) = self.bloom {
+            render.insert("bloom_strength".into(), Value::Float(bloom.into()));
+        }
+
+        let mut settings = Table::new();
+        settings.insert("render".into(), Value::Table(render));
+        settings
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct FlipbookArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Frame width in pixels [default: 480]
+    #[arg(long)]
+    pub width: Option<u32>,
+
+    /// Frame height in pixels [default: 270]
+    #[arg(long)]
+    pub height: Option<u32>,
+
+    /// Frames across the contact sheet [default: 6]
+    #[arg(long)]
+    pub columns: Option<usize>,
+
+    /// Milliseconds between frames of the gif [default: 80]
+    #[arg(long)]
+    pub delay: Option<u32>,
+
+    /// Draw each collection in its own colour instead of its lighting
+    #[arg(long)]
+    pub collection_colors: bool,
+
+    /// Also draw the transits between splines
+    #[arg(long)]
+    pub transits: bool,
+}
+
+impl FlipbookArgs {
+    // Express the flags as a [flipbook] table, layered over the vortex.toml
+    pub fn to_settings(&self) -> Table {
+        let mut flipbook = Table::new();
+
+        if let Some(width) = self.width {
+            flipbook.insert("width".into(), Value::Integer(width.into()));
+        }
+        if let Some(height) = self.height {
+            flipbook.insert("height".into(), Value::Integer(height.into()));
+        }
+        if let Some(columns) = self.columns {
+            flipbook.insert("columns".into(), Value::Integer(columns as i64));
+        }
+        if let Some(delay) = self.delay {
+            flipbook.insert("frame_delay_ms".into(), Value::Integer(delay.into()));
+        }
+        if self.collection_colors {
+            flipbook.insert("collection_colors".into(), Value::Boolean(true));
+        }
+        if self.transits {
+            flipbook.insert("show_transits".into(), Value::Boolean(true));
+        }
+
+        let mut settings = Table::new();
+        settings.insert("flipbook".into(), Value::Table(flipbook));
+        settings
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PatchArgs {
+    /// Folder to write the patches toolpath and list into
+    #[arg(short, long, default_value = ".")]
+    pub output: PathBuf,
+
+    /// Project settings file, for the machine and workspace [default: vortex.toml in the working directory, if present]
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Steps in each channel's brightness ramp
+    #[arg(long, default_value_t = 8)]
+    pub levels: usize,
+
+    /// Width of each patch in mm
+    #[arg(long, default_value_t = 10.0)]
+    pub size: f32,
+}
+
+impl RunArgs {
+    // Generated files are kept out of the Blender exports, so later runs and the watcher don't pick them up
+    pub fn output_root(&self) -> VortexResult<PathBuf> {
+        let input = full_path(&self.input);
+
+        let output = match &self.output {
+            Some(output) => full_path(output),
+            None => match (input.parent(), input.file_name()) {
+                (Some(parent), Some(name)) => {
+                    parent.join(format!("{}_vortex", name.to_string_lossy()))
+                }
+                _ => {
+                    return Err(VortexError::Config(format!(
+                        "Choose an --output folder for {}",
+                        input.display()
+                    )))
+                }
+            },
+        };
+
+        if output.starts_with(&input) {
+            return Err(VortexError::Config(format!(
+                "The output folder {} can't be inside the input folder {}",
+                output.display(),
+                input.display()
+            )));
+        }
+
+        Ok(output)
+    }
+}
+
+// An absolute path without any . or .. components, following symlinks where the path already exists
+fn full_path(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut full = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                full.pop();
+            }
+            other => full.push(other),
+        }
+    }
+
+    // Parts of the path which do exist may be symlinks
+    match (full.parent(), full.file_name()) {
+        (Some(parent), Some(name)) => full_path(parent).join(name),
+        _ => full,
+    }
+}
+
+// Overrides for the planner constants. These take priority over the project file
+#[derive(Args, Debug, Clone)]
+pub struct TuningArgs {
+    /// Drawing and transit speed in mm/s [default: 200]
+    #[arg(long)]
+    pub speed: Option<f32>,
+
+    /// Largest colour error allowed along a lighting fade, in ΔE [default: 2.3]
+    #[arg(long)]
+    pub cluster_threshold: Option<f64>,
+
+    /// How colour differences are measured [default: ciede2000]
+    #[arg(long, value_parser = ["cie76", "cie94", "ciede2000", "hsl"])]
+    pub color_metric: Option<String>,
+
+    /// How lighting fades blend between colours [default: hsl]
+    #[arg(long, value_parser = ["hsl", "linear_rgb", "oklab"])]
+    pub fade_space: Option<String>,
+
+    /// Dim strokes where the effector moves slower than the reference speed, so they expose evenly
+    #[arg(long)]
+    pub speed_compensation: bool,
+
+    /// Speed in mm/s which is drawn at full brightness with speed compensation [default: 200]
+    #[arg(long)]
+    pub reference_speed: Option<f32>,
+
+    /// Pause before each particle trail, in milliseconds [default: 10]
+    #[arg(long)]
+    pub point_delay: Option<u32>,
+
+    /// Position of the transit bezier control points along the move, between 0 and 1 [default: 0.01]
+    #[arg(long)]
+    pub transit_shaping: Option<f32>,
+
+    /// Draw splines in the order they were exported, instead of reordering them to reduce transit
+    #[arg(long)]
+    pub keep_order: bool,
+
+    /// Draw splines and particles in the same order as the previous frame
+    #[arg(long)]
+    pub temporal: bool,
+
+    /// How particle trails are ordered [default: greedy]
+    #[arg(long, value_parser = ["greedy", "two_opt", "lin_kernighan", "temporal"])]
+    pub particle_order: Option<String>,
+
+    /// Seed for the particle order, so runs are reproducible [default: random each run]
+    // Capped to fit the project file's integers, so the recorded seed is the one passed
+    #[arg(long, value_parser = clap::value_parser!(u64).range(..=i64::MAX as u64))]
+    pub seed: Option<u64>,
+
+    /// Poly spline points within this many mm of a merged line are dropped, 0 disables [default: 0.1]
+    #[arg(long)]
+    pub simplify_tolerance: Option<f32>,
+
+    /// Velocity profile used to estimate move durations [default: trapezoidal]
+    #[arg(long, value_parser = ["constant", "trapezoidal", "s_curve"])]
+    pub profile: Option<String>,
+
+    /// Maximum acceleration in mm/s^2 [default: 2000]
+    #[arg(long)]
+    pub acceleration: Option<f32>,
+
+    /// Maximum jerk in mm/s^3, used by the s_curve profile [default: 40000]
+    #[arg(long)]
+    pub jerk: Option<f32>,
+
+    /// Junction deviation in mm, larger values corner faster [default: 0.05]
+    #[arg(long)]
+    pub junction_deviation: Option<f32>,
+
+    /// Blender units to millimeters scale factor [default: 100]
+    #[arg(long)]
+    pub scale: Option<f32>,
+
+    /// Z offset in millimeters applied after scaling [default: 30]
+    #[arg(long)]
+    pub z_offset: Option<f32>,
+
+    /// SVG user units to millimeters scale factor [default: 1]
+    #[arg(long)]
+    pub svg_scale: Option<f32>,
+
+    /// Height of the plane SVG drawings are placed on, in millimeters [default: 50]
+    #[arg(long)]
+    pub svg_z: Option<f32>,
+}
+
+impl TuningArgs {
+    // Express the flags as project settings, so they are layered over the vortex.toml
+    pub fn to_settings(&self) -> Table {
+        let mut import = Table::new();
+        let mut planner = Table::new();
+        let mut svg = Table::new();
+
+        if let Some(speed) = self.speed {
+            planner.insert("movement_speed".into(), Value::Float(speed.into()));
+        }
+        if let Some(threshold) = self.cluster_threshold {
+            planner.insert("cluster_threshold".into(), Value::Float(threshold));
+        }
+        if let Some(metric) = &self.color_metric {
+            planner.insert("color_metric".into(), Value::String(metric.clone()));
+        }
+        if let Some(space) = &self.fade_space {
+            planner.insert("fade_space".into(), Value::String(space.clone()));
+        }
+        if self.speed_compensation {
+            planner.insert("speed_compensation".into(), Value::Boolean(true));
+        }
+        if let Some(speed) = self.reference_speed {
+            planner.insert("reference_speed".into(), Value::Float(speed.into()));
+        }
+        if let Some(delay) = self.point_delay {
+            planner.insert("point_delay_ms".into(), Value::Integer(delay.into()));
+        }
+        if let Some(shaping) = self.transit_shaping {
+            planner.insert(
+                "transit_shaping_factor".into(),
+                Value::Float(shaping.into()),
+            );
+        }
+        if self.keep_order {
+            planner.insert("optimise_order".into(), Value::Boolean(false));
+        }
+        if self.temporal {
+            planner.insert("temporal_coherence".into(), Value::Boolean(true));
+        }
+        if let Some(order) = &self.particle_order {
+            planner.insert("particle_order".into(), Value::String(order.clone()));
+        }
+        if let Some(seed) = self.seed {
+            planner.insert("particle_seed".into(), Value::Integer(seed as i64));
+        }
+        if let Some(tolerance) = self.simplify_tolerance {
+            planner.insert("simplify_tolerance".into(), Value::Float(tolerance.into()));
+        }
+        if let Some(profile) = &self.profile {
+            planner.insert("motion_profile".into(), Value::String(profile.clone()));
+        }
+        if let Some(acceleration) = self.acceleration {
+            planner.insert("acceleration".into(), Value::Float(acceleration.into()));
+        }
+        if let Some(jerk) = self.jerk {
+            planner.insert("jerk".into(), Value::Float(jerk.into()));
+        }
+        if let Some(deviation) = self.junction_deviation {
+            planner.insert("junction_deviation".into(), Value::Float(deviation.into()));
+        }
+        if let Some(scale) = self.scale {
+            import.insert("scale_factor".into(), Value::Float(scale.into()));
+        }
+        if let Some(offset) = self.z_offset {
+            import.insert("z_offset".into(), Value::Float(offset.into()));
+        }
+        if let Some(scale) = self.svg_scale {
+            svg.insert("scale".into(), Value::Float(scale.into()));
+        }
+        if let Some(z) = self.svg_z {
+            svg.insert("z".into(), Value::Float(z.into()));
+        }
+
+        let mut settings = Table::new();
+        settings.insert("import".into(), Value::Table(import));
+        settings.insert("planner".into(), Value::Table(planner));
+        settings.insert("svg".into(), Value::Table(svg));
+        settings
+    }
+}
@@ -0,0 +1,229 @@
+use colorsys::Hsl;
+use serde::{Deserialize, Serialize};
+
+use crate::color_utils::{distance_hsl, linear_rgb_from_hsl};
+
+// CIE standard illuminant D65, the sRGB white point
+const WHITE: [f64; 3] = [0.950_47, 1.0, 1.088_83];
+
+// A colour in CIELAB, where equal distances are roughly equally noticeable.
+// L is lightness from 0 to 100, a runs green to red and b runs blue to yellow.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Lab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+impl Lab {
+    pub fn from_hsl(color: &Hsl) -> Lab {
+        let linear = linear_rgb_from_hsl(color);
+
+        // Linear sRGB to XYZ, relative to the white point
+        let xyz = [
+            0.412_456_4 * linear[0] + 0.357_576_1 * linear[1] + 0.180_437_5 * linear[2],
+            0.212_672_9 * linear[0] + 0.715_152_2 * linear[1] + 0.072_175_0 * linear[2],
+            0.019_333_9 * linear[0] + 0.119_192_0 * linear[1] + 0.950_304_1 * linear[2],
+        ];
+        let [fx, fy, fz] = [0, 1, 2].map(|i| {
+            let t = xyz[i] / WHITE[i];
+            if t > 216.0 / 24389.0 {
+                t.cbrt()
+            } else {
+                (24389.0 / 27.0 * t + 16.0) / 116.0
+            }
+        });
+
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+
+    fn chroma(&self) -> f64 {
+        self.a.hypot(self.b)
+    }
+}
+
+// How the difference between two colours is measured when clustering lighting fades.
+// Except for the original hsl distance, differences are in ΔE, where about 2.3 is just noticeable.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMetric {
+    Cie76,     // straight line distance in CIELAB
+    Cie94,     // weights chroma and hue differences down for saturated colours
+    Ciede2000, // also corrects blue hues and lightness near the middle of the range
+    Hsl,       // the original cone projection, in its own arbitrary units
+}
+
+impl ColorMetric {
+    pub fn distance(self, x: &Hsl, y: &Hsl) -> f64 {
+        match self {
+            ColorMetric::Hsl => distance_hsl(x, y).abs(),
+            ColorMetric::Cie76 => delta_e76(&Lab::from_hsl(x), &Lab::from_hsl(y)),
+            ColorMetric::Cie94 => delta_e94(&Lab::from_hsl(x), &Lab::from_hsl(y)),
+            ColorMetric::Ciede2000 => ciede2000(&Lab::from_hsl(x), &Lab::from_hsl(y)),
+        }
+    }
+}
+
+pub fn delta_e76(x: &Lab, y: &Lab) -> f64 {
+    ((x.l - y.l).powi(2) + (x.a - y.a).powi(2) + (x.b - y.b).powi(2)).sqrt()
+}
+
+// With the graphic arts weightings, the first colour is the reference
+pub fn delta_e94(x: &Lab, y: &Lab) -> f64 {
+    let delta_l = x.l - y.l;
+    let (c1, c2) = (x.chroma(), y.chroma());
+    let delta_c = c1 - c2;
+
+    // Whatever isn't explained by lightness or chroma is hue, rounding can leave it slightly negative
+    let delta_h_squared = ((x.a - y.a).powi(2) + (x.b - y.b).powi(2) - delta_c.powi(2)).max(0.0);
+
+    let s_c = 1.0 + 0.045 * c1;
+    let s_h = 1.0 + 0.015 * c1;
+
+    (delta_l.powi(2) + (delta_c / s_c).powi(2) + delta_h_squared / s_h.powi(2)).sqrt()
+}
+
+// Following Sharma, Wu and Dalal's implementation notes, with unit weighting factors
+pub fn ciede2000(x: &Lab, y: &Lab) -> f64 {
+    let mean_chroma = (x.chroma() + y.chroma()) / 2.0;
+    let g = 0.5 * (1.0 - (mean_chroma.powi(7) / (mean_chroma.powi(7) + 25f64.powi(7))).sqrt());
+
+    // Stretch the a axis so neutral colours are compared more fairly
+    let prime = |lab: &Lab| {
+        let a = lab.a * (1.0 + g);
+        let chroma = a.hypot(lab.b);
+        let hue = if chroma == 0.0 {
+            0.0
+        } else {
+            lab.b.atan2(a).to_degrees().rem_euclid(360.0)
+        };
+        (chroma, hue)
+    };
+    let (c1, h1) = prime(x);
+    let (c2, h2) = prime(y);
+
+    let delta_l = y.l - x.l;
+    let delta_c = c2 - c1;
+    let delta_hue = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 <= h1 {
+        h2 - h1 + 360.0
+    } else {
+        h2 - h1 - 360.0
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_hue / 2.0).to_radians().sin();
+
+    let mean_l = (x.l + y.l) / 2.0;
+    let mean_c = (c1 + c2) / 2.0;
+    let mean_h = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (mean_h - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * mean_h).to_radians().cos()
+        + 0.32 * (3.0 * mean_h + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * mean_h - 63.0).to_radians().cos();
+
+    let s_l = 1.0 + 0.015 * (mean_l - 50.0).powi(2) / (20.0 + (mean_l - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * mean_c;
+    let s_h = 1.0 + 0.015 * mean_c * t;
+
+    // Blues need the chroma and hue differences rotated together
+    let rotation = 30.0 * (-((mean_h - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (mean_c.powi(7) / (mean_c.powi(7) + 25f64.powi(7))).sqrt();
+    let r_t = -r_c * (2.0 * rotation).to_radians().sin();
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    (l.powi(2) + c.powi(2) + h.powi(2) + r_t * c * h).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lab(l: f64, a: f64, b: f64) -> Lab {
+        Lab { l, a, b }
+    }
+
+    #[test]
+    fn ciede2000_matches_reference_pairs() {
+        // From Sharma, Wu and Dalal's test data
+        let pairs = [
+            (
+                lab(50.0, 2.6772, -79.7751),
+                lab(50.0, 0.0, -82.7485),
+                2.0425,
+            ),
+            (
+                lab(50.0, 3.1571, -77.2803),
+                lab(50.0, 0.0, -82.7485),
+                2.8615,
+            ),
+            (lab(50.0, 0.0, 0.0), lab(50.0, -1.0, 2.0), 2.3669),
+            (lab(50.0, 2.5, 0.0), lab(73.0, 25.0, -18.0), 27.1492),
+            (
+                lab(60.2574, -34.0099, 36.2677),
+                lab(60.4626, -34.1751, 39.4387),
+                1.2644,
+            ),
+            (
+                lab(2.0776, 0.0795, -1.1350),
+                lab(0.9033, -0.0636, -0.5514),
+                0.9082,
+            ),
+        ];
+
+        for (x, y, expected) in &pairs {
+            assert!((ciede2000(x, y) - expected).abs() < 1e-4, "{:?} {:?}", x, y);
+            assert!((ciede2000(y, x) - expected).abs() < 1e-4, "{:?} {:?}", y, x);
+        }
+    }
+
+    #[test]
+    fn cie76_and_cie94_distances() {
+        let grey = lab(50.0, 0.0, 0.0);
+        assert!((delta_e76(&grey, &lab(50.0, 3.0, 4.0)) - 5.0).abs() < 1e-9);
+
+        // Neutral references aren't weighted down, saturated ones are
+        assert!((delta_e94(&grey, &lab(50.0, 3.0, 4.0)) - 5.0).abs() < 1e-9);
+        let saturated = lab(50.0, 60.0, 0.0);
+        assert!(delta_e94(&saturated, &lab(50.0, 60.0, 5.0)) < 5.0);
+    }
+
+    #[test]
+    fn lab_from_srgb_primaries() {
+        let white = Lab::from_hsl(&Hsl::new(0.0, 0.0, 100.0, None));
+        assert!((white.l - 100.0).abs() < 0.01);
+        assert!(white.a.abs() < 0.01 && white.b.abs() < 0.01);
+
+        let red = Lab::from_hsl(&Hsl::new(0.0, 100.0, 50.0, None));
+        assert!((red.l - 53.24).abs() < 0.05);
+        assert!((red.a - 80.09).abs() < 0.05);
+        assert!((red.b - 67.20).abs() < 0.05);
+    }
+
+    #[test]
+    fn identical_colours_have_no_difference() {
+        let color = Hsl::new(210.0, 70.0, 40.0, None);
+        for metric in &[
+            ColorMetric::Cie76,
+            ColorMetric::Cie94,
+            ColorMetric::Ciede2000,
+            ColorMetric::Hsl,
+        ] {
+            assert!(metric.distance(&color, &color) < 1e-9);
+        }
+    }
+}
@@ -44,3 +44,32 @@ pub fn hsl_to_rgb8(color: &Hsl) -> (u8, u8, u8) {
         rgb.get_blue() as u8,
     )
 }
+
+// Undo the sRGB gamma, so channels in [0, 1] add up like light
+pub fn linear_rgb_from_hsl(color: &Hsl) -> [f64; 3] {
+    let rgb = Rgb::from(color);
+
+    [rgb.get_red(), rgb.get_green(), rgb.get_blue()].map(|c| {
+        let c = (c / 255.0).clamp(0.0, 1.0);
+        if c <= 0.040_45 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+// Colours outside the sRGB gamut are clipped
+pub fn hsl_from_linear_rgb(linear: [f64; 3]) -> Hsl {
+    let [r, g, b] = linear.map(|c| {
+        let c = c.clamp(0.0, 1.0);
+        let encoded = if c <= 0.003_130_8 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        encoded * 255.0
+    });
+
+    Hsl::from(&Rgb::new(r, g, b, None))
+}
@@ -0,0 +1,681 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use colorsys::Hsl;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use toml::value::{Table, Value};
+
+use crate::calibration::LedProfile;
+use crate::color_difference::ColorMetric;
+use crate::error::*;
+use crate::fade_space::FadeSpace;
+use crate::motion_profile::ProfileKind;
+use crate::particle_order::ParticleOrder;
+
+// Tunable parameters which control how Blender data is imported and how toolpaths are planned.
+// Defaults match the values previously hard-coded in the importer and sequencer.
+// A vortex.toml project file can set any of these, with per-frame or per-collection overrides.
+
+pub const PROJECT_FILE_NAME: &str = "vortex.toml";
+
+// Tables for reviewing planned output, which are set for the whole project rather than planning
+const OUTPUT_TABLES: [&str; 2] = ["render", "flipbook"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub name: String,
+
+    // Arm geometry in mm. Radii are measured from the center to the shoulder/wrist joints
+    pub base_radius: f32,
+    pub effector_radius: f32,
+    pub bicep_length: f32,
+    pub forearm_length: f32,
+
+    // Height of the shoulder joints above the toolpath's Z=0, in mm
+    pub base_height: f32,
+
+    // Joint limits in degrees, 0 is a horizontal bicep and positive angles point down
+    pub min_joint_angle: f32,
+    pub max_joint_angle: f32,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            name: String::from("zaphod-bot"),
+            base_radius: 100.0,
+            effector_radius: 35.0,
+            bicep_length: 180.0,
+            forearm_length: 390.0,
+            base_height: 450.0,
+            min_joint_angle: -50.0,
+            max_joint_angle: 85.0,
+        }
+    }
+}
+
+// Legal points are inside a vertical cylinder above the build plate
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkspaceConfig {
+    pub center_x: f32,
+    pub center_y: f32,
+    pub radius: f32,
+    pub z_min: f32,
+    pub z_max: f32,
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        WorkspaceConfig {
+            center_x: 0.0,
+            center_y: 0.0,
+            radius: 200.0,
+            z_min: 0.0,
+            z_max: 200.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportConfig {
+    pub scale_factor: f32, // Blender units to millimeters
+    pub z_offset: f32,     // millimeters added to every Z co-ordinate after scaling
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        ImportConfig {
+            scale_factor: 100.0,
+            z_offset: 30.0,
+        }
+    }
+}
+
+// SVG drawings are flattened onto a horizontal plane, centered in the workspace
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SvgConfig {
+    pub scale: f32,              // SVG user units to millimeters
+    pub z: f32,                  // height of the drawing plane in millimeters
+    pub tolerance: f32,          // mm, furthest a curve can be from the lines it's split into
+    pub gradient_samples: usize, // colours sampled along strokes with a gradient
+}
+
+impl Default for SvgConfig {
+    fn default() -> Self {
+        SvgConfig {
+            scale: 1.0,
+            z: 50.0,
+            tolerance: 0.05,
+            gradient_samples: 32,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlannerConfig {
+    pub movement_speed: f32,         // mm/second
+    pub cluster_threshold: f64,      // furthest a UV colour may be from the fade lighting it
+    pub color_metric: ColorMetric,   // how the difference is measured, ΔE except for hsl
+    pub fade_space: FadeSpace, // how fades blend between colours, split into keyframes for the firmware
+    pub point_delay_ms: u32,   // pause before drawing each particle trail
+    pub transit_shaping_factor: f32, // between 0 and 1
+
+    // Poly spline points closer than this many mm to a merged line are dropped, 0 keeps every point
+    pub simplify_tolerance: f32,
+
+    // Furthest in mm the bezier moves drawing a NURBS spline can be from the curve
+    pub curve_tolerance: f32,
+
+    // Reorder and reverse splines to reduce transit between them
+    pub optimise_order: bool,
+
+    // Follow the previous frame's spline and particle order, so paths don't jump around between frames
+    pub temporal_coherence: bool,
+
+    // How particle trails are ordered, the seed fixes the order between runs and it's random when unset
+    pub particle_order: ParticleOrder,
+    pub particle_seed: Option<u64>,
+
+    // Velocity profile used to estimate how long each move takes
+    pub motion_profile: ProfileKind,
+    pub acceleration: f32,       // mm/second^2
+    pub jerk: f32,               // mm/second^3, only used by the s_curve profile
+    pub junction_deviation: f32, // mm, larger values allow faster cornering between moves
+
+    // How the LED responds to colours, fade colours are left as they are without one
+    pub led_profile: Option<LedProfile>,
+
+    // Dim strokes where the effector is slower than the reference speed (mm/second), so they expose evenly
+    pub speed_compensation: bool,
+    pub reference_speed: f32,
+}
+
+impl Default for PlannerConfig {
+    fn default() -> Self {
+        PlannerConfig {
+            movement_speed: 200.0,
+            cluster_threshold: 2.3,
+            color_metric: ColorMetric::Ciede2000,
+            fade_space: FadeSpace::Hsl,
+            point_delay_ms: 10,
+            transit_shaping_factor: 0.01,
+            optimise_order: true,
+            temporal_coherence: false,
+            particle_order: ParticleOrder::Greedy,
+            particle_seed: None,
+            simplify_tolerance: 0.1,
+            curve_tolerance: 0.05,
+            motion_profile: ProfileKind::Trapezoidal,
+            acceleration: 2000.0,
+            jerk: 40000.0,
+            junction_deviation: 0.05,
+            led_profile: None,
+            speed_compensation: false,
+            reference_speed: 200.0,
+        }
+    }
+}
+
+impl PlannerConfig {
+    // Whether planning a collection needs the order it was drawn in the previous frame
+    pub fn follows_previous_frame(&self) -> bool {
+        self.temporal_coherence || self.particle_order == ParticleOrder::Temporal
+    }
+
+    // The colour to ask the LED for, to show a wanted colour
+    pub fn led_color(&self, color: &Hsl) -> Hsl {
+        match &self.led_profile {
+            Some(profile) => profile.drive(color),
+            None => color.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VortexConfig {
+    pub machine: MachineConfig,
+    pub workspace: WorkspaceConfig,
+    pub import: ImportConfig,
+    pub svg: SvgConfig,
+    pub planner: PlannerConfig,
+}
+
+impl VortexConfig {
+    // Catch settings which would otherwise cause the planner to fail part way through a run
+    pub fn validate(&self) -> VortexResult<()> {
+        let shaping = self.planner.transit_shaping_factor;
+        if shaping <= 0.0 || shaping >= 1.0 {
+            return Err(VortexError::Config(format!(
+                "Transit shaping factor must be between 0 and 1, got {}",
+                shaping
+            )));
+        }
+
+        if self.planner.movement_speed <= 0.0 {
+            return Err(VortexError::Config(
+                "Movement speed must be positive".to_string(),
+            ));
+        }
+
+        if self.planner.acceleration <= 0.0 || self.planner.jerk <= 0.0 {
+            return Err(VortexError::Config(
+                "Acceleration and jerk must be positive".to_string(),
+            ));
+        }
+
+        if self.planner.reference_speed <= 0.0 {
+            return Err(VortexError::Config(
+                "Reference speed must be positive".to_string(),
+            ));
+        }
+
+        if self.planner.cluster_threshold < 0.0 {
+            return Err(VortexError::Config(
+                "Cluster threshold can't be negative".to_string(),
+            ));
+        }
+
+        if self.planner.simplify_tolerance < 0.0 {
+            return Err(VortexError::Config(
+                "Simplify tolerance can't be negative".to_string(),
+            ));
+        }
+
+        if self.planner.curve_tolerance <= 0.0 {
+            return Err(VortexError::Config(
+                "Curve tolerance must be positive".to_string(),
+            ));
+        }
+
+        if self.planner.junction_deviation < 0.0 {
+            return Err(VortexError::Config(
+                "Junction deviation can't be negative".to_string(),
+            ));
+        }
+
+        if self.svg.scale <= 0.0 || self.svg.tolerance <= 0.0 {
+            return Err(VortexError::Config(
+                "SVG scale and tolerance must be positive".to_string(),
+            ));
+        }
+
+        if self.svg.gradient_samples < 2 {
+            return Err(VortexError::Config(
+                "SVG gradients need at least 2 samples".to_string(),
+            ));
+        }
+
+        if self.machine.min_joint_angle >= self.machine.max_joint_angle {
+            return Err(VortexError::Config(
+                "Machine min_joint_angle must be below max_joint_angle".to_string(),
+            ));
+        }
+
+        if self.workspace.z_min >= self.workspace.z_max {
+            return Err(VortexError::Config(
+                "Workspace z_min must be below z_max".to_string(),
+            ));
+        }
+
+        if let Some(profile) = &self.planner.led_profile {
+            profile.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+// How the render command photographs a planned animation, as a long exposure through a pinhole camera.
+// Kept out of VortexConfig so changing the camera doesn't invalidate planned collections.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    pub width: u32,
+    pub height: u32,
+    pub camera_position: [f32; 3], // mm, in the machine's co-ordinates
+    pub camera_target: [f32; 3],
+    pub field_of_view: f32,  // vertical, in degrees
+    pub exposure: f32,       // brightness added for each ms the lit LED spends over a pixel
+    pub bloom_radius: f32,   // px
+    pub bloom_strength: f32, // 0 disables bloom
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig {
+            width: 1280,
+            height: 720,
+            camera_position: [0.0, -600.0, 150.0],
+            camera_target: [0.0, 0.0, 100.0],
+            field_of_view: 35.0,
+            exposure: 0.5,
+            bloom_radius: 8.0,
+            bloom_strength: 0.0,
+        }
+    }
+}
+
+impl RenderConfig {
+    pub fn validate(&self) -> VortexResult<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(VortexError::Config(
+                "Render width and height must be positive".to_string(),
+            ));
+        }
+
+        if self.field_of_view <= 0.0 || self.field_of_view >= 180.0 {
+            return Err(VortexError::Config(format!(
+                "Render field of view must be between 0 and 180 degrees, got {}",
+                self.field_of_view
+            )));
+        }
+
+        if self.camera_position == self.camera_target {
+            return Err(VortexError::Config(
+                "Render camera can't be at its target".to_string(),
+            ));
+        }
+
+        if self.exposure <= 0.0 || self.bloom_radius < 0.0 || self.bloom_strength < 0.0 {
+            return Err(VortexError::Config(
+                "Render exposure must be positive, bloom can't be negative".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// How the flipbook command sketches the preview of each frame, from the render camera's viewpoint
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlipbookConfig {
+    pub width: u32,
+    pub height: u32,
+    pub columns: usize,          // frames across the contact sheet
+    pub frame_delay_ms: u32,     // between frames of the gif
+    pub collection_colors: bool, // draw each collection in its own colour instead of its lighting
+    pub show_transits: bool,
+}
+
+impl Default for FlipbookConfig {
+    fn default() -> Self {
+        FlipbookConfig {
+            width: 480,
+            height: 270,
+            columns: 6,
+            frame_delay_ms: 80,
+            collection_colors: false,
+            show_transits: false,
+        }
+    }
+}
+
+impl FlipbookConfig {
+    pub fn validate(&self) -> VortexResult<()> {
+        if self.width == 0 || self.height == 0 || self.width > 65535 || self.height > 65535 {
+            return Err(VortexError::Config(
+                "Flipbook width and height must be between 1 and 65535".to_string(),
+            ));
+        }
+
+        if self.columns == 0 {
+            return Err(VortexError::Config(
+                "Flipbook contact sheets need at least one column".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Settings which only apply to some frames and/or collections.
+// Any section of the base config can be repeated inside an override.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfigOverride {
+    pub frames: Option<Vec<i32>>,
+    pub first_frame: Option<i32>,
+    pub last_frame: Option<i32>,
+    pub collection: Option<String>,
+
+    #[serde(flatten)]
+    pub settings: Table,
+}
+
+impl ConfigOverride {
+    fn matches(&self, frame: i32, collection: &str) -> bool {
+        let in_list = self.frames.as_ref().is_none_or(|f| f.contains(&frame));
+        let after_first = self.first_frame.is_none_or(|f| frame >= f);
+        let before_last = self.last_frame.is_none_or(|f| frame <= f);
+        let same_collection = self
+            .collection
+            .as_ref()
+            .is_none_or(|c| c.eq_ignore_ascii_case(collection));
+
+        in_list && after_first && before_last && same_collection
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ProjectFile {
+    #[serde(default, rename = "override")]
+    overrides: Vec<ConfigOverride>,
+
+    // An LED profile file, relative to the project file
+    led_profile: Option<PathBuf>,
+
+    #[serde(flatten)]
+    settings: Table,
+}
+
+// The project settings as loaded from disk, resolved into a VortexConfig per collection
+#[derive(Debug, Default)]
+pub struct ProjectConfig {
+    pub path: Option<PathBuf>,
+    settings: Table,
+    overrides: Vec<ConfigOverride>,
+    forced: Table,
+}
+
+impl ProjectConfig {
+    // Use the explicitly requested file, otherwise look for a vortex.toml in the working directory
+    pub fn discover(explicit_path: Option<&Path>) -> VortexResult<ProjectConfig> {
+        match explicit_path {
+            Some(path) => ProjectConfig::load(path),
+            None => {
+                let default_path = Path::new(PROJECT_FILE_NAME);
+                if default_path.is_file() {
+                    ProjectConfig::load(default_path)
+                } else {
+                    Ok(ProjectConfig::default())
+                }
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> VortexResult<ProjectConfig> {
+        let contents = fs::read_to_string(path).map_err(|e| VortexError::io(path, e))?;
+
+        let project: ProjectFile = toml::from_str(&contents).map_err(|e| {
+            VortexError::Config(format!("Couldn't parse {}: {}", path.display(), e))
+        })?;
+
+        // Settings in the project file take priority over the profile's
+        let mut settings = Table::new();
+        if let Some(profile) = &project.led_profile {
+            let folder = path.parent().unwrap_or_else(|| Path::new(""));
+            settings = led_profile_settings(&folder.join(profile))?;
+        }
+        merge_tables(&mut settings, &project.settings);
+
+        let config = ProjectConfig {
+            path: Some(path.to_path_buf()),
+            settings,
+            overrides: project.overrides,
+            forced: Table::new(),
+        };
+
+        // Check the base settings and every override deserialise cleanly before planning starts,
+        // so misspelt keys are reported rather than silently left at their defaults
+        config.base()?;
+        config.output_table::<RenderConfig>("render")?;
+        config.output_table::<FlipbookConfig>("flipbook")?;
+        for o in &config.overrides {
+            if let Some(name) = OUTPUT_TABLES
+                .iter()
+                .find(|&&name| o.settings.contains_key(name))
+            {
+                return Err(VortexError::Config(format!(
+                    "{} settings can't be overridden for some frames or collections",
+                    name
+                )));
+            }
+
+            let mut merged = config.settings.clone();
+            merge_tables(&mut merged, &o.settings);
+            deserialise_config(merged)?;
+        }
+
+        Ok(config)
+    }
+
+    // Settings which take priority over the file and every override, i.e. command-line flags
+    pub fn force_settings(&mut self, settings: Table) -> VortexResult<()> {
+        merge_tables(&mut self.forced, &settings);
+        self.base().map(|_| ())
+    }
+
+    // Settings before any frame or collection overrides are applied
+    pub fn base(&self) -> VortexResult<VortexConfig> {
+        let mut merged = self.settings.clone();
+        merge_tables(&mut merged, &self.forced);
+
+        deserialise_config(merged)
+    }
+
+    // The [render] table, which isn't affected by frame or collection overrides
+    pub fn render(&self) -> VortexResult<RenderConfig> {
+        let config: RenderConfig = self.output_table("render")?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // The [flipbook] table, also only set for the whole project
+    pub fn flipbook(&self) -> VortexResult<FlipbookConfig> {
+        let config: FlipbookConfig = self.output_table("flipbook")?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // A table of settings for reviewing planned output, which doesn't take part in planning
+    fn output_table<T: DeserializeOwned>(&self, name: &str) -> VortexResult<T> {
+        let mut merged = Table::new();
+        for settings in &[&self.settings, &self.forced] {
+            if let Some(Value::Table(table)) = settings.get(name) {
+                merge_tables(&mut merged, table);
+            }
+        }
+
+        Value::Table(merged)
+            .try_into()
+            .map_err(|e| VortexError::Config(format!("Invalid {} settings: {}", name, e)))
+    }
+
+    // Settings for a specific collection in a frame, overrides are applied in file order
+    pub fn resolve(&self, frame: i32, collection: &str) -> VortexResult<VortexConfig> {
+        let mut merged = self.settings.clone();
+
+        for o in self
+            .overrides
+            .iter()
+            .filter(|o| o.matches(frame, collection))
+        {
+            merge_tables(&mut merged, &o.settings);
+        }
+        merge_tables(&mut merged, &self.forced);
+
+        deserialise_config(merged)
+    }
+}
+
+// An LED profile file as settings, ready to layer under the project file or force from the command line
+pub fn led_profile_settings(path: &Path) -> VortexResult<Table> {
+    let contents = fs::read_to_string(path).map_err(|e| VortexError::io(path, e))?;
+
+    let profile: Table = toml::from_str(&contents)
+        .map_err(|e| VortexError::Config(format!("Couldn't parse {}: {}", path.display(), e)))?;
+
+    let mut planner = Table::new();
+    planner.insert("led_profile".into(), Value::Table(profile));
+
+    let mut settings = Table::new();
+    settings.insert("planner".into(), Value::Table(planner));
+    Ok(settings)
+}
+
+fn deserialise_config(mut settings: Table) -> VortexResult<VortexConfig> {
+    for name in &OUTPUT_TABLES {
+        settings.remove(*name);
+    }
+
+    Value::Table(settings)
+        .try_into()
+        .map_err(|e| VortexError::Config(format!("Invalid settings: {}", e)))
+}
+
+// Recursively copy the values in the overlay over the base table
+fn merge_tables(base: &mut Table, overlay: &Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table);
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Load a project file's contents the way they'd be read from disk
+    fn project(name: &str, contents: &str) -> VortexResult<ProjectConfig> {
+        let path =
+            std::env::temp_dir().join(format!("vortex-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let project = ProjectConfig::load(&path);
+        fs::remove_file(&path).unwrap();
+        project
+    }
+
+    #[test]
+    fn overrides_layer_over_the_project_and_flags_over_both() {
+        let mut project = project(
+            "layers",
+            r#"
+            [planner]
+            movement_speed = 100.0
+            point_delay_ms = 20
+
+            [[override]]
+            frames = [2]
+            collection = "lines"
+            planner.movement_speed = 150.0
+            planner.point_delay_ms = 30
+            "#,
+        )
+        .unwrap();
+
+        let speed = |p: &ProjectConfig, frame, collection| {
+            p.resolve(frame, collection).unwrap().planner.movement_speed
+        };
+        assert_eq!(speed(&project, 1, "Lines"), 100.0);
+        assert_eq!(speed(&project, 2, "Lines"), 150.0);
+        assert_eq!(speed(&project, 2, "Sparks"), 100.0);
+        assert_eq!(project.base().unwrap().planner.movement_speed, 100.0);
+
+        project
+            .force_settings(toml::from_str("planner.movement_speed = 300.0").unwrap())
+            .unwrap();
+        assert_eq!(speed(&project, 1, "Lines"), 300.0);
+        assert_eq!(speed(&project, 2, "Lines"), 300.0);
+
+        // Settings which aren't forced still come from the override
+        assert_eq!(
+            project.resolve(2, "Lines").unwrap().planner.point_delay_ms,
+            30
+        );
+        assert_eq!(
+            project.resolve(1, "Lines").unwrap().planner.point_delay_ms,
+            20
+        );
+    }
+
+    #[test]
+    fn misspelt_settings_are_rejected() {
+        let error = project("typo", "[planner]\ncluster_treshold = 2.0\n").unwrap_err();
+        assert!(error.to_string().contains("cluster_treshold"), "{}", error);
+
+        let in_override = project(
+            "override-typo",
+            "[[override]]\nframes = [1]\nworkspace.radiuss = 10.0\n",
+        );
+        assert!(in_override.unwrap_err().to_string().contains("radiuss"));
+
+        let mut project = ProjectConfig::default();
+        let forced = project.force_settings(toml::from_str("machine.bicep = 1.0").unwrap());
+        assert!(forced.unwrap_err().to_string().contains("bicep"));
+    }
+}
@@ -1,15 +1,20 @@
+use crate::config::WorkspaceConfig;
+use crate::error::*;
+use crate::export_types::*;
 use crate::import_types::*;
-use std::f32::MAX;
 
 // Find a point partially between two points
 pub fn interpolate_line_point(
     a: &BlenderPoint3,
     b: &BlenderPoint3,
     weight: f32,
-) -> Result<BlenderPoint3, String> {
+) -> VortexResult<BlenderPoint3> {
     if weight <= 0.0 || weight >= 1.0 {
         // Weights should be between 0.0-1.0 representing the percentage point to interpolate
-        return Err("Can't interpolate point from line with input weight".to_string());
+        return Err(VortexError::UnsupportedOperation(format!(
+            "Can't interpolate point from line with input weight {}",
+            weight
+        )));
     }
 
     Ok(BlenderPoint3 {
@@ -29,7 +34,7 @@ fn distance_2d(a: &BlenderPoint2, b: &BlenderPoint2) -> f32 {
 }
 
 // Calculate the 3D distance in mm between two points
-fn distance_3d(a: &BlenderPoint3, b: &BlenderPoint3) -> f32 {
+pub fn distance_3d(a: &BlenderPoint3, b: &BlenderPoint3) -> f32 {
     let dx = a.x - b.x;
     let dy = a.y - b.y;
     let dz = a.z - b.z;
@@ -38,13 +43,84 @@ fn distance_3d(a: &BlenderPoint3, b: &BlenderPoint3) -> f32 {
     distance.abs()
 }
 
-pub fn interpolate_catmull_point(
-    p: &[BlenderPoint3],
-    weight: f32,
-) -> Result<BlenderPoint3, String> {
+// Shortest distance in mm from a point to the line segment between a and b
+fn distance_to_segment(point: &BlenderPoint3, a: &BlenderPoint3, b: &BlenderPoint3) -> f32 {
+    let (dx, dy, dz) = (b.x - a.x, b.y - a.y, b.z - a.z);
+    let length_squared = dx * dx + dy * dy + dz * dz;
+
+    if length_squared <= f32::EPSILON {
+        return distance_3d(point, a);
+    }
+
+    let t = (((point.x - a.x) * dx + (point.y - a.y) * dy + (point.z - a.z) * dz) / length_squared)
+        .clamp(0.0, 1.0);
+
+    distance_3d(
+        point,
+        &BlenderPoint3 {
+            x: a.x + dx * t,
+            y: a.y + dy * t,
+            z: a.z + dz * t,
+        },
+    )
+}
+
+// Ramer-Douglas-Peucker simplification of a polyline.
+// Returns the indices of the points to keep, the merged lines stay within tolerance (mm) of every removed point.
+pub fn simplify_polyline(points: &[BlenderPoint3], tolerance: f32) -> Vec<usize> {
+    if points.len() < 3 || tolerance <= 0.0 {
+        return (0..points.len()).collect();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // Split ranges at their furthest point until every range is within tolerance
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let furthest = (start + 1..end)
+            .map(|i| {
+                (
+                    i,
+                    distance_to_segment(&points[i], &points[start], &points[end]),
+                )
+            })
+            .fold(None, |best: Option<(usize, f32)>, candidate| match best {
+                Some(b) if b.1 >= candidate.1 => Some(b),
+                _ => Some(candidate),
+            });
+
+        if let Some((index, distance)) = furthest {
+            if distance > tolerance {
+                keep[index] = true;
+                ranges.push((start, index));
+                ranges.push((index, end));
+            }
+        }
+    }
+
+    keep.iter()
+        .enumerate()
+        .filter(|(_, &k)| k)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+pub fn interpolate_catmull_point(p: &[BlenderPoint3], weight: f32) -> VortexResult<BlenderPoint3> {
     if weight <= 0.0 || weight >= 1.0 {
         // Weights should be between 0.0-1.0 representing the percentage point to interpolate
-        return Err("Can't interpolate catmull with input weight".to_string());
+        return Err(VortexError::UnsupportedOperation(format!(
+            "Can't interpolate catmull with input weight {}",
+            weight
+        )));
+    }
+
+    if p.len() != 4 {
+        return Err(VortexError::EmptyGeometry(format!(
+            "Catmull-rom segments need 4 control points, got {}",
+            p.len()
+        )));
     }
 
     let t = weight;
@@ -84,49 +160,133 @@ pub fn interpolate_catmull_point(
     })
 }
 
-// Estimate the 3D length of a catmull-rom spline by sampling repeatedly
-fn distance_catmull(control_points: &[BlenderPoint3]) -> Result<f32, String> {
-    let samples: Vec<u32> = (1..99).collect();
-
-    let length: f32 = samples
-        .windows(2)
-        .map(|p| {
-            let a = interpolate_catmull_point(control_points, p[0] as f32 * 0.01).unwrap();
-            let b = interpolate_catmull_point(control_points, p[1] as f32 * 0.01).unwrap();
-            distance_3d(&a, &b)
-        })
-        .sum();
+// Evaluate a quadratic (3 point) or cubic (4 point) bezier curve.
+// Unlike catmull-rom segments, the start and end points are on the curve so weights of 0.0 and 1.0 are valid
+pub fn interpolate_bezier_point(p: &[BlenderPoint3], weight: f32) -> VortexResult<BlenderPoint3> {
+    if !(0.0..=1.0).contains(&weight) {
+        return Err(VortexError::UnsupportedOperation(format!(
+            "Can't interpolate bezier with input weight {}",
+            weight
+        )));
+    }
 
-    Ok(length)
+    let t = weight;
+    let u = 1.0 - t;
+
+    let coefficients = match p.len() {
+        3 => vec![u * u, 2.0 * u * t, t * t],
+        4 => vec![u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t],
+        n => {
+            return Err(VortexError::EmptyGeometry(format!(
+                "Bezier curves need 3 or 4 control points, got {}",
+                n
+            )))
+        }
+    };
+
+    Ok(p.iter()
+        .zip(coefficients)
+        .fold(BlenderPoint3::default(), |acc, (point, k)| BlenderPoint3 {
+            x: acc.x + point.x * k,
+            y: acc.y + point.y * k,
+            z: acc.z + point.z * k,
+        }))
 }
 
-pub fn calculate_duration(points: &[BlenderPoint3], speed: f32) -> Result<f32, String> {
-    let distance;
+// Sample points along a motion's path, including the start and end points.
+// Point transits and relative moves only know their destination, so return just that point.
+pub fn sample_motion(motion: &Motion, samples: usize) -> VortexResult<Vec<BlenderPoint3>> {
+    let points: Vec<BlenderPoint3> = motion.points.iter().map(|&p| p.into()).collect();
+    let steps = samples.max(2) - 1;
+    let weights = (0..=steps).map(|i| i as f32 / steps as f32);
+
+    match motion.motion_type {
+        MotionInterpolationType::PointTransit => Ok(points),
+        MotionInterpolationType::Line => {
+            if points.len() != 2 {
+                return Err(VortexError::EmptyGeometry(format!(
+                    "Lines need 2 points, got {}",
+                    points.len()
+                )));
+            }
 
-    match points.len() {
-        1 => return Err("Duration for one point?".to_string()),
-        2 => {
-            distance = distance_3d(&points[0], &points[1]);
+            Ok(weights
+                .map(|t| BlenderPoint3 {
+                    x: points[0].x + (points[1].x - points[0].x) * t,
+                    y: points[0].y + (points[1].y - points[0].y) * t,
+                    z: points[0].z + (points[1].z - points[0].z) * t,
+                })
+                .collect())
         }
-        4 => {
-            distance = distance_catmull(points)?;
+        MotionInterpolationType::CatmullSpline => {
+            // The curve runs between the two inner control points
+            weights
+                .map(|t| match t {
+                    t if t <= 0.0 => points.get(1).copied().ok_or_else(|| {
+                        VortexError::EmptyGeometry("Catmull-rom segment without points".to_string())
+                    }),
+                    t if t >= 1.0 => points.get(2).copied().ok_or_else(|| {
+                        VortexError::EmptyGeometry("Catmull-rom segment without points".to_string())
+                    }),
+                    t => interpolate_catmull_point(&points, t),
+                })
+                .collect()
         }
-        _ => return Err("Can't calculate duration on this number of points".to_string()),
+        MotionInterpolationType::BezierQuadratic | MotionInterpolationType::BezierCubic => weights
+            .map(|t| interpolate_bezier_point(&points, t))
+            .collect(),
     }
+}
 
-    let mut duration = (distance / speed) * 1000.0; // in milliseconds
+// Estimate the 3D length of a catmull-rom spline by sampling repeatedly
+fn distance_catmull(control_points: &[BlenderPoint3]) -> VortexResult<f32> {
+    let samples = (1..99)
+        .map(|i| interpolate_catmull_point(control_points, i as f32 * 0.01))
+        .collect::<VortexResult<Vec<BlenderPoint3>>>()?;
 
-    if duration < 10.0 {
-        duration = 10.0;
-    }
+    let length: f32 = samples.windows(2).map(|p| distance_3d(&p[0], &p[1])).sum();
 
-    Ok(duration)
+    Ok(length)
+}
+
+// Estimate the 3D length of a bezier curve by sampling repeatedly, the ends are on the curve
+pub fn distance_bezier(control_points: &[BlenderPoint3]) -> VortexResult<f32> {
+    let samples = (0..=100)
+        .map(|i| interpolate_bezier_point(control_points, i as f32 * 0.01))
+        .collect::<VortexResult<Vec<BlenderPoint3>>>()?;
+
+    let length: f32 = samples.windows(2).map(|p| distance_3d(&p[0], &p[1])).sum();
+
+    Ok(length)
 }
 
-pub fn vertex_from_spline(spline_type: u32, geometry: &[BlenderPoint3]) -> Vec<(f32, f32, f32)> {
+// The firmware needs some time to process each move
+pub const MIN_MOVE_DURATION_MS: f32 = 10.0;
+
+pub fn calculate_duration(points: &[BlenderPoint3], speed: f32) -> VortexResult<f32> {
+    let distance = match points.len() {
+        2 => distance_3d(&points[0], &points[1]),
+        4 => distance_catmull(points)?,
+        n => {
+            return Err(VortexError::UnsupportedOperation(format!(
+                "Can't calculate duration on {} points",
+                n
+            )))
+        }
+    };
+
+    let duration = (distance / speed) * 1000.0; // in milliseconds
+
+    Ok(duration.max(MIN_MOVE_DURATION_MS))
+}
+
+pub fn vertex_from_spline(
+    spline_type: u32,
+    geometry: &[BlenderPoint3],
+) -> VortexResult<Vec<(f32, f32, f32)>> {
     let mut points_list: Vec<(f32, f32, f32)> = vec![];
 
-    // take the two points of the line, or sample points from the catmull chain
+    // take the two points of the line, or sample points from the catmull chain or bezier curve
     match spline_type {
         1 => {
             // Grab the xyz co-ords (discard blender's w term)
@@ -134,64 +294,103 @@ pub fn vertex_from_spline(spline_type: u32, geometry: &[BlenderPoint3]) -> Vec<(
                 points_list.push((point.x, point.y, point.z));
             }
         }
-        2 => {
-            let samples: Vec<u32> = (1..99).collect();
+        2 | 4 => {
+            if spline_type == 2 && geometry.len() != 4 {
+                return Err(VortexError::EmptyGeometry(format!(
+                    "Catmull-rom segments need 4 control points, got {}",
+                    geometry.len()
+                )));
+            }
 
-            for sample in samples {
-                let point = interpolate_catmull_point(geometry, sample as f32 * 0.01).unwrap();
-                points_list.push((point.x, point.y, point.z));
+            // Pairs of points for each line along the curve, matching the line segments of a poly spline.
+            // A catmull-rom segment runs between its two inner control points, which can't be interpolated.
+            let samples = (0..=100)
+                .map(|i| match (spline_type, i) {
+                    (2, 0) => Ok(geometry[1]),
+                    (2, 100) => Ok(geometry[2]),
+                    (2, i) => interpolate_catmull_point(geometry, i as f32 * 0.01),
+                    (_, i) => interpolate_bezier_point(geometry, i as f32 * 0.01),
+                })
+                .collect::<VortexResult<Vec<BlenderPoint3>>>()?;
+
+            for pair in samples.windows(2) {
+                points_list.push((pair[0].x, pair[0].y, pair[0].z));
+                points_list.push((pair[1].x, pair[1].y, pair[1].z));
             }
         }
-        _ => println!("Error generating preview vertices for unknown spline type"),
+        _ => {
+            return Err(VortexError::UnsupportedOperation(format!(
+                "Can't generate preview vertices for spline type {}",
+                spline_type
+            )))
+        }
     }
 
-    points_list
+    Ok(points_list)
 }
 
 fn is_point_in_circle(point: &BlenderPoint2, circle_center: &BlenderPoint2, radius: f32) -> bool {
     distance_2d(point, circle_center) <= radius
 }
 
-pub fn is_point_legal(point: &BlenderPoint3) -> bool {
-    let cylinder_offset = BlenderPoint2 { x: 0.0, y: 0.0 };
-    let cylinder_ends: (f32, f32) = (0.0, 200.0);
-    let cylinder_radius: f32 = 200.0;
+pub fn is_point_legal(point: &BlenderPoint3, workspace: &WorkspaceConfig) -> bool {
+    let cylinder_offset = BlenderPoint2 {
+        x: workspace.center_x,
+        y: workspace.center_y,
+    };
 
-    is_point_in_circle(&point.into_bp2_xy(), &cylinder_offset, cylinder_radius)
-        && point.z > cylinder_ends.0
-        && point.z < cylinder_ends.1
+    is_point_in_circle(&point.into_bp2_xy(), &cylinder_offset, workspace.radius)
+        && point.z > workspace.z_min
+        && point.z < workspace.z_max
 }
 
-// Sort the particles into a chain of next-nearest distances to reduce the traversal distance for particle systems
-// Very naiive approach - TODO solve travelling salesman problem!
-pub fn sort_particles(particles: &mut Vec<BlenderParticle>) -> Vec<BlenderParticle> {
-    let mut sorted_particles = vec![];
-    sorted_particles.reserve(particles.len());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Randomly pick a starting point, Non-deterministic pathing through the particle cloud is desirable
-    // as it should 'fuzz' any artifacts influenced by transit moves.
-    let random_start = (rand::random::<f32>() * particles.len() as f32).floor() as usize;
-    sorted_particles.push(particles.remove(random_start));
+    fn points(coordinates: &[(f32, f32)]) -> Vec<BlenderPoint3> {
+        coordinates
+            .iter()
+            .map(|&(x, y)| BlenderPoint3 { x, y, z: 0.0 })
+            .collect()
+    }
 
-    while !particles.is_empty() {
-        let mut closest_dist = MAX;
-        let mut closest_index = None;
-        let check_p = sorted_particles.last().unwrap(); // search from the most recent sorted point
+    #[test]
+    fn collinear_points_are_merged() {
+        let line = points(&[(0.0, 0.0), (1.0, 0.0), (2.0, 0.01), (3.0, 0.0), (4.0, 0.0)]);
 
-        for (i, search_p) in particles.iter().enumerate() {
-            let dist = distance_3d(&check_p.location, &search_p.location);
+        assert_eq!(simplify_polyline(&line, 0.1), vec![0, 4]);
+    }
 
-            if dist < closest_dist {
-                closest_dist = dist;
-                closest_index = Some(i);
-            }
-        }
+    #[test]
+    fn points_beyond_tolerance_are_kept() {
+        let corner = points(&[(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (2.0, 1.0), (2.0, 2.0)]);
+        assert_eq!(simplify_polyline(&corner, 0.1), vec![0, 2, 4]);
 
-        // Take the closest point from this search pass, and move it into the sorted vector
-        if let Some(sorted_point_index) = closest_index {
-            sorted_particles.push(particles.remove(sorted_point_index));
-        }
+        // A bump just past the tolerance stays, one inside it goes
+        let bump = points(&[(0.0, 0.0), (1.0, 0.15), (2.0, 0.0)]);
+        assert_eq!(simplify_polyline(&bump, 0.1), vec![0, 1, 2]);
+        assert_eq!(simplify_polyline(&bump, 0.2), vec![0, 2]);
     }
 
-    sorted_particles
+    #[test]
+    fn endpoints_are_always_kept() {
+        // A closed loop starts and ends at the same point, so every other point is far from the line between them
+        let square = points(&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 0.0)]);
+        let kept = simplify_polyline(&square, 0.1);
+        assert_eq!(kept, vec![0, 1, 2, 3, 4]);
+
+        let short = points(&[(0.0, 0.0), (0.0, 0.0)]);
+        assert_eq!(simplify_polyline(&short, 10.0), vec![0, 1]);
+
+        // Without a tolerance nothing is removed
+        let line = points(&[(0.0, 0.0), (1.0, 0.0), (2.0, 0.0)]);
+        assert_eq!(simplify_polyline(&line, 0.0), vec![0, 1, 2]);
+
+        for tolerance in &[0.01, 0.5, 100.0] {
+            let kept = simplify_polyline(&square, *tolerance);
+            assert_eq!(kept[0], 0);
+            assert_eq!(kept[kept.len() - 1], square.len() - 1);
+        }
+    }
 }
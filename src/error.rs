@@ -0,0 +1,94 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Everything which can go wrong while importing, planning or exporting a collection
+#[derive(Debug)]
+pub enum VortexError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    JsonSchema {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    MissingUv {
+        path: PathBuf,
+        reason: String,
+    },
+    Image {
+        path: PathBuf,
+        reason: String,
+    },
+    Svg {
+        path: PathBuf,
+        reason: String,
+    },
+    EmptyGeometry(String),
+    UnsupportedOperation(String),
+    OutOfWorkspace {
+        motion_ids: Vec<u32>,
+    },
+    Config(String),
+}
+
+pub type VortexResult<T> = Result<T, VortexError>;
+
+impl VortexError {
+    pub fn io(path: &Path, source: io::Error) -> VortexError {
+        VortexError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn json(path: &Path, error: serde_json::Error) -> VortexError {
+        // serde_json appends the position to its message, it's reported separately here
+        let position = format!(" at line {} column {}", error.line(), error.column());
+        let message = error.to_string();
+
+        VortexError::JsonSchema {
+            path: path.to_path_buf(),
+            line: error.line(),
+            column: error.column(),
+            message: message.trim_end_matches(&position).to_string(),
+        }
+    }
+}
+
+impl fmt::Display for VortexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VortexError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            VortexError::JsonSchema {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            VortexError::MissingUv { path, reason } => {
+                write!(f, "UV map {} unavailable: {}", path.display(), reason)
+            }
+            VortexError::Image { path, reason } => write!(f, "{}: {}", path.display(), reason),
+            VortexError::Svg { path, reason } => write!(f, "{}: {}", path.display(), reason),
+            VortexError::EmptyGeometry(what) => write!(f, "Empty geometry: {}", what),
+            VortexError::UnsupportedOperation(what) => write!(f, "Unsupported operation: {}", what),
+            VortexError::OutOfWorkspace { motion_ids } => {
+                write!(f, "Moves outside the workspace: {:?}", motion_ids)
+            }
+            VortexError::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for VortexError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VortexError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -2,8 +2,10 @@ use std::fs;
 use std::path::Path;
 
 use crate::color_utils::*;
+use crate::error::*;
 use crate::export_types::*;
 use colorsys::Hsl;
+use serde::Serialize;
 
 use image::imageops::resize;
 use image::{FilterType, ImageBuffer, Rgb, RgbImage};
@@ -15,17 +17,27 @@ pub fn generate_header(title: String) -> EventMetadata {
     }
 }
 
-pub fn export_toolpath(write_path: &Path, data: DeltaEvents) {
-    let data_to_write = serde_json::to_string_pretty(&data).expect("Serialisation Failed");
-    fs::write(write_path, data_to_write).expect("Unable to write file");
+pub fn export_toolpath(write_path: &Path, data: DeltaEvents) -> VortexResult<()> {
+    export_json(write_path, &data)
 }
 
-pub fn export_vertices(write_path: &Path, data: Vec<(f32, f32, f32)>) {
-    let data_to_write = serde_json::to_string_pretty(&data).expect("Serialisation Failed");
-    fs::write(write_path, data_to_write).expect("Unable to write file");
+pub fn export_vertices(write_path: &Path, data: Vec<(f32, f32, f32)>) -> VortexResult<()> {
+    export_json(write_path, &data)
 }
 
-pub fn export_uv(write_path: &Path, data: Vec<Hsl>) {
+pub fn export_json<T: Serialize>(write_path: &Path, data: &T) -> VortexResult<()> {
+    let data_to_write =
+        serde_json::to_string_pretty(data).map_err(|e| VortexError::io(write_path, e.into()))?;
+    fs::write(write_path, data_to_write).map_err(|e| VortexError::io(write_path, e))
+}
+
+pub fn export_uv(write_path: &Path, data: Vec<Hsl>) -> VortexResult<()> {
+    if data.is_empty() {
+        return Err(VortexError::EmptyGeometry(
+            "No UV colours to export".to_string(),
+        ));
+    }
+
     let mut image_buffer: RgbImage = ImageBuffer::new(data.len() as u32, 16);
 
     for (x, _y, pixel) in image_buffer.enumerate_pixels_mut() {
@@ -44,7 +56,10 @@ pub fn export_uv(write_path: &Path, data: Vec<Hsl>) {
         FilterType::Gaussian,
     );
 
-    new_size.save(write_path).unwrap();
+    new_size.save(write_path).map_err(|e| VortexError::Image {
+        path: write_path.to_path_buf(),
+        reason: e.to_string(),
+    })
 }
 
 fn next_power_two(input: u32) -> u32 {
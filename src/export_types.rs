@@ -1,60 +1,81 @@
-use serde::Serialize;
-use serde_repr::Serialize_repr;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Serialize, Debug)]
+use crate::config::VortexConfig;
+use crate::fade_fitting::LightingReport;
+use crate::ordering::{CollectionOrder, OrderingReport};
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct DeltaEvents {
     pub metadata: EventMetadata,
     pub actions: Vec<ActionGroups>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct EventMetadata {
     pub name: String,
     #[serde(rename = "formatVersion")]
     pub format_version: String,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ActionGroups {
     pub delta: Vec<DeltaAction>,
     pub light: Vec<LightAction>,
     pub run: Vec<GenericAction>,
 
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     global_id: u32, // all moves, lights, extra actions need a unique global ID, as json doesn't guarantee order
 
-    #[serde(skip_serializing)]
-    move_time: u32,
-
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     move_barrier_id: u32,
 
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     move_barrier_reset: bool,
+
+    #[serde(skip)]
+    merged_moves: u32, // moves removed by merging near-collinear lines
+
+    #[serde(skip)]
+    color_error_max: f64, // furthest any UV colour is from the fade lighting it
+
+    #[serde(skip)]
+    color_error_total: f64,
+
+    #[serde(skip)]
+    color_samples: u32,
 }
 
 pub trait Actions {
-    fn new() -> ActionGroups;
+    fn new() -> Self;
 
     fn add_delta_action(&mut self, m: Motion);
     fn add_light_action(&mut self, l: Fade);
     fn add_generic_action(&mut self, a: String, p: String);
 
     fn get_next_global_id(&self) -> u32;
-    fn get_movement_duration(&self) -> u32;
     fn reset_barrier_id(&mut self);
+
+    fn add_merged_moves(&mut self, count: u32);
+    fn get_merged_moves(&self) -> u32;
+
+    fn add_color_errors(&mut self, errors: &[f64]);
+    fn get_lighting_report(&self) -> LightingReport;
 }
 
 impl Actions for ActionGroups {
-    fn new() -> ActionGroups {
+    fn new() -> Self {
         ActionGroups {
             delta: vec![],
             light: vec![],
             run: vec![],
             global_id: 0,
-            move_time: 0,
             move_barrier_id: 0,
             move_barrier_reset: true,
+            merged_moves: 0,
+            color_error_max: 0.0,
+            color_error_total: 0.0,
+            color_samples: 0,
         }
     }
 
@@ -67,13 +88,6 @@ impl Actions for ActionGroups {
             self.move_barrier_reset = false;
         }
 
-        // Accumulate movement time (don't count transit moves)
-        if m.motion_type != MotionInterpolationType::PointTransit
-            && m.motion_type != MotionInterpolationType::BezierCubic
-        {
-            self.move_time += m.duration;
-        }
-
         self.delta.push(DeltaAction {
             id: self.global_id,
             action: String::from("queue_movement"),
@@ -112,24 +126,46 @@ impl Actions for ActionGroups {
         self.global_id
     }
 
-    fn get_movement_duration(&self) -> u32 {
-        self.move_time
-    }
-
     fn reset_barrier_id(&mut self) {
         self.move_barrier_reset = true;
-        self.move_time = 0;
+    }
+
+    fn add_merged_moves(&mut self, count: u32) {
+        self.merged_moves += count;
+    }
+
+    fn get_merged_moves(&self) -> u32 {
+        self.merged_moves
+    }
+
+    fn add_color_errors(&mut self, errors: &[f64]) {
+        for &error in errors {
+            self.color_error_max = self.color_error_max.max(error);
+            self.color_error_total += error;
+        }
+        self.color_samples += errors.len() as u32;
+    }
+
+    fn get_lighting_report(&self) -> LightingReport {
+        LightingReport {
+            color_error_max: self.color_error_max,
+            color_error_mean: if self.color_samples > 0 {
+                self.color_error_total / f64::from(self.color_samples)
+            } else {
+                0.0
+            },
+        }
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct DeltaAction {
     pub id: u32,
     pub action: String,
     pub payload: Motion,
 }
 
-#[derive(Serialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
 #[repr(u8)]
 pub enum MotionInterpolationType {
     PointTransit = 0,
@@ -139,14 +175,14 @@ pub enum MotionInterpolationType {
     BezierCubic = 4,
 }
 
-#[derive(Serialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
 #[repr(u8)]
 pub enum MotionReferenceFrame {
     Absolute = 0,
     Relative = 1,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Motion {
     #[serde(rename = "type")]
     pub motion_type: MotionInterpolationType,
@@ -156,7 +192,7 @@ pub struct Motion {
     pub points: Vec<(f32, f32, f32)>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct LightAction {
     pub id: u32,
     pub action: String,
@@ -164,14 +200,14 @@ pub struct LightAction {
     pub comment: String,
 }
 
-#[derive(Serialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
 #[repr(u8)]
 pub enum LightAnimationType {
     ConstantOn = 0,
     LinearFade = 1,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Fade {
     #[serde(rename = "type")]
     pub animation_type: LightAnimationType,
@@ -191,7 +227,7 @@ impl Fade {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GenericAction {
     pub id: u32,
     pub action: String,
@@ -201,3 +237,76 @@ pub struct GenericAction {
     #[serde(rename = "waitFor")]
     pub wait_for: u32,
 }
+
+// The summary.json lists every planned collection for each frame of the animation
+#[derive(Serialize, Debug)]
+pub struct AnimationMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_path: Option<String>,
+    pub settings: VortexConfig,
+    pub collections: Vec<String>,
+    pub frames: Vec<FrameMetadata>,
+}
+
+impl AnimationMetadata {
+    pub fn failure_count(&self) -> usize {
+        self.frames.iter().map(|f| f.failures.len()).sum()
+    }
+
+    pub fn reused_count(&self) -> usize {
+        self.frames
+            .iter()
+            .flat_map(|f| &f.collections)
+            .filter(|c| c.reused)
+            .count()
+    }
+
+    pub fn collection_count(&self) -> usize {
+        self.frames.iter().map(|f| f.collections.len()).sum()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FrameMetadata {
+    pub frame_num: i32,
+    pub collections: Vec<FileMetadata>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<FailureMetadata>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FailureMetadata {
+    pub name: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileMetadata {
+    pub name: String,
+    pub toolpath_path: String,
+    pub duration: u32,
+    pub first_move: u32,
+    pub last_move: u32,
+    pub num_lights: u32,
+    pub num_moves: u32,
+    pub num_moves_unsimplified: u32, // before near-collinear lines were merged
+    pub ordering: OrderingReport,
+    #[serde(default)]
+    pub lighting: LightingReport, // how closely the fades follow the UV gradients
+    pub viewer_vertices_path: String,
+    pub viewer_uv_path: String,
+    #[serde(default)]
+    pub viewer_transit_path: String, // line segments for the transits between splines
+
+    // Only recorded when overrides make this collection differ from the animation's settings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings: Option<VortexConfig>,
+
+    // Whether the files from a previous build were reused because nothing changed
+    #[serde(default)]
+    pub reused: bool,
+
+    // How the collection was drawn, so the next frame can follow it
+    #[serde(skip)]
+    pub order: CollectionOrder,
+}
@@ -0,0 +1,212 @@
+use colorsys::Hsl;
+use serde::{Deserialize, Serialize};
+
+use crate::config::PlannerConfig;
+
+// How closely a collection's fades follow its UV gradients, in the units of the colour metric
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LightingReport {
+    pub color_error_max: f64,
+    pub color_error_mean: f64,
+}
+
+// The fewest linear fades which reproduce a gradient within tolerance
+pub(crate) struct GradientFit {
+    pub breakpoints: Vec<usize>, // indices of the colours where fades start and finish
+    pub errors: Vec<f64>,        // how far each colour is from what the LED shows as it's passed
+}
+
+// Split a gradient into the minimum number of linear fades where every sampled colour is within
+// tolerance of the fade it falls in. Times are when the effector passes each colour, as fades run
+// at an even pace in time rather than along the spline.
+//
+// A fade can't be extended one colour at a time, as moving its end changes the whole line, so every
+// run of the gradient is tried from every colour, keeping the shortest chain. Runs are only checked
+// when they'd reach their end with fewer fades than already found.
+pub(crate) fn fit_gradient(colors: &[Hsl], times: &[u32], config: &PlannerConfig) -> GradientFit {
+    let n = colors.len();
+    if n < 2 {
+        return GradientFit {
+            breakpoints: (0..n).collect(),
+            errors: vec![0.0; n],
+        };
+    }
+
+    // Fewest fades to reach each colour, and where the last of them started.
+    // Neighbouring colours can always be joined, as there's nothing between them.
+    let mut fades: Vec<usize> = (0..n).collect();
+    let mut previous: Vec<usize> = (0..n).map(|i| i.saturating_sub(1)).collect();
+
+    for start in 0..n - 1 {
+        let mut misfit = None;
+
+        for end in start + 2..n {
+            // Already reached with as few fades, so there's nothing to check
+            if fades[end] <= fades[start] + 1 {
+                continue;
+            }
+
+            // The colour which stopped a shorter fade usually stops a longer one, so it's tried first
+            misfit = first_misfit(colors, times, start, end, misfit, config);
+            if misfit.is_none() {
+                fades[end] = fades[start] + 1;
+                previous[end] = start;
+            }
+        }
+    }
+
+    let mut breakpoints = vec![n - 1];
+    while let Some(&end) = breakpoints.last() {
+        if end == 0 {
+            break;
+        }
+        breakpoints.push(previous[end]);
+    }
+    breakpoints.reverse();
+
+    let mut errors = vec![0.0; n];
+    for pair in breakpoints.windows(2) {
+        for i in pair[0] + 1..pair[1] {
+            errors[i] = config.color_metric.distance(
+                &colors[i],
+                &fade_color(colors, times, pair[0], pair[1], i, config),
+            );
+        }
+    }
+
+    GradientFit {
+        breakpoints,
+        errors,
+    }
+}
+
+// The first colour between start and end which is out of tolerance of a fade between them, if any
+fn first_misfit(
+    colors: &[Hsl],
+    times: &[u32],
+    start: usize,
+    end: usize,
+    hint: Option<usize>,
+    config: &PlannerConfig,
+) -> Option<usize> {
+    let misses = |i: usize| {
+        let error = config.color_metric.distance(
+            &colors[i],
+            &fade_color(colors, times, start, end, i, config),
+        );
+        error > config.cluster_threshold
+    };
+
+    hint.into_iter()
+        .chain(start + 1..end)
+        .find(|&i| start < i && i < end && misses(i))
+}
+
+// The colour a fade from start to end reaches at colour i, blended in the configured fade space
+fn fade_color(
+    colors: &[Hsl],
+    times: &[u32],
+    start: usize,
+    end: usize,
+    i: usize,
+    config: &PlannerConfig,
+) -> Hsl {
+    let span = times[end].saturating_sub(times[start]);
+    let weight = if span > 0 {
+        f64::from(times[i].saturating_sub(times[start])) / f64::from(span)
+    } else {
+        (i - start) as f64 / (end - start) as f64
+    };
+
+    config.fade_space.mix(&colors[start], &colors[end], weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hsl(h: f64, s: f64, l: f64) -> Hsl {
+        Hsl::new(h, s, l, Option::from(1.0))
+    }
+
+    fn even_times(n: usize) -> Vec<u32> {
+        (0..n as u32).map(|i| i * 10).collect()
+    }
+
+    // Fewest fades found by trying every pair of breakpoints
+    fn brute_force_fades(colors: &[Hsl], times: &[u32], config: &PlannerConfig) -> usize {
+        let n = colors.len();
+        let mut fades = vec![usize::MAX; n];
+        fades[0] = 0;
+
+        for end in 1..n {
+            for start in 0..end {
+                let fits = (start + 1..end).all(|i| {
+                    let error = config.color_metric.distance(
+                        &colors[i],
+                        &fade_color(colors, times, start, end, i, config),
+                    );
+                    error <= config.cluster_threshold
+                });
+                if fits && fades[start] != usize::MAX {
+                    fades[end] = fades[end].min(fades[start] + 1);
+                }
+            }
+        }
+
+        fades[n - 1]
+    }
+
+    #[test]
+    fn straight_gradient_is_one_fade() {
+        let config = PlannerConfig::default();
+        let colors: Vec<Hsl> = (0..20).map(|i| hsl(200.0, 80.0, 20.0 + i as f64)).collect();
+
+        let fit = fit_gradient(&colors, &even_times(20), &config);
+
+        assert_eq!(fit.breakpoints, vec![0, 19]);
+        assert!(fit.errors.iter().all(|&e| e <= config.cluster_threshold));
+    }
+
+    #[test]
+    fn single_colour_has_no_fades() {
+        let config = PlannerConfig::default();
+        let fit = fit_gradient(&[hsl(0.0, 100.0, 50.0)], &[0], &config);
+
+        assert_eq!(fit.breakpoints, vec![0]);
+        assert_eq!(fit.errors, vec![0.0]);
+    }
+
+    #[test]
+    fn fit_is_minimal_and_within_tolerance() {
+        let config = PlannerConfig::default();
+
+        for seed in 0..20 {
+            // Wavy gradients with uneven timing, which need several fades
+            let n = 24;
+            let colors: Vec<Hsl> = (0..n)
+                .map(|i| {
+                    let t = i as f64 * 0.25 + seed as f64 * 0.37;
+                    hsl(
+                        (180.0 + 20.0 * t.sin()).rem_euclid(360.0),
+                        60.0 + 10.0 * (t * 0.7).cos(),
+                        45.0 + 5.0 * (t * 1.3 + seed as f64).sin(),
+                    )
+                })
+                .collect();
+            let times: Vec<u32> = (0..n as u32).map(|i| i * 10 + (i * seed) % 7).collect();
+
+            let fit = fit_gradient(&colors, &times, &config);
+
+            assert_eq!(fit.breakpoints.first(), Some(&0));
+            assert_eq!(fit.breakpoints.last(), Some(&(n - 1)));
+            assert_eq!(
+                fit.breakpoints.len() - 1,
+                brute_force_fades(&colors, &times, &config),
+                "seed {}",
+                seed
+            );
+            assert!(fit.errors.iter().all(|&e| e <= config.cluster_threshold));
+        }
+    }
+}
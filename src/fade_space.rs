@@ -0,0 +1,317 @@
+use colorsys::Hsl;
+use serde::{Deserialize, Serialize};
+
+use crate::color_utils::{hsl_from_linear_rgb, linear_rgb_from_hsl};
+use crate::config::PlannerConfig;
+
+// Colours this close to grey, black or white (in percent) don't have a hue worth following
+const ACHROMATIC: f64 = 0.5;
+
+// How many points along a keyframe are checked against the intended blend
+const KEYFRAME_SAMPLES: usize = 8;
+
+// Keyframes are halved at most this many times, so at most 64 are emitted for one fade
+const MAX_SPLITS: u32 = 6;
+
+// How a fade should blend between its colours.
+// The firmware fades each component of the LED's HSL tuple on its own, so fades are split into
+// keyframes wherever it would stray from the intended blend.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FadeSpace {
+    Hsl,       // hue takes the shortest way round the colour wheel
+    LinearRgb, // mixes like the light from the LED's channels
+    Oklab,     // perceptually even steps, keeping the hue of blues steady
+}
+
+impl FadeSpace {
+    // The colour a fraction t of the way through a fade
+    pub fn mix(self, from: &Hsl, to: &Hsl, t: f64) -> Hsl {
+        match self {
+            FadeSpace::Hsl => {
+                let (start, end) = hue_path(from, to);
+
+                Hsl::new(
+                    lerp(start, end, t).rem_euclid(360.0),
+                    lerp(from.get_saturation(), to.get_saturation(), t),
+                    lerp(from.get_lightness(), to.get_lightness(), t),
+                    Option::from(1.0),
+                )
+            }
+            FadeSpace::LinearRgb => {
+                let (a, b) = (linear_rgb_from_hsl(from), linear_rgb_from_hsl(to));
+                hsl_from_linear_rgb([0, 1, 2].map(|c| lerp(a[c], b[c], t)))
+            }
+            FadeSpace::Oklab => {
+                let (a, b) = (oklab_from_hsl(from), oklab_from_hsl(to));
+                hsl_from_oklab([0, 1, 2].map(|c| lerp(a[c], b[c], t)))
+            }
+        }
+    }
+}
+
+// A fade the firmware can run as it is, between LED tuples with each component in [0, 1]
+pub(crate) struct Keyframe {
+    pub from: (f32, f32, f32),
+    pub to: (f32, f32, f32),
+    pub duration: u32,
+}
+
+// Split a fade into keyframes which the firmware's HSL interpolation follows within tolerance.
+// Hues are unwrapped so the LED goes the short way round the colour wheel, with a keyframe where
+// it passes red, as the firmware can only fade between hues in [0, 1].
+pub(crate) fn firmware_keyframes(
+    from: &Hsl,
+    to: &Hsl,
+    duration: u32,
+    config: &PlannerConfig,
+) -> Vec<Keyframe> {
+    let fractions = split_fade(from, to, duration, config);
+    let time_at = |t: f64| (t * f64::from(duration)).round() as u32;
+
+    let mut keyframes = vec![];
+    for pair in fractions.windows(2) {
+        let (t0, t1) = (pair[0], pair[1]);
+        // Keyframes are calibrated before the hue path is found, as the profile can shift hues
+        let start = config.led_color(&config.fade_space.mix(from, to, t0));
+        let end = config.led_color(&config.fade_space.mix(from, to, t1));
+
+        let (h0, h1) = hue_path(&start, &end);
+        let tuple = |hue: f64, color: &Hsl| {
+            (
+                (hue / 360.0) as f32,
+                (color.get_saturation() / 100.0) as f32,
+                (color.get_lightness() / 100.0) as f32,
+            )
+        };
+
+        // Where the hue crosses red, if it does, as a fraction of this keyframe
+        let wrap = if h1 < 0.0 {
+            Some((0.0, 360.0))
+        } else if h1 > 360.0 {
+            Some((360.0, 0.0))
+        } else {
+            None
+        };
+
+        match wrap {
+            Some((edge, other)) if h0 != edge => {
+                let f = (edge - h0) / (h1 - h0);
+                let crossing = FadeSpace::Hsl.mix(&start, &end, f);
+                let t = lerp(t0, t1, f);
+
+                keyframes.push(Keyframe {
+                    from: tuple(h0, &start),
+                    to: tuple(edge, &crossing),
+                    duration: time_at(t) - time_at(t0),
+                });
+                keyframes.push(Keyframe {
+                    from: tuple(other, &crossing),
+                    to: tuple(h1 - edge + other, &end),
+                    duration: time_at(t1) - time_at(t),
+                });
+            }
+            // Starting on red, the start can be written as the other end of the hue range instead
+            Some((edge, other)) => keyframes.push(Keyframe {
+                from: tuple(other, &start),
+                to: tuple(h1 - edge + other, &end),
+                duration: time_at(t1) - time_at(t0),
+            }),
+            None => keyframes.push(Keyframe {
+                from: tuple(h0, &start),
+                to: tuple(h1, &end),
+                duration: time_at(t1) - time_at(t0),
+            }),
+        }
+    }
+
+    keyframes
+}
+
+// Halve keyframes until the firmware follows the fade closely enough, returning the fraction of the
+// fade where each keyframe starts and finishes
+fn split_fade(from: &Hsl, to: &Hsl, duration: u32, config: &PlannerConfig) -> Vec<f64> {
+    let space = config.fade_space;
+    let mut fractions = vec![0.0];
+
+    // Keyframes still to check, leftmost last, with how many times they've been halved
+    let mut pending = vec![(1.0, 0)];
+    while let Some((t1, splits)) = pending.pop() {
+        let t0 = fractions[fractions.len() - 1];
+        let (start, end) = (space.mix(from, to, t0), space.mix(from, to, t1));
+
+        let strays = (1..KEYFRAME_SAMPLES).any(|k| {
+            let f = k as f64 / KEYFRAME_SAMPLES as f64;
+            let intended = space.mix(from, to, lerp(t0, t1, f));
+            let firmware = FadeSpace::Hsl.mix(&start, &end, f);
+            config.color_metric.distance(&intended, &firmware) > config.cluster_threshold
+        });
+
+        // Keyframes can't be shorter than the firmware's 1 ms timing
+        let too_short = (t1 - t0) * f64::from(duration) < 2.0;
+
+        if strays && !too_short && splits < MAX_SPLITS {
+            pending.push((t1, splits + 1));
+            pending.push(((t0 + t1) / 2.0, splits + 1));
+        } else {
+            fractions.push(t1);
+        }
+    }
+
+    fractions
+}
+
+// Hues in degrees at either end of the shortest way between two colours, with the end's hue
+// unwrapped so they're no more than 180° apart. Grey ends take the hue of the other end.
+fn hue_path(from: &Hsl, to: &Hsl) -> (f64, f64) {
+    let start = if achromatic(from) {
+        to.get_hue()
+    } else {
+        from.get_hue()
+    };
+    let start = start.rem_euclid(360.0);
+    let end = if achromatic(to) { start } else { to.get_hue() };
+
+    let delta = (end - start + 180.0).rem_euclid(360.0) - 180.0;
+    (start, start + delta)
+}
+
+fn achromatic(color: &Hsl) -> bool {
+    color.get_saturation() < ACHROMATIC
+        || color.get_lightness() < ACHROMATIC
+        || color.get_lightness() > 100.0 - ACHROMATIC
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+// Björn Ottosson's OKLab, from linear sRGB
+fn oklab_from_hsl(color: &Hsl) -> [f64; 3] {
+    let [r, g, b] = linear_rgb_from_hsl(color);
+
+    let lms = [
+        0.412_221_470_8 * r + 0.536_332_536_3 * g + 0.051_445_992_9 * b,
+        0.211_903_498_2 * r + 0.680_699_545_1 * g + 0.107_396_956_6 * b,
+        0.088_302_461_9 * r + 0.281_718_837_6 * g + 0.629_978_700_5 * b,
+    ]
+    .map(f64::cbrt);
+
+    [
+        0.210_454_255_3 * lms[0] + 0.793_617_785_0 * lms[1] - 0.004_072_046_8 * lms[2],
+        1.977_998_495_1 * lms[0] - 2.428_592_205_0 * lms[1] + 0.450_593_709_9 * lms[2],
+        0.025_904_037_1 * lms[0] + 0.782_771_766_2 * lms[1] - 0.808_675_766_0 * lms[2],
+    ]
+}
+
+fn hsl_from_oklab(lab: [f64; 3]) -> Hsl {
+    let lms = [
+        lab[0] + 0.396_337_777_4 * lab[1] + 0.215_803_757_3 * lab[2],
+        lab[0] - 0.105_561_345_8 * lab[1] - 0.063_854_172_8 * lab[2],
+        lab[0] - 0.089_484_177_5 * lab[1] - 1.291_485_548_0 * lab[2],
+    ]
+    .map(|c| c.powi(3));
+
+    hsl_from_linear_rgb([
+        4.076_741_662_1 * lms[0] - 3.307_711_591_3 * lms[1] + 0.230_969_929_2 * lms[2],
+        -1.268_438_004_6 * lms[0] + 2.609_757_401_1 * lms[1] - 0.341_319_396_5 * lms[2],
+        -0.004_196_086_3 * lms[0] - 0.703_418_614_7 * lms[1] + 1.707_614_701_0 * lms[2],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hsl(hue: f64) -> Hsl {
+        Hsl::new(hue, 100.0, 50.0, None)
+    }
+
+    fn from_tuple((h, s, l): (f32, f32, f32)) -> Hsl {
+        Hsl::new(
+            f64::from(h) * 360.0,
+            f64::from(s) * 100.0,
+            f64::from(l) * 100.0,
+            None,
+        )
+    }
+
+    fn config(fade_space: FadeSpace) -> PlannerConfig {
+        PlannerConfig {
+            fade_space,
+            ..PlannerConfig::default()
+        }
+    }
+
+    #[test]
+    fn fades_across_red_are_split_where_they_wrap() {
+        let config = config(FadeSpace::Hsl);
+        let keyframes = firmware_keyframes(&hsl(10.0), &hsl(330.0), 400, &config);
+
+        // Down to red, then on from the top of the hue range, rather than back round through green
+        assert_eq!(keyframes.len(), 2);
+        assert!((keyframes[0].from.0 - 10.0 / 360.0).abs() < 1e-5);
+        assert_eq!(keyframes[0].to.0, 0.0);
+        assert_eq!(keyframes[1].from.0, 1.0);
+        assert!((keyframes[1].to.0 - 330.0 / 360.0).abs() < 1e-5);
+        assert_eq!(keyframes[0].duration, 100);
+        assert_eq!(keyframes[1].duration, 300);
+
+        for keyframe in &keyframes {
+            assert!((keyframe.to.0 - keyframe.from.0).abs() <= 40.0 / 360.0);
+            assert!((0.0..=1.0).contains(&keyframe.from.0) && (0.0..=1.0).contains(&keyframe.to.0));
+        }
+
+        // The other way round wraps from the top of the range
+        let keyframes = firmware_keyframes(&hsl(330.0), &hsl(10.0), 400, &config);
+        assert_eq!(keyframes.len(), 2);
+        assert_eq!(keyframes[0].to.0, 1.0);
+        assert_eq!(keyframes[1].from.0, 0.0);
+    }
+
+    #[test]
+    fn fades_starting_on_red_start_from_the_nearer_end() {
+        let keyframes = firmware_keyframes(&hsl(0.0), &hsl(330.0), 400, &config(FadeSpace::Hsl));
+
+        assert_eq!(keyframes.len(), 1);
+        assert_eq!(keyframes[0].from.0, 1.0);
+        assert!((keyframes[0].to.0 - 330.0 / 360.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn blends_are_split_until_the_firmware_follows_them() {
+        // Keyframe boundaries land on whole milliseconds
+        let duration = 64_000;
+        let (red, blue) = (hsl(0.0), hsl(240.0));
+
+        assert_eq!(
+            firmware_keyframes(&red, &blue, duration, &config(FadeSpace::Hsl)).len(),
+            1
+        );
+
+        for &space in &[FadeSpace::LinearRgb, FadeSpace::Oklab] {
+            let config = config(space);
+            let keyframes = firmware_keyframes(&red, &blue, duration, &config);
+            assert!(keyframes.len() > 1);
+
+            let mut time = 0;
+            for keyframe in &keyframes {
+                let (start, end) = (from_tuple(keyframe.from), from_tuple(keyframe.to));
+                for k in 1..KEYFRAME_SAMPLES {
+                    let f = k as f64 / KEYFRAME_SAMPLES as f64;
+                    let t =
+                        (f64::from(time) + f * f64::from(keyframe.duration)) / f64::from(duration);
+                    let firmware = FadeSpace::Hsl.mix(&start, &end, f);
+                    let intended = space.mix(&red, &blue, t);
+
+                    assert!(
+                        config.color_metric.distance(&intended, &firmware)
+                            <= config.cluster_threshold
+                    );
+                }
+                time += keyframe.duration;
+            }
+            assert_eq!(time, duration);
+        }
+    }
+}
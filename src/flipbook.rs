@@ -0,0 +1,247 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use colorsys::Hsl;
+use gif::SetParameter;
+use image::{GenericImage, ImageBuffer, Rgb, RgbImage};
+use rayon::prelude::*;
+
+use crate::color_utils::hsl_to_rgb8;
+use crate::config::{FlipbookConfig, RenderConfig};
+use crate::error::*;
+use crate::export_types::*;
+use crate::import_data::load_toolpath;
+use crate::import_types::*;
+use crate::preview::generate_toolpath_preview;
+use crate::render::Camera;
+
+const TRANSIT_COLOR: Rgb<u8> = Rgb([70, 70, 70]);
+
+/// The files written by [`write_flipbook`].
+pub struct Flipbook {
+    pub frames: Vec<PathBuf>,
+    pub contact_sheet: PathBuf,
+    pub animation: PathBuf,
+}
+
+/// Sketch every frame's preview from the render camera, to scrub through a planned animation.
+///
+/// Each frame's preview polylines are replayed from its collections' toolpaths and drawn into
+/// `flipbook/<frame>.png`, then tiled into `flipbook/contact_sheet.png` and looped in `flipbook/animation.gif`.
+pub fn write_flipbook(
+    summary: &AnimationMetadata,
+    output_root: &Path,
+    camera: &RenderConfig,
+    config: &FlipbookConfig,
+) -> VortexResult<Flipbook> {
+    config.validate()?;
+
+    // The render camera's viewpoint, at the flipbook's size
+    let camera = Camera::new(&RenderConfig {
+        width: config.width,
+        height: config.height,
+        ..camera.clone()
+    });
+
+    let folder = output_root.join("flipbook");
+    fs::create_dir_all(&folder).map_err(|e| VortexError::io(&folder, e))?;
+
+    let sketches: Vec<(PathBuf, RgbImage)> = summary
+        .frames
+        .par_iter()
+        .map(|frame| {
+            let sketch = sketch_frame(frame, &summary.collections, &camera, config)?;
+
+            let path = folder.join(format!("{:04}.png", frame.frame_num));
+            sketch.save(&path).map_err(|e| image_error(&path, e))?;
+
+            Ok((path, sketch))
+        })
+        .collect::<VortexResult<_>>()?;
+
+    if sketches.is_empty() {
+        return Err(VortexError::EmptyGeometry(
+            "No frames to put in the flipbook".to_string(),
+        ));
+    }
+
+    let contact_sheet = folder.join("contact_sheet.png");
+    write_contact_sheet(&contact_sheet, &sketches, config)?;
+
+    let animation = folder.join("animation.gif");
+    write_gif(&animation, &sketches, config)?;
+
+    Ok(Flipbook {
+        frames: sketches.into_iter().map(|(path, _)| path).collect(),
+        contact_sheet,
+        animation,
+    })
+}
+
+// Draw the planned preview of every collection in a frame, with transits underneath
+fn sketch_frame(
+    frame: &FrameMetadata,
+    collection_names: &[String],
+    camera: &Camera,
+    config: &FlipbookConfig,
+) -> VortexResult<RgbImage> {
+    let mut sketch = RgbImage::new(config.width, config.height);
+
+    let mut previews = vec![];
+    for collection in &frame.collections {
+        let toolpath = load_toolpath(Path::new(&collection.toolpath_path))?;
+        for events in &toolpath.actions {
+            previews.push((&collection.name, generate_toolpath_preview(events)?));
+        }
+    }
+
+    if config.show_transits {
+        for (_, preview) in &previews {
+            for pair in preview.transits.chunks_exact(2) {
+                draw_line(&mut sketch, camera, pair[0], pair[1], TRANSIT_COLOR);
+            }
+        }
+    }
+
+    for (name, preview) in &previews {
+        // Collections keep the same colour from frame to frame
+        let collection_color = collection_names
+            .iter()
+            .position(|n| n == *name)
+            .map(collection_color);
+
+        for (pair, colors) in preview
+            .vertices
+            .chunks_exact(2)
+            .zip(preview.colors.chunks_exact(2))
+        {
+            let color = match &collection_color {
+                Some(color) if config.collection_colors => *color,
+                _ => Rgb(rgb8_array(&colors[0])),
+            };
+            draw_line(&mut sketch, camera, pair[0], pair[1], color);
+        }
+    }
+
+    Ok(sketch)
+}
+
+// Spread hues around the colour wheel by the golden angle, so neighbouring collections contrast
+fn collection_color(index: usize) -> Rgb<u8> {
+    let hue = (index as f64 * 137.508) % 360.0;
+    Rgb(rgb8_array(&Hsl::new(hue, 80.0, 55.0, None)))
+}
+
+fn rgb8_array(color: &Hsl) -> [u8; 3] {
+    let (r, g, b) = hsl_to_rgb8(color);
+    [r, g, b]
+}
+
+// Step along the projected line, keeping the brightest colour where lines cross
+fn draw_line(
+    sketch: &mut RgbImage,
+    camera: &Camera,
+    a: (f32, f32, f32),
+    b: (f32, f32, f32),
+    color: Rgb<u8>,
+) {
+    let (a, b) = match (
+        camera.project(BlenderPoint3::from(a)),
+        camera.project(BlenderPoint3::from(b)),
+    ) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return,
+    };
+
+    let pixels = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+    let steps = (pixels * 2.0).ceil().clamp(1.0, 100_000.0) as usize;
+
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let x = (a.0 + (b.0 - a.0) * t).floor();
+        let y = (a.1 + (b.1 - a.1) * t).floor();
+        if x < 0.0 || y < 0.0 || x >= sketch.width() as f32 || y >= sketch.height() as f32 {
+            continue;
+        }
+
+        let pixel = sketch.get_pixel_mut(x as u32, y as u32);
+        for c in 0..3 {
+            pixel.0[c] = pixel.0[c].max(color.0[c]);
+        }
+    }
+}
+
+fn write_contact_sheet(
+    path: &Path,
+    sketches: &[(PathBuf, RgbImage)],
+    config: &FlipbookConfig,
+) -> VortexResult<()> {
+    let columns = config.columns.min(sketches.len());
+    let rows = sketches.len().div_ceil(columns);
+
+    let mut sheet: RgbImage =
+        ImageBuffer::new(config.width * columns as u32, config.height * rows as u32);
+
+    for (i, (_, sketch)) in sketches.iter().enumerate() {
+        let x = (i % columns) as u32 * config.width;
+        let y = (i / columns) as u32 * config.height;
+        sheet
+            .copy_from(sketch, x, y)
+            .then_some(())
+            .ok_or_else(|| VortexError::Image {
+                path: path.to_path_buf(),
+                reason: "frame doesn't fit on the contact sheet".to_string(),
+            })?;
+    }
+
+    sheet.save(path).map_err(|e| image_error(path, e))
+}
+
+fn write_gif(
+    path: &Path,
+    sketches: &[(PathBuf, RgbImage)],
+    config: &FlipbookConfig,
+) -> VortexResult<()> {
+    let file = File::create(path).map_err(|e| VortexError::io(path, e))?;
+
+    let mut encoder = gif::Encoder::new(
+        BufWriter::new(file),
+        config.width as u16,
+        config.height as u16,
+        &[],
+    )
+    .map_err(|e| VortexError::io(path, e))?;
+    encoder
+        .set(gif::Repeat::Infinite)
+        .map_err(|e| VortexError::io(path, e))?;
+
+    // Gif delays are in hundredths of a second
+    let delay = (config.frame_delay_ms / 10).min(u32::from(u16::MAX)) as u16;
+
+    let frames: Vec<gif::Frame> = sketches
+        .par_iter()
+        .map(|(_, sketch)| {
+            let pixels: &[u8] = sketch;
+            let mut frame =
+                gif::Frame::from_rgb_speed(config.width as u16, config.height as u16, pixels, 10);
+            frame.delay = delay;
+            frame
+        })
+        .collect();
+
+    for frame in &frames {
+        encoder
+            .write_frame(frame)
+            .map_err(|e| VortexError::io(path, e))?;
+    }
+
+    Ok(())
+}
+
+fn image_error(path: &Path, error: impl std::fmt::Display) -> VortexError {
+    VortexError::Image {
+        path: path.to_path_buf(),
+        reason: error.to_string(),
+    }
+}
@@ -1,4 +1,4 @@
-use std::fs::File;
+use std::fs;
 use std::path::Path;
 
 extern crate image;
@@ -8,74 +8,159 @@ use image::{imageops, GenericImageView};
 extern crate colorsys;
 use colorsys::{Hsl, Rgb};
 
+use crate::config::{ImportConfig, WorkspaceConfig};
 use crate::delta_utils::*;
+use crate::error::*;
+use crate::export_types::DeltaEvents;
 use crate::import_types::*;
+use crate::nurbs::{generate_knots, validate_nurbs};
 
-const BLENDER_TO_MILLIMETERS_SCALE_FACTOR: f32 = 100.0;
-const BLENDER_Z_OFFSET_MILLIMETERS: f32 = 30.0;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct TypeTag {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+fn parse_blender_json(input_path: &Path, contents: &str) -> VortexResult<BlenderData> {
+    serde_json::from_str(contents).map_err(|error| {
+        // Errors inside internally tagged enums lose their line/column, so parse again as the
+        // tagged type to find where the problem is
+        let positioned = match serde_json::from_str::<TypeTag>(contents) {
+            Ok(tag) => match tag.kind.as_str() {
+                "poly" => serde_json::from_str::<BlenderPoly>(contents).err(),
+                "nurbs" => serde_json::from_str::<BlenderNURBS>(contents).err(),
+                "bezier" => serde_json::from_str::<BlenderBezier>(contents).err(),
+                "particles" => serde_json::from_str::<BlenderParticles>(contents).err(),
+                _ => None,
+            },
+            Err(tag_error) => Some(tag_error),
+        };
+
+        VortexError::json(input_path, positioned.unwrap_or(error))
+    })
+}
 
 // Parses the JSON spline data generated by the Blender python export script
 // Finds the UV map referenced in the JSON file, scrapes image data
 // Returns the spline and illumination data
-pub fn load_blender_data(input_path: &Path) -> BlenderData {
-    let folder_root = input_path
-        .parent()
-        .expect("Error getting parent path of Blender JSON file");
+pub fn load_blender_data(
+    input_path: &Path,
+    config: &ImportConfig,
+    workspace: &WorkspaceConfig,
+) -> VortexResult<BlenderData> {
+    let folder_root = input_path.parent().unwrap_or_else(|| Path::new("."));
 
-    let json_file = File::open(input_path).expect("Blender JSON file not found");
+    let contents = fs::read_to_string(input_path).map_err(|e| VortexError::io(input_path, e))?;
+
+    log::debug!("Parsing {}", input_path.display());
+
+    let blender_data = parse_blender_json(input_path, &contents)?;
+
+    prepare_blender_data(blender_data, Some(folder_root), config, workspace).map_err(|e| match e {
+        VortexError::EmptyGeometry(what) => {
+            VortexError::EmptyGeometry(format!("{}: {}", input_path.display(), what))
+        }
+        other => other,
+    })
+}
 
-    println!("About to parse {:?}", folder_root);
+// Read back a toolpath written by the planner
+pub fn load_toolpath(input_path: &Path) -> VortexResult<DeltaEvents> {
+    let contents = fs::read_to_string(input_path).map_err(|e| VortexError::io(input_path, e))?;
 
-    let blender_data: BlenderData =
-        serde_json::from_reader(json_file).expect("Blender JSON Parsing Failed");
+    serde_json::from_str(&contents).map_err(|e| VortexError::json(input_path, e))
+}
 
+// Apply the import transforms to spline data as exported by Blender, which may have come from disk or memory.
+// UV maps are loaded relative to uv_folder, unless the spline already has colours assigned.
+pub fn prepare_blender_data(
+    blender_data: BlenderData,
+    uv_folder: Option<&Path>,
+    config: &ImportConfig,
+    workspace: &WorkspaceConfig,
+) -> VortexResult<BlenderData> {
     // Apply coordinate transforms like scaling/offsets
     // Grab and apply UV data as required
+    // TODO work out how to put close_loop(), scale_points(), and offset_points() here once, rather than requiring type-based incantation
     match blender_data {
         BlenderData::PolySpline(bp) => {
             let mut p: BlenderPoly = bp.clone();
 
-            p.close_loop();
-            p.scale_points(BLENDER_TO_MILLIMETERS_SCALE_FACTOR);
-            p.offset_points(0.0, 0.0, BLENDER_Z_OFFSET_MILLIMETERS);
+            if p.points.len() < BlenderPoly::get_recommended_window_size() {
+                return Err(VortexError::EmptyGeometry(format!(
+                    "spline has {} points",
+                    p.points.len()
+                )));
+            }
 
-            let uv_full_path = folder_root.join(Path::new(&p.uv_path));
-            p.color = match load_uv(uv_full_path.as_path()) {
-                Ok(contents) => convert_uv(contents),
-                Err(_error) => generate_placeholder_uv_data(),
-            };
+            p.close_loop()?;
+            p.scale_points(config.scale_factor);
+            p.offset_points(0.0, 0.0, config.z_offset);
+
+            if p.color.is_empty() {
+                p.color = load_spline_colors(uv_folder, &p.uv_path)?;
+            }
 
-            return BlenderData::PolySpline(p);
+            Ok(BlenderData::PolySpline(p))
         }
         BlenderData::NURBSSpline(bp) => {
             let mut p: BlenderNURBS = bp.clone();
 
-            p.close_loop();
-            p.scale_points(BLENDER_TO_MILLIMETERS_SCALE_FACTOR);
-            p.offset_points(0.0, 0.0, BLENDER_Z_OFFSET_MILLIMETERS);
+            if p.points.len() < p.order.max(BlenderNURBS::get_recommended_window_size()) {
+                return Err(VortexError::EmptyGeometry(format!(
+                    "order {} spline has {} points",
+                    p.order,
+                    p.points.len()
+                )));
+            }
 
-            let uv_full_path = folder_root.join(Path::new(&p.uv_path));
-            p.color = match load_uv(uv_full_path.as_path()) {
-                Ok(contents) => convert_uv(contents),
-                Err(_error) => generate_placeholder_uv_data(),
-            };
+            p.close_loop()?;
+            if p.knots.is_empty() {
+                p.knots = generate_knots(p.points.len(), p.order, p.use_endpoint, p.use_bezier);
+            }
+            validate_nurbs(&p)?;
+            p.scale_points(config.scale_factor);
+            p.offset_points(0.0, 0.0, config.z_offset);
 
-            return BlenderData::NURBSSpline(p);
+            if p.color.is_empty() {
+                p.color = load_spline_colors(uv_folder, &p.uv_path)?;
+            }
+
+            Ok(BlenderData::NURBSSpline(p))
+        }
+        BlenderData::BezierSpline(bp) => {
+            let mut p: BlenderBezier = bp.clone();
+
+            if p.points.len() < BlenderBezier::get_recommended_window_size() {
+                return Err(VortexError::EmptyGeometry(format!(
+                    "spline has {} points",
+                    p.points.len()
+                )));
+            }
+
+            p.close_loop()?;
+            p.scale_points(config.scale_factor);
+            p.offset_points(0.0, 0.0, config.z_offset);
+
+            if p.color.is_empty() {
+                p.color = load_spline_colors(uv_folder, &p.uv_path)?;
+            }
+
+            Ok(BlenderData::BezierSpline(p))
         }
         BlenderData::Particles(bp) => {
             let mut p: BlenderParticles = bp.clone();
 
-            p.scale_points(BLENDER_TO_MILLIMETERS_SCALE_FACTOR);
-            p.offset_points(0.0, 0.0, BLENDER_Z_OFFSET_MILLIMETERS);
+            p.scale_points(config.scale_factor);
+            p.offset_points(0.0, 0.0, config.z_offset);
 
             // Remove particles outside the workspace. Particles with a trail in legal space are retained
-            p.particles
-                .retain(|x| is_point_legal(&x.location) || is_point_legal(&x.prev_location));
-
-            if !p.particles.is_empty()
-            {
-                p.particles = sort_particles(&mut p.particles);
-            }
+            p.particles.retain(|x| {
+                is_point_legal(&x.location, workspace)
+                    || is_point_legal(&x.prev_location, workspace)
+            });
 
             let rgb = Rgb::from(&(
                 f64::from(p.color_rgba.0) * 255.0,
@@ -85,16 +170,34 @@ pub fn load_blender_data(input_path: &Path) -> BlenderData {
             let hsl = Hsl::from(&rgb);
             p.color = vec![hsl; 1];
 
-            return BlenderData::Particles(p);
+            Ok(BlenderData::Particles(p))
         }
-    };
+    }
+}
 
-    // TODO work out how to put close_loop(), scale_points(), and offset_points() here once, rather than requiring type-based incantation
+fn load_uv(input_path: &Path) -> VortexResult<DynamicImage> {
+    image::open(input_path).map_err(|e| VortexError::MissingUv {
+        path: input_path.to_path_buf(),
+        reason: e.to_string(),
+    })
 }
 
-fn load_uv(input_path: &Path) -> Result<DynamicImage, image::ImageError> {
-    let img = image::open(input_path)?;
-    Ok(img)
+fn load_spline_colors(uv_folder: Option<&Path>, uv_path: &str) -> VortexResult<Vec<Hsl>> {
+    match uv_folder {
+        Some(folder) => load_uv_colors(folder.join(uv_path).as_path()),
+        None => Ok(generate_placeholder_uv_data()),
+    }
+}
+
+// A missing or unreadable UV map fails the spline, but maps too small to fade between use the placeholder
+fn load_uv_colors(input_path: &Path) -> VortexResult<Vec<Hsl>> {
+    let colors = convert_uv(load_uv(input_path)?);
+
+    if colors.len() >= 2 {
+        Ok(colors)
+    } else {
+        Ok(generate_placeholder_uv_data())
+    }
 }
 
 // The blender exported UV map is a X*Y sized RGB8 PNG file and we want a 1D set of HSL colours
@@ -123,5 +226,45 @@ fn convert_uv(image: DynamicImage) -> Vec<Hsl> {
 
 // Create a fallback white fade pair to provide lighting on moves which didn't have a valid UV map provided.
 fn generate_placeholder_uv_data() -> Vec<Hsl> {
-    return vec![Hsl::new(0.0, 0.0, 50.0, Option::from(1.0)); 2];
+    vec![Hsl::new(0.0, 0.0, 50.0, Option::from(1.0)); 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splines_without_their_uv_map_fail() {
+        let folder = std::env::temp_dir().join(format!("vortex-missing-uv-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let points: Vec<String> = (0..5)
+            .map(|i| {
+                format!(
+                    r#"{{"x": {}, "y": 0.0, "z": 0.5, "w": 1.0}}"#,
+                    i as f32 * 0.01
+                )
+            })
+            .collect();
+        let spline = folder.join("line.json");
+        fs::write(
+            &spline,
+            format!(
+                r#"{{"type": "poly", "curve_length": 1.0, "points": [{}], "cyclic": false, "uv": "line.png"}}"#,
+                points.join(", ")
+            ),
+        )
+        .unwrap();
+
+        let loaded = load_blender_data(
+            &spline,
+            &ImportConfig::default(),
+            &WorkspaceConfig::default(),
+        );
+        fs::remove_dir_all(&folder).unwrap();
+
+        match loaded {
+            Err(VortexError::MissingUv { path, .. }) => assert_eq!(path, folder.join("line.png")),
+            other => panic!("expected a missing UV map, got {:?}", other.map(|_| ())),
+        }
+    }
 }
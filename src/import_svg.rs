@@ -0,0 +1,826 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use colorsys::{Hsl, Rgb};
+use roxmltree::{Document, Node};
+use svgtypes::{
+    Color, Length, LengthUnit, Paint, PointsParser, SimplePathSegment, SimplifyingPathParser,
+    Transform, ViewBox,
+};
+
+use crate::config::{SvgConfig, WorkspaceConfig};
+use crate::delta_utils::distance_3d;
+use crate::error::*;
+use crate::import_types::*;
+
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+// Elements whose contents are only drawn when referenced from elsewhere
+const NOT_RENDERED: [&str; 9] = [
+    "defs", "clipPath", "mask", "marker", "pattern", "symbol", "metadata", "style", "text",
+];
+
+// Curves are split into at most this many lines, however tight the tolerance
+const MAX_CURVE_SEGMENTS: f64 = 1000.0;
+
+// Gradients can reference each other for their stops and attributes, this stops reference loops
+const MAX_GRADIENT_REFERENCES: usize = 8;
+
+// A 2D affine transform in the SVG matrix(a b c d e f) form
+#[derive(Debug, Copy, Clone)]
+struct Affine {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+    f: f64,
+}
+
+impl Affine {
+    const IDENTITY: Affine = Affine {
+        a: 1.0,
+        b: 0.0,
+        c: 0.0,
+        d: 1.0,
+        e: 0.0,
+        f: 0.0,
+    };
+
+    fn parse(text: Option<&str>) -> Affine {
+        match text.map(Transform::from_str) {
+            Some(Ok(t)) => Affine {
+                a: t.a,
+                b: t.b,
+                c: t.c,
+                d: t.d,
+                e: t.e,
+                f: t.f,
+            },
+            _ => Affine::IDENTITY,
+        }
+    }
+
+    // Apply the other transform first, then this one
+    fn then(&self, other: &Affine) -> Affine {
+        Affine {
+            a: self.a * other.a + self.c * other.b,
+            b: self.b * other.a + self.d * other.b,
+            c: self.a * other.c + self.c * other.d,
+            d: self.b * other.c + self.d * other.d,
+            e: self.a * other.e + self.c * other.f + self.e,
+            f: self.b * other.e + self.d * other.f + self.f,
+        }
+    }
+
+    fn apply(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        (
+            self.a * x + self.c * y + self.e,
+            self.b * x + self.d * y + self.f,
+        )
+    }
+
+    fn inverse(&self) -> Option<Affine> {
+        let det = self.a * self.d - self.b * self.c;
+        if det.abs() < f64::EPSILON {
+            return None;
+        }
+
+        Some(Affine {
+            a: self.d / det,
+            b: -self.b / det,
+            c: -self.c / det,
+            d: self.a / det,
+            e: (self.c * self.f - self.d * self.e) / det,
+            f: (self.b * self.e - self.a * self.f) / det,
+        })
+    }
+
+    // Average scale, used to convert tolerances between coordinate spaces
+    fn scale(&self) -> f64 {
+        (self.a * self.d - self.b * self.c).abs().sqrt()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum GradientShape {
+    Linear { x1: f64, y1: f64, x2: f64, y2: f64 },
+    Radial { cx: f64, cy: f64, r: f64 },
+}
+
+#[derive(Debug, Clone)]
+struct Gradient {
+    shape: GradientShape,
+    stops: Vec<(f64, Color)>,
+    bounding_box: bool,  // co-ordinates are fractions of the stroke's bounding box
+    to_gradient: Affine, // from user space into gradient space
+}
+
+#[derive(Debug, Clone)]
+enum StrokePaint {
+    Solid(Color),
+    Gradient(Gradient),
+}
+
+// One subpath, flattened into lines
+struct Subpath {
+    points: Vec<(f64, f64)>,
+    closed: bool,
+}
+
+// A flattened subpath in document co-ordinates, with its colours
+struct Stroke {
+    points: Vec<(f64, f64)>,
+    closed: bool,
+    color: Vec<Hsl>,
+}
+
+// Parses an SVG drawing into poly splines on a horizontal plane, centered in the workspace.
+// Every stroked path and basic shape becomes one spline per subpath, coloured by its stroke colour or gradient.
+pub fn load_svg(
+    input_path: &Path,
+    config: &SvgConfig,
+    workspace: &WorkspaceConfig,
+) -> VortexResult<Vec<BlenderData>> {
+    let contents = fs::read_to_string(input_path).map_err(|e| VortexError::io(input_path, e))?;
+
+    log::debug!("Parsing {}", input_path.display());
+
+    parse_svg(&contents, config, workspace).map_err(|reason| VortexError::Svg {
+        path: input_path.to_path_buf(),
+        reason,
+    })
+}
+
+fn parse_svg(
+    contents: &str,
+    config: &SvgConfig,
+    workspace: &WorkspaceConfig,
+) -> Result<Vec<BlenderData>, String> {
+    let document = Document::parse(contents).map_err(|e| e.to_string())?;
+    let root = document.root_element();
+    if !root.has_tag_name("svg") {
+        return Err(format!(
+            "expected an <svg> element, found <{}>",
+            root.tag_name().name()
+        ));
+    }
+
+    let mut strokes = vec![];
+    collect_strokes(&document, root, &Affine::IDENTITY, config, &mut strokes)?;
+
+    // The drawing is centered on the middle of the view box, or of the strokes when there isn't one
+    let (center_x, center_y) = match root.attribute("viewBox").map(ViewBox::from_str) {
+        Some(Ok(view_box)) => (view_box.x + view_box.w / 2.0, view_box.y + view_box.h / 2.0),
+        _ => bounds_center(strokes.iter().flat_map(|s| s.points.iter())),
+    };
+
+    // SVG's Y axis points down the page
+    let to_workspace = |(x, y): (f64, f64)| BlenderPoint4 {
+        x: ((x - center_x) * f64::from(config.scale)) as f32 + workspace.center_x,
+        y: (-(y - center_y) * f64::from(config.scale)) as f32 + workspace.center_y,
+        z: config.z,
+        w: 1.0,
+    };
+
+    strokes
+        .into_iter()
+        .map(|stroke| {
+            let mut poly = BlenderPoly {
+                curve_length: 0.0,
+                points: stroke.points.into_iter().map(to_workspace).collect(),
+                cyclic: stroke.closed,
+                uv_path: String::new(),
+                color: stroke.color,
+            };
+            poly.close_loop().map_err(|e| e.to_string())?;
+            poly.curve_length = poly
+                .points
+                .windows(2)
+                .map(|p| distance_3d(&p[0].into_bp3(), &p[1].into_bp3()))
+                .sum();
+
+            Ok(BlenderData::PolySpline(poly))
+        })
+        .collect()
+}
+
+fn collect_strokes(
+    document: &Document,
+    node: Node,
+    parent_transform: &Affine,
+    config: &SvgConfig,
+    strokes: &mut Vec<Stroke>,
+) -> Result<(), String> {
+    if NOT_RENDERED.contains(&node.tag_name().name())
+        || property(node, "display") == Some("none")
+        || inherited(node, "visibility").is_some_and(|v| v == "hidden" || v == "collapse")
+    {
+        return Ok(());
+    }
+
+    let transform = parent_transform.then(&Affine::parse(node.attribute("transform")));
+
+    if let Some(path_data) = shape_path_data(node) {
+        let paint = match stroke_paint(document, node) {
+            Some(paint) => paint,
+            None => return Ok(()), // nothing is drawn without a stroke
+        };
+
+        // Curves are flattened in the element's own co-ordinates, so the tolerance is scaled to match
+        let tolerance = f64::from(config.tolerance)
+            / (f64::from(config.scale) * transform.scale()).max(f64::EPSILON);
+        let subpaths = flatten_path(&path_data, tolerance)?;
+        let bounds = bounds(subpaths.iter().flat_map(|s| s.points.iter()));
+
+        for subpath in subpaths {
+            let color = stroke_colors(&paint, &subpath.points, bounds, config.gradient_samples);
+            strokes.push(Stroke {
+                points: subpath.points.iter().map(|&p| transform.apply(p)).collect(),
+                closed: subpath.closed,
+                color,
+            });
+        }
+    } else if node.has_tag_name("use") || node.has_tag_name("image") {
+        log::debug!(
+            "Skipping unsupported SVG <{}> element",
+            node.tag_name().name()
+        );
+    }
+
+    for child in node.children().filter(|n| n.is_element()) {
+        collect_strokes(document, child, &transform, config, strokes)?;
+    }
+
+    Ok(())
+}
+
+// Path data for a path or basic shape, shapes are converted to the equivalent path
+fn shape_path_data(node: Node) -> Option<String> {
+    let number = |name: &str| length(node.attribute(name)).unwrap_or(0.0);
+
+    match node.tag_name().name() {
+        "path" => node.attribute("d").map(String::from),
+        "line" => Some(format!(
+            "M {} {} L {} {}",
+            number("x1"),
+            number("y1"),
+            number("x2"),
+            number("y2")
+        )),
+        "polyline" | "polygon" => {
+            let points: Vec<String> = PointsParser::from(node.attribute("points")?)
+                .map(|(x, y)| format!("{} {}", x, y))
+                .collect();
+            let close = if node.has_tag_name("polygon") {
+                " Z"
+            } else {
+                ""
+            };
+            Some(format!("M {}{}", points.join(" L "), close))
+        }
+        "rect" => {
+            let (x, y, w, h) = (number("x"), number("y"), number("width"), number("height"));
+            if w <= 0.0 || h <= 0.0 {
+                return None;
+            }
+
+            // A missing corner radius matches the other one
+            let rx = length(node.attribute("rx"));
+            let ry = length(node.attribute("ry"));
+            let rx = rx.or(ry).unwrap_or(0.0).min(w / 2.0);
+            let ry = ry.or(Some(rx)).unwrap_or(0.0).min(h / 2.0);
+
+            Some(format!(
+                "M {x0} {y} H {x1} A {rx} {ry} 0 0 1 {r} {y0} V {y1} A {rx} {ry} 0 0 1 {x1} {b} \
+                 H {x0} A {rx} {ry} 0 0 1 {x} {y1} V {y0} A {rx} {ry} 0 0 1 {x0} {y} Z",
+                x = x,
+                y = y,
+                r = x + w,
+                b = y + h,
+                x0 = x + rx,
+                x1 = x + w - rx,
+                y0 = y + ry,
+                y1 = y + h - ry,
+                rx = rx,
+                ry = ry,
+            ))
+        }
+        "circle" | "ellipse" => {
+            let (cx, cy) = (number("cx"), number("cy"));
+            let (rx, ry) = if node.has_tag_name("circle") {
+                (number("r"), number("r"))
+            } else {
+                (number("rx"), number("ry"))
+            };
+            if rx <= 0.0 || ry <= 0.0 {
+                return None;
+            }
+
+            Some(format!(
+                "M {} {cy} A {rx} {ry} 0 0 1 {} {cy} A {rx} {ry} 0 0 1 {} {cy} Z",
+                cx + rx,
+                cx - rx,
+                cx + rx,
+                cy = cy,
+                rx = rx,
+                ry = ry,
+            ))
+        }
+        _ => None,
+    }
+}
+
+// Split path data into subpaths of straight lines, curves deviate from their lines by less than the tolerance
+fn flatten_path(path_data: &str, tolerance: f64) -> Result<Vec<Subpath>, String> {
+    let mut subpaths = vec![];
+    let mut current = Subpath {
+        points: vec![],
+        closed: false,
+    };
+
+    let mut finish = |current: &mut Subpath, closed: bool| {
+        let mut subpath = std::mem::replace(
+            current,
+            Subpath {
+                points: vec![],
+                closed: false,
+            },
+        );
+
+        // Closed subpaths are closed again when imported, so an explicit final point isn't needed
+        if closed && subpath.points.len() > 2 && subpath.points.first() == subpath.points.last() {
+            subpath.points.pop();
+        }
+        subpath.closed = closed;
+
+        if subpath.points.len() >= 2 {
+            subpaths.push(subpath);
+        }
+    };
+
+    for segment in SimplifyingPathParser::from(path_data) {
+        let segment = segment.map_err(|e| format!("invalid path data: {}", e))?;
+        let last = current.points.last().copied().unwrap_or((0.0, 0.0));
+
+        match segment {
+            SimplePathSegment::MoveTo { x, y } => {
+                finish(&mut current, false);
+                current.points.push((x, y));
+            }
+            SimplePathSegment::LineTo { x, y } => current.points.push((x, y)),
+            SimplePathSegment::CurveTo {
+                x1,
+                y1,
+                x2,
+                y2,
+                x,
+                y,
+            } => flatten_cubic(
+                &mut current.points,
+                last,
+                (x1, y1),
+                (x2, y2),
+                (x, y),
+                tolerance,
+            ),
+            SimplePathSegment::Quadratic { x1, y1, x, y } => {
+                // Raise the quadratic to the equivalent cubic
+                let c1 = (
+                    last.0 + 2.0 / 3.0 * (x1 - last.0),
+                    last.1 + 2.0 / 3.0 * (y1 - last.1),
+                );
+                let c2 = (x + 2.0 / 3.0 * (x1 - x), y + 2.0 / 3.0 * (y1 - y));
+                flatten_cubic(&mut current.points, last, c1, c2, (x, y), tolerance);
+            }
+            SimplePathSegment::ClosePath => finish(&mut current, true),
+        }
+    }
+    finish(&mut current, false);
+
+    Ok(subpaths)
+}
+
+// Append the points along a cubic bezier, the number of lines needed comes from the curve's second differences
+fn flatten_cubic(
+    points: &mut Vec<(f64, f64)>,
+    p0: (f64, f64),
+    p1: (f64, f64),
+    p2: (f64, f64),
+    p3: (f64, f64),
+    tolerance: f64,
+) {
+    let dd = f64::max(
+        (p0.0 - 2.0 * p1.0 + p2.0).hypot(p0.1 - 2.0 * p1.1 + p2.1),
+        (p1.0 - 2.0 * p2.0 + p3.0).hypot(p1.1 - 2.0 * p2.1 + p3.1),
+    );
+    let segments = (0.75 * dd / tolerance)
+        .sqrt()
+        .ceil()
+        .clamp(1.0, MAX_CURVE_SEGMENTS) as usize;
+
+    for i in 1..=segments {
+        let t = i as f64 / segments as f64;
+        let mt = 1.0 - t;
+        let (a, b, c, d) = (mt * mt * mt, 3.0 * mt * mt * t, 3.0 * mt * t * t, t * t * t);
+        points.push((
+            a * p0.0 + b * p1.0 + c * p2.0 + d * p3.0,
+            a * p0.1 + b * p1.1 + c * p2.1 + d * p3.1,
+        ));
+    }
+}
+
+// The resolved stroke of an element, None when it isn't stroked
+fn stroke_paint(document: &Document, node: Node) -> Option<StrokePaint> {
+    let current_color = || {
+        inherited(node, "color")
+            .and_then(|c| Color::from_str(c).ok())
+            .unwrap_or_else(Color::black)
+    };
+
+    match Paint::from_str(inherited(node, "stroke")?).ok()? {
+        Paint::Color(color) => Some(StrokePaint::Solid(color)),
+        Paint::CurrentColor => Some(StrokePaint::Solid(current_color())),
+        Paint::FuncIRI(id, fallback) => match gradient(document, id) {
+            Some(gradient) => Some(gradient),
+            None => match fallback? {
+                svgtypes::PaintFallback::Color(color) => Some(StrokePaint::Solid(color)),
+                svgtypes::PaintFallback::CurrentColor => Some(StrokePaint::Solid(current_color())),
+                svgtypes::PaintFallback::None => None,
+            },
+        },
+        _ => None,
+    }
+}
+
+// A linear or radial gradient by id, a gradient with a single stop is a solid colour
+fn gradient(document: &Document, id: &str) -> Option<StrokePaint> {
+    let node = document
+        .descendants()
+        .find(|n| n.attribute("id") == Some(id))
+        .filter(|n| n.has_tag_name("linearGradient") || n.has_tag_name("radialGradient"))?;
+
+    // Gradients inherit anything they don't set from the gradient they reference
+    let chain: Vec<Node> = std::iter::successors(Some(node), |n| {
+        let reference = n
+            .attribute((XLINK_NS, "href"))
+            .or_else(|| n.attribute("href"))?;
+        let reference = reference.strip_prefix('#')?;
+        document
+            .descendants()
+            .find(|r| r.attribute("id") == Some(reference))
+    })
+    .take(MAX_GRADIENT_REFERENCES)
+    .collect();
+
+    let attribute = |name: &str| chain.iter().find_map(|n| n.attribute(name));
+    let fraction = |name: &str, default: f64| {
+        attribute(name)
+            .and_then(|v| Length::from_str(v).ok())
+            .map(|l| match l.unit {
+                LengthUnit::Percent => l.number / 100.0,
+                _ => l.number,
+            })
+            .unwrap_or(default)
+    };
+
+    let mut stops: Vec<(f64, Color)> = vec![];
+    if let Some(with_stops) = chain
+        .iter()
+        .find(|n| n.children().any(|c| c.has_tag_name("stop")))
+    {
+        for stop in with_stops.children().filter(|c| c.has_tag_name("stop")) {
+            let offset = stop
+                .attribute("offset")
+                .and_then(|v| Length::from_str(v).ok())
+                .map(|l| match l.unit {
+                    LengthUnit::Percent => l.number / 100.0,
+                    _ => l.number,
+                })
+                .unwrap_or(0.0)
+                .clamp(0.0, 1.0);
+
+            // Offsets can't go backwards
+            let offset = stops
+                .last()
+                .map_or(offset, |&(previous, _)| offset.max(previous));
+            let color = property(stop, "stop-color")
+                .and_then(|c| Color::from_str(c).ok())
+                .unwrap_or_else(Color::black);
+            stops.push((offset, color));
+        }
+    }
+
+    match stops.len() {
+        0 => return None,
+        1 => return Some(StrokePaint::Solid(stops[0].1)),
+        _ => {}
+    }
+
+    let shape = if node.has_tag_name("linearGradient") {
+        GradientShape::Linear {
+            x1: fraction("x1", 0.0),
+            y1: fraction("y1", 0.0),
+            x2: fraction("x2", 1.0),
+            y2: fraction("y2", 0.0),
+        }
+    } else {
+        GradientShape::Radial {
+            cx: fraction("cx", 0.5),
+            cy: fraction("cy", 0.5),
+            r: fraction("r", 0.5),
+        }
+    };
+
+    let to_gradient = Affine::parse(attribute("gradientTransform"))
+        .inverse()
+        .unwrap_or(Affine::IDENTITY);
+
+    Some(StrokePaint::Gradient(Gradient {
+        shape,
+        stops,
+        bounding_box: attribute("gradientUnits") != Some("userSpaceOnUse"),
+        to_gradient,
+    }))
+}
+
+// Colours along a stroke. Gradients are sampled at evenly spaced distances, matching how the sequencer
+// spreads a spline's colours along its length.
+fn stroke_colors(
+    paint: &StrokePaint,
+    points: &[(f64, f64)],
+    bounds: ((f64, f64), (f64, f64)),
+    samples: usize,
+) -> Vec<Hsl> {
+    let gradient = match paint {
+        StrokePaint::Solid(color) => return vec![to_hsl(*color); 2],
+        StrokePaint::Gradient(gradient) => gradient,
+    };
+
+    let distances: Vec<f64> = std::iter::once(0.0)
+        .chain(points.windows(2).scan(0.0, |total, p| {
+            *total += (p[1].0 - p[0].0).hypot(p[1].1 - p[0].1);
+            Some(*total)
+        }))
+        .collect();
+    let length = distances.last().copied().unwrap_or(0.0);
+
+    (0..samples.max(2))
+        .map(|i| {
+            let target = length * i as f64 / (samples.max(2) - 1) as f64;
+            let segment = distances
+                .windows(2)
+                .position(|d| target <= d[1])
+                .unwrap_or(distances.len().saturating_sub(2));
+
+            let point = match (points.get(segment), points.get(segment + 1)) {
+                (Some(&a), Some(&b)) => {
+                    let span = distances[segment + 1] - distances[segment];
+                    let t = if span > 0.0 {
+                        (target - distances[segment]) / span
+                    } else {
+                        0.0
+                    };
+                    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+                }
+                (Some(&a), None) => a,
+                _ => (0.0, 0.0),
+            };
+
+            to_hsl(gradient_color(gradient, point, bounds))
+        })
+        .collect()
+}
+
+fn gradient_color(
+    gradient: &Gradient,
+    point: (f64, f64),
+    bounds: ((f64, f64), (f64, f64)),
+) -> Color {
+    let ((min_x, min_y), (max_x, max_y)) = bounds;
+    let point = if gradient.bounding_box {
+        (
+            (point.0 - min_x) / (max_x - min_x).max(f64::EPSILON),
+            (point.1 - min_y) / (max_y - min_y).max(f64::EPSILON),
+        )
+    } else {
+        point
+    };
+    let (x, y) = gradient.to_gradient.apply(point);
+
+    // Positions beyond the ends take the colour of the nearest stop
+    let t = match gradient.shape {
+        GradientShape::Linear { x1, y1, x2, y2 } => {
+            let (dx, dy) = (x2 - x1, y2 - y1);
+            let length_squared = dx * dx + dy * dy;
+            if length_squared > 0.0 {
+                ((x - x1) * dx + (y - y1) * dy) / length_squared
+            } else {
+                1.0
+            }
+        }
+        GradientShape::Radial { cx, cy, r } => (x - cx).hypot(y - cy) / r.max(f64::EPSILON),
+    }
+    .clamp(0.0, 1.0);
+
+    let stops = &gradient.stops;
+    let next = stops
+        .iter()
+        .position(|&(offset, _)| offset >= t)
+        .unwrap_or(stops.len() - 1);
+    if next == 0 {
+        return stops[0].1;
+    }
+
+    let (start, from) = stops[next - 1];
+    let (end, to) = stops[next];
+    let blend = if end > start {
+        (t - start) / (end - start)
+    } else {
+        1.0
+    };
+    let mix = |a: u8, b: u8| (f64::from(a) + (f64::from(b) - f64::from(a)) * blend).round() as u8;
+
+    Color::new_rgb(
+        mix(from.red, to.red),
+        mix(from.green, to.green),
+        mix(from.blue, to.blue),
+    )
+}
+
+fn to_hsl(color: Color) -> Hsl {
+    let rgb = Rgb::from(&(
+        f64::from(color.red),
+        f64::from(color.green),
+        f64::from(color.blue),
+    ));
+
+    Hsl::from(&rgb)
+}
+
+// A presentation attribute or style property set on this element, style declarations take priority
+fn property<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    let from_style = node.attribute("style").and_then(|style| {
+        style
+            .split(';')
+            .rev()
+            .filter_map(|declaration| declaration.split_once(':'))
+            .find(|(key, _)| key.trim() == name)
+            .map(|(_, value)| value.trim())
+    });
+
+    from_style.or_else(|| node.attribute(name).map(str::trim))
+}
+
+// A property set on this element or inherited from the closest ancestor which sets it
+fn inherited<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.ancestors()
+        .filter(|n| n.is_element())
+        .filter_map(|n| property(n, name))
+        .find(|value| *value != "inherit")
+}
+
+fn length(text: Option<&str>) -> Option<f64> {
+    text.and_then(|t| Length::from_str(t).ok())
+        .map(|l| l.number)
+}
+
+fn bounds<'a, I: Iterator<Item = &'a (f64, f64)>>(points: I) -> ((f64, f64), (f64, f64)) {
+    points.fold(
+        ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN)),
+        |((min_x, min_y), (max_x, max_y)), &(x, y)| {
+            ((min_x.min(x), min_y.min(y)), (max_x.max(x), max_y.max(y)))
+        },
+    )
+}
+
+fn bounds_center<'a, I: Iterator<Item = &'a (f64, f64)>>(points: I) -> (f64, f64) {
+    let ((min_x, min_y), (max_x, max_y)) = bounds(points);
+    if min_x > max_x {
+        return (0.0, 0.0); // no points
+    }
+
+    ((min_x + max_x) / 2.0, (min_y + max_y) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strokes in a 100 unit square view box, centered on the workspace
+    fn parse(body: &str) -> Vec<BlenderPoly> {
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100">{}</svg>"#,
+            body
+        );
+
+        parse_svg(&svg, &SvgConfig::default(), &WorkspaceConfig::default())
+            .unwrap()
+            .into_iter()
+            .map(|spline| match spline {
+                BlenderData::PolySpline(poly) => poly,
+                other => panic!("expected a poly spline, got {:?}", other),
+            })
+            .collect()
+    }
+
+    // Points back in the SVG's co-ordinates
+    fn svg_points(poly: &BlenderPoly) -> Vec<(f32, f32)> {
+        poly.points
+            .iter()
+            .map(|p| (p.x + 50.0, 50.0 - p.y))
+            .collect()
+    }
+
+    fn rgb(color: &Hsl) -> (u8, u8, u8) {
+        let rgb = Rgb::from(color);
+        (
+            rgb.get_red().round() as u8,
+            rgb.get_green().round() as u8,
+            rgb.get_blue().round() as u8,
+        )
+    }
+
+    #[test]
+    fn nested_transforms_apply_innermost_first() {
+        let polys = parse(
+            r#"<g transform="translate(10 20)">
+                <g transform="scale(2)">
+                    <line x1="0" y1="0" x2="10" y2="5" stroke="red"/>
+                </g>
+            </g>"#,
+        );
+
+        assert_eq!(polys.len(), 1);
+        assert_eq!(svg_points(&polys[0]), vec![(10.0, 20.0), (30.0, 30.0)]);
+        assert!(polys[0].points.iter().all(|p| p.z == 50.0));
+        assert_eq!(polys[0].color.len(), 2);
+        assert!(polys[0].color.iter().all(|c| rgb(c) == (255, 0, 0)));
+    }
+
+    #[test]
+    fn arcs_are_flattened_within_tolerance() {
+        let polys = parse(r#"<path d="M 60 50 A 10 10 0 0 1 40 50" stroke="blue"/>"#);
+
+        assert_eq!(polys.len(), 1);
+        let points = svg_points(&polys[0]);
+        assert!(points.len() > 8);
+        assert_eq!(points[0], (60.0, 50.0));
+        let end = points[points.len() - 1];
+        assert!((end.0 - 40.0).abs() < 1e-4 && (end.1 - 50.0).abs() < 1e-4);
+
+        // Every point is on the circle, and the sweep goes down the page
+        for (x, y) in &points {
+            assert!(((x - 50.0).hypot(y - 50.0) - 10.0).abs() < 0.05);
+            assert!(*y >= 50.0 - 1e-4);
+        }
+        assert!(polys[0].color.iter().all(|c| rgb(c) == (0, 0, 255)));
+    }
+
+    #[test]
+    fn closed_subpaths_join_back_to_their_start() {
+        let polys = parse(r#"<path d="M 0 0 L 10 0 L 10 10 Z M 20 20 L 30 20" stroke="black"/>"#);
+
+        assert_eq!(polys.len(), 2);
+        assert!(polys[0].cyclic);
+        assert_eq!(
+            svg_points(&polys[0]),
+            vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 0.0)]
+        );
+        assert!((polys[0].curve_length - (20.0 + 200.0_f32.sqrt())).abs() < 1e-3);
+
+        assert!(!polys[1].cyclic);
+        assert_eq!(svg_points(&polys[1]), vec![(20.0, 20.0), (30.0, 20.0)]);
+        assert!(polys[1].color.iter().all(|c| rgb(c) == (0, 0, 0)));
+    }
+
+    #[test]
+    fn linear_gradient_strokes_are_sampled_along_their_length() {
+        let polys = parse(
+            r##"<defs>
+                <linearGradient id="fade">
+                    <stop offset="0" stop-color="#ff0000"/>
+                    <stop offset="1" stop-color="#0000ff"/>
+                </linearGradient>
+            </defs>
+            <line x1="0" y1="50" x2="100" y2="50" stroke="url(#fade)"/>"##,
+        );
+
+        assert_eq!(polys.len(), 1);
+        assert_eq!(svg_points(&polys[0]), vec![(0.0, 50.0), (100.0, 50.0)]);
+
+        let colors: Vec<(u8, u8, u8)> = polys[0].color.iter().map(rgb).collect();
+        assert_eq!(colors.len(), SvgConfig::default().gradient_samples);
+        assert_eq!(colors[0], (255, 0, 0));
+        assert_eq!(colors[colors.len() - 1], (0, 0, 255));
+
+        // Red fades into blue at an even pace
+        for (i, &(red, green, blue)) in colors.iter().enumerate() {
+            let expected = 255.0 * i as f32 / (colors.len() - 1) as f32;
+            assert!((f32::from(blue) - expected).abs() <= 1.0);
+            assert!((i32::from(red) + i32::from(blue) - 255).abs() <= 1);
+            assert_eq!(green, 0);
+        }
+    }
+}
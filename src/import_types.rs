@@ -1,5 +1,7 @@
 use colorsys::Hsl;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::error::*;
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
@@ -8,21 +10,25 @@ pub enum BlenderData {
     PolySpline(BlenderPoly),
     #[serde(rename = "nurbs")]
     NURBSSpline(BlenderNURBS),
+    #[serde(rename = "bezier")]
+    BezierSpline(BlenderBezier),
     #[serde(rename = "particles")]
     Particles(BlenderParticles),
 }
 
 pub trait Spline {
-    fn close_loop(&mut self) {
-        // optional - particle systems don't have loops
+    type Point;
+
+    fn close_loop(&mut self) -> VortexResult<()> {
+        Ok(())
     }
 
     fn scale_points(&mut self, factor: f32);
     fn offset_points(&mut self, x_offset: f32, y_offset: f32, z_offset: f32);
 
     // The edges of the movement (not the first or last element, as those are often control points)
-    fn get_start_point(slice: &[BlenderPoint4]) -> BlenderPoint4;
-    fn get_end_point(slice: &[BlenderPoint4]) -> BlenderPoint4;
+    fn get_start_point(slice: &[Self::Point]) -> VortexResult<Self::Point>;
+    fn get_end_point(slice: &[Self::Point]) -> VortexResult<Self::Point>;
 
     // Size of the window to slide through the points
     fn get_recommended_window_size() -> usize;
@@ -42,11 +48,18 @@ pub struct BlenderPoly {
 }
 
 impl Spline for BlenderPoly {
-    fn close_loop(&mut self) {
+    type Point = BlenderPoint4;
+
+    fn close_loop(&mut self) -> VortexResult<()> {
         if self.cyclic {
             // Put the first point at the end of the set
-            self.points.push(self.points.first().unwrap().clone());
+            let first = *self.points.first().ok_or_else(|| {
+                VortexError::EmptyGeometry("Can't close a poly spline without points".to_string())
+            })?;
+            self.points.push(first);
         }
+
+        Ok(())
     }
 
     fn scale_points(&mut self, factor: f32) {
@@ -63,12 +76,12 @@ impl Spline for BlenderPoly {
         }
     }
 
-    fn get_start_point(slice: &[BlenderPoint4]) -> BlenderPoint4 {
-        slice[0]
+    fn get_start_point(slice: &[BlenderPoint4]) -> VortexResult<BlenderPoint4> {
+        point_from_window(slice, 0)
     }
 
-    fn get_end_point(slice: &[BlenderPoint4]) -> BlenderPoint4 {
-        slice[1]
+    fn get_end_point(slice: &[BlenderPoint4]) -> VortexResult<BlenderPoint4> {
+        point_from_window(slice, 1)
     }
 
     fn get_recommended_window_size() -> usize {
@@ -76,6 +89,9 @@ impl Spline for BlenderPoly {
     }
 }
 
+// A rational B-spline, each point's w is its weight.
+// Blender doesn't expose knot vectors, so unless the export includes them they're generated
+// the same way Blender does from the order and endpoint/bezier settings.
 #[derive(Deserialize, Debug, Clone)]
 pub struct BlenderNURBS {
     pub curve_length: f32,
@@ -85,18 +101,135 @@ pub struct BlenderNURBS {
     #[serde(rename = "uv")]
     pub uv_path: String,
 
+    #[serde(default = "default_nurbs_order")]
+    pub order: usize,
+    #[serde(default)]
+    pub knots: Vec<f32>,
+    #[serde(default)]
+    pub use_endpoint: bool,
+    #[serde(default)]
+    pub use_bezier: bool,
+
     #[serde(skip)]
     pub color: Vec<Hsl>,
 }
 
+// Blender's default order for new NURBS curves
+fn default_nurbs_order() -> usize {
+    4
+}
+
+impl BlenderNURBS {
+    // Draw the curve from its end back to its start, the knots are mirrored to match
+    pub fn reverse(&mut self) {
+        self.points.reverse();
+        self.color.reverse();
+
+        if let (Some(&first), Some(&last)) = (self.knots.first(), self.knots.last()) {
+            self.knots = self.knots.iter().rev().map(|k| first + last - k).collect();
+        }
+    }
+}
+
 impl Spline for BlenderNURBS {
-    fn close_loop(&mut self) {
+    type Point = BlenderPoint4;
+
+    fn close_loop(&mut self) -> VortexResult<()> {
+        // Exported knots already describe how the curve closes
+        if self.cyclic && self.knots.is_empty() {
+            if self.points.len() < self.order {
+                return Err(VortexError::EmptyGeometry(format!(
+                    "Can't close an order {} NURBS spline with {} points",
+                    self.order,
+                    self.points.len()
+                )));
+            }
+
+            // Wrapping the first order-1 points onto the end joins the curve smoothly with uniform knots
+            let wrapped: Vec<BlenderPoint4> = self.points[..self.order - 1].to_vec();
+            self.points.extend(wrapped);
+            self.use_endpoint = false;
+            self.use_bezier = false;
+        }
+
+        Ok(())
+    }
+
+    fn scale_points(&mut self, factor: f32) {
+        for p in &mut self.points {
+            p.scale(factor)
+        }
+
+        self.curve_length *= factor;
+    }
+
+    fn offset_points(&mut self, x_offset: f32, y_offset: f32, z_offset: f32) {
+        for point in &mut self.points {
+            point.offset(x_offset, y_offset, z_offset);
+        }
+    }
+
+    // The curve is evaluated as a whole (see nurbs.rs), these are the control points at either end
+    fn get_start_point(slice: &[BlenderPoint4]) -> VortexResult<BlenderPoint4> {
+        point_from_window(slice, 0)
+    }
+
+    fn get_end_point(slice: &[BlenderPoint4]) -> VortexResult<BlenderPoint4> {
+        point_from_window(slice, slice.len().saturating_sub(1))
+    }
+
+    fn get_recommended_window_size() -> usize {
+        2
+    }
+}
+
+// Blender's default curve type. Each point has a handle either side of it,
+// the curve between two points is a cubic bezier through the first point's right handle and the second's left handle.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BlenderBezier {
+    pub curve_length: f32,
+    pub points: Vec<BlenderBezierPoint>,
+    #[serde(default)]
+    pub cyclic: bool,
+    #[serde(rename = "uv")]
+    pub uv_path: String,
+
+    #[serde(skip)]
+    pub color: Vec<Hsl>,
+}
+
+impl BlenderBezier {
+    // Control points of the cubic bezier between a pair of consecutive points
+    pub fn segment(pair: &[BlenderBezierPoint]) -> VortexResult<[BlenderPoint3; 4]> {
+        let start = point_from_window(pair, 0)?;
+        let end = point_from_window(pair, 1)?;
+
+        Ok([start.co, start.handle_right, end.handle_left, end.co])
+    }
+
+    // Draw the curve from its last point back to its first, the handles swap sides
+    pub fn reverse(&mut self) {
+        self.points.reverse();
+        for point in &mut self.points {
+            std::mem::swap(&mut point.handle_left, &mut point.handle_right);
+        }
+        self.color.reverse();
+    }
+}
+
+impl Spline for BlenderBezier {
+    type Point = BlenderBezierPoint;
+
+    fn close_loop(&mut self) -> VortexResult<()> {
         if self.cyclic {
-            // Put the first two points at the end of the set
-            self.points.push(self.points[0]);
-            self.points.push(self.points[1]);
-            self.points.push(self.points[2]);
+            // The closing segment runs from the last point back to the first
+            let first = *self.points.first().ok_or_else(|| {
+                VortexError::EmptyGeometry("Can't close a bezier spline without points".to_string())
+            })?;
+            self.points.push(first);
         }
+
+        Ok(())
     }
 
     fn scale_points(&mut self, factor: f32) {
@@ -113,16 +246,37 @@ impl Spline for BlenderNURBS {
         }
     }
 
-    fn get_start_point(slice: &[BlenderPoint4]) -> BlenderPoint4 {
-        slice[1]
+    fn get_start_point(slice: &[BlenderBezierPoint]) -> VortexResult<BlenderBezierPoint> {
+        point_from_window(slice, 0)
     }
 
-    fn get_end_point(slice: &[BlenderPoint4]) -> BlenderPoint4 {
-        slice[2]
+    fn get_end_point(slice: &[BlenderBezierPoint]) -> VortexResult<BlenderBezierPoint> {
+        point_from_window(slice, 1)
     }
 
     fn get_recommended_window_size() -> usize {
-        4
+        2
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct BlenderBezierPoint {
+    pub co: BlenderPoint3,
+    pub handle_left: BlenderPoint3,
+    pub handle_right: BlenderPoint3,
+}
+
+impl BlenderTransforms for BlenderBezierPoint {
+    fn scale(&mut self, factor: f32) {
+        self.co.scale(factor);
+        self.handle_left.scale(factor);
+        self.handle_right.scale(factor);
+    }
+
+    fn offset(&mut self, x_offset: f32, y_offset: f32, z_offset: f32) {
+        self.co.offset(x_offset, y_offset, z_offset);
+        self.handle_left.offset(x_offset, y_offset, z_offset);
+        self.handle_right.offset(x_offset, y_offset, z_offset);
     }
 }
 
@@ -137,8 +291,12 @@ pub struct BlenderParticles {
 }
 
 impl Spline for BlenderParticles {
-    fn close_loop(&mut self) {
-        unimplemented!();
+    type Point = BlenderPoint4;
+
+    fn close_loop(&mut self) -> VortexResult<()> {
+        Err(VortexError::UnsupportedOperation(
+            "Particle systems can't be closed into a loop".to_string(),
+        ))
     }
 
     fn scale_points(&mut self, factor: f32) {
@@ -153,12 +311,16 @@ impl Spline for BlenderParticles {
         }
     }
 
-    fn get_start_point(slice: &[BlenderPoint4]) -> BlenderPoint4 {
-        unimplemented!()
+    fn get_start_point(_slice: &[BlenderPoint4]) -> VortexResult<BlenderPoint4> {
+        Err(VortexError::UnsupportedOperation(
+            "Particle systems don't have a start point".to_string(),
+        ))
     }
 
-    fn get_end_point(slice: &[BlenderPoint4]) -> BlenderPoint4 {
-        unimplemented!()
+    fn get_end_point(_slice: &[BlenderPoint4]) -> VortexResult<BlenderPoint4> {
+        Err(VortexError::UnsupportedOperation(
+            "Particle systems don't have an end point".to_string(),
+        ))
     }
 
     fn get_recommended_window_size() -> usize {
@@ -166,12 +328,22 @@ impl Spline for BlenderParticles {
     }
 }
 
+fn point_from_window<T: Copy>(slice: &[T], index: usize) -> VortexResult<T> {
+    slice.get(index).copied().ok_or_else(|| {
+        VortexError::EmptyGeometry(format!(
+            "Spline segment has {} points, expected at least {}",
+            slice.len(),
+            index + 1
+        ))
+    })
+}
+
 pub trait BlenderTransforms {
     fn scale(&mut self, factor: f32);
     fn offset(&mut self, x_offset: f32, y_offset: f32, z_offset: f32);
 }
 
-#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub struct BlenderParticle {
     pub location: BlenderPoint3,
     pub prev_location: BlenderPoint3,
@@ -213,7 +385,7 @@ pub struct BlenderPoint2 {
     pub y: f32,
 }
 
-#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
 pub struct BlenderPoint3 {
     pub x: f32,
     pub y: f32,
@@ -233,6 +405,16 @@ impl BlenderTransforms for BlenderPoint3 {
         self.z += z_offset;
     }
 }
+impl From<(f32, f32, f32)> for BlenderPoint3 {
+    fn from(p: (f32, f32, f32)) -> Self {
+        BlenderPoint3 {
+            x: p.0,
+            y: p.1,
+            z: p.2,
+        }
+    }
+}
+
 impl BlenderPoint3 {
     pub fn into_bp2_xy(self) -> BlenderPoint2 {
         BlenderPoint2 {
@@ -245,12 +427,12 @@ impl BlenderPoint3 {
     }
 }
 
-#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub struct BlenderPoint4 {
     pub x: f32,
     pub y: f32,
     pub z: f32,
-    pub w: f32, // not currently used/implemented
+    pub w: f32, // NURBS weight, unused by other spline types
 }
 
 impl BlenderTransforms for BlenderPoint4 {
@@ -0,0 +1,274 @@
+use crate::config::{MachineConfig, WorkspaceConfig};
+use crate::delta_utils::*;
+use crate::error::*;
+use crate::export_types::*;
+use crate::import_types::*;
+
+// Number of points checked along each move when validating reachability
+const SAMPLES_PER_MOVE: usize = 16;
+
+// Inverse kinematics for a rotary delta with three arms spaced 120 degrees apart.
+// The first arm's shoulder lies on the -Y axis, the others are rotated anti-clockwise from it.
+#[derive(Debug, Clone)]
+pub struct DeltaKinematics {
+    base_radius: f32,
+    effector_radius: f32,
+    bicep_length: f32,
+    forearm_length: f32,
+    base_height: f32,
+    min_joint_angle: f32,
+    max_joint_angle: f32,
+}
+
+impl DeltaKinematics {
+    pub fn new(machine: &MachineConfig) -> DeltaKinematics {
+        DeltaKinematics {
+            base_radius: machine.base_radius,
+            effector_radius: machine.effector_radius,
+            bicep_length: machine.bicep_length,
+            forearm_length: machine.forearm_length,
+            base_height: machine.base_height,
+            min_joint_angle: machine.min_joint_angle,
+            max_joint_angle: machine.max_joint_angle,
+        }
+    }
+
+    // Joint angles in degrees for an effector position in toolpath co-ordinates.
+    // Returns None when the forearms can't physically connect the biceps to the effector.
+    pub fn inverse(&self, point: &BlenderPoint3) -> Option<[f32; 3]> {
+        // Toolpath Z is measured up from the bottom of the workspace, the kinematics work down from the shoulders
+        let z = point.z - self.base_height;
+        let (sin_120, cos_120) = 120_f32.to_radians().sin_cos();
+
+        Some([
+            self.arm_angle(point.x, point.y, z)?,
+            self.arm_angle(
+                point.x * cos_120 + point.y * sin_120,
+                point.y * cos_120 - point.x * sin_120,
+                z,
+            )?,
+            self.arm_angle(
+                point.x * cos_120 - point.y * sin_120,
+                point.y * cos_120 + point.x * sin_120,
+                z,
+            )?,
+        ])
+    }
+
+    // Solve the bicep angle for the arm in the YZ plane by intersecting the circle the elbow travels on
+    // with the sphere of forearm positions around the wrist joint
+    fn arm_angle(&self, x: f32, y: f32, z: f32) -> Option<f32> {
+        if z >= 0.0 {
+            return None; // the effector can't be level with or above the shoulders
+        }
+
+        let shoulder_y = -self.base_radius;
+        let wrist_y = y - self.effector_radius;
+        let bicep = self.bicep_length;
+        let forearm = self.forearm_length;
+
+        // The elbow lies on the line z = a + b*y
+        let a = (x * x + wrist_y * wrist_y + z * z + bicep * bicep
+            - forearm * forearm
+            - shoulder_y * shoulder_y)
+            / (2.0 * z);
+        let b = (shoulder_y - wrist_y) / z;
+
+        let discriminant = -(a + b * shoulder_y).powi(2) + bicep * (b * b * bicep + bicep);
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        // Take the outward elbow solution
+        let elbow_y = (shoulder_y - a * b - discriminant.sqrt()) / (b * b + 1.0);
+        let elbow_z = a + b * elbow_y;
+
+        let mut angle = (-elbow_z / (shoulder_y - elbow_y)).atan().to_degrees();
+        if elbow_y > shoulder_y {
+            angle += 180.0;
+        }
+
+        Some(angle)
+    }
+
+    pub fn within_limits(&self, angles: &[f32; 3]) -> bool {
+        angles
+            .iter()
+            .all(|a| *a >= self.min_joint_angle && *a <= self.max_joint_angle)
+    }
+
+    pub fn is_reachable(&self, point: &BlenderPoint3) -> bool {
+        self.inverse(point)
+            .map(|angles| self.within_limits(&angles))
+            .unwrap_or(false)
+    }
+}
+
+// Find the ID's of moves which pass through points the arms can't reach, or which leave the legal workspace.
+// Curves are sampled along their length, as both ends being reachable doesn't mean the whole move is.
+pub fn find_unreachable_motions(
+    events: &ActionGroups,
+    machine: &MachineConfig,
+    workspace: &WorkspaceConfig,
+) -> VortexResult<Vec<u32>> {
+    let kinematics = DeltaKinematics::new(machine);
+    let mut unreachable = vec![];
+    let mut position: Option<BlenderPoint3> = None;
+
+    for motion in events.delta.iter().map(|action| &action.payload) {
+        let samples = match (&motion.reference, &motion.motion_type) {
+            (MotionReferenceFrame::Relative, _) => {
+                // Relative moves are offsets from wherever the previous move finished
+                match (position, motion.points.last()) {
+                    (Some(start), Some(&offset)) => {
+                        let offset: BlenderPoint3 = offset.into();
+                        vec![BlenderPoint3 {
+                            x: start.x + offset.x,
+                            y: start.y + offset.y,
+                            z: start.z + offset.z,
+                        }]
+                    }
+                    _ => vec![],
+                }
+            }
+            (MotionReferenceFrame::Absolute, MotionInterpolationType::PointTransit) => {
+                // Transits travel in a straight line from the previous position when it's known
+                match (position, motion.points.last()) {
+                    (Some(start), Some(&end)) => sample_motion(
+                        &Motion {
+                            motion_type: MotionInterpolationType::Line,
+                            reference: MotionReferenceFrame::Absolute,
+                            id: motion.id,
+                            duration: motion.duration,
+                            points: vec![(start.x, start.y, start.z), end],
+                        },
+                        SAMPLES_PER_MOVE,
+                    )?,
+                    _ => sample_motion(motion, SAMPLES_PER_MOVE)?,
+                }
+            }
+            (MotionReferenceFrame::Absolute, _) => sample_motion(motion, SAMPLES_PER_MOVE)?,
+        };
+
+        if samples
+            .iter()
+            .any(|p| !is_point_legal(p, workspace) || !kinematics.is_reachable(p))
+        {
+            unreachable.push(motion.id);
+        }
+
+        if let Some(last) = samples.last() {
+            position = Some(*last);
+        }
+    }
+
+    Ok(unreachable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32, z: f32) -> BlenderPoint3 {
+        BlenderPoint3 { x, y, z }
+    }
+
+    fn line(from: (f32, f32, f32), to: (f32, f32, f32)) -> Motion {
+        Motion {
+            id: 0,
+            reference: MotionReferenceFrame::Absolute,
+            motion_type: MotionInterpolationType::Line,
+            duration: 100,
+            points: vec![from, to],
+        }
+    }
+
+    #[test]
+    fn horizontal_biceps_hold_the_effector_a_forearm_away() {
+        let machine = MachineConfig::default();
+        let kinematics = DeltaKinematics::new(&machine);
+
+        // With level biceps the elbows are a bicep out from the shoulders, and the forearms reach down
+        // from there to the wrists
+        let reach = machine.base_radius + machine.bicep_length - machine.effector_radius;
+        let drop = (machine.forearm_length.powi(2) - reach.powi(2)).sqrt();
+        let angles = kinematics
+            .inverse(&point(0.0, 0.0, machine.base_height - drop))
+            .unwrap();
+
+        for angle in &angles {
+            assert!(angle.abs() < 0.05, "{:?}", angles);
+        }
+    }
+
+    #[test]
+    fn each_elbow_is_a_forearm_from_its_wrist() {
+        let machine = MachineConfig::default();
+        let kinematics = DeltaKinematics::new(&machine);
+        let effector = point(40.0, -25.0, 120.0);
+        let angles = kinematics.inverse(&effector).unwrap();
+
+        for (arm, angle) in angles.iter().enumerate() {
+            // Put the effector in the arm's frame, where its shoulder is on the -Y axis
+            let (sin, cos) = (-120.0 * arm as f32).to_radians().sin_cos();
+            let (x, y) = (
+                effector.x * cos - effector.y * sin,
+                effector.x * sin + effector.y * cos,
+            );
+            let (sin, cos) = angle.to_radians().sin_cos();
+            let elbow = point(
+                0.0,
+                -machine.base_radius - machine.bicep_length * cos,
+                machine.base_height - machine.bicep_length * sin,
+            );
+            let wrist = point(x, y - machine.effector_radius, effector.z);
+
+            assert!((distance_3d(&elbow, &wrist) - machine.forearm_length).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn points_outside_the_envelope_are_unreachable() {
+        let machine = MachineConfig::default();
+        let kinematics = DeltaKinematics::new(&machine);
+
+        assert!(kinematics.is_reachable(&point(0.0, 0.0, 100.0)));
+        assert!(kinematics
+            .inverse(&point(0.0, 0.0, machine.base_height))
+            .is_none());
+        assert!(kinematics.inverse(&point(1000.0, 0.0, 100.0)).is_none());
+        assert!(!kinematics.is_reachable(&point(0.0, 0.0, -200.0)));
+
+        // Points the arms can connect to are still out of reach past the joint limits
+        let limited = DeltaKinematics::new(&MachineConfig {
+            max_joint_angle: 10.0,
+            ..MachineConfig::default()
+        });
+        assert!(limited.inverse(&point(0.0, 0.0, 100.0)).is_some());
+        assert!(!limited.is_reachable(&point(0.0, 0.0, 100.0)));
+    }
+
+    #[test]
+    fn moves_leaving_the_workspace_or_the_arms_reach_are_reported() {
+        let machine = MachineConfig::default();
+        let mut events = ActionGroups::new();
+        events.add_delta_action(line((0.0, 0.0, 100.0), (50.0, 50.0, 100.0)));
+        events.add_delta_action(line((50.0, 50.0, 100.0), (250.0, 0.0, 100.0)));
+        events.add_delta_action(line((0.0, 0.0, 100.0), (0.0, 0.0, 250.0)));
+
+        let workspace = WorkspaceConfig::default();
+        let unreachable = find_unreachable_motions(&events, &machine, &workspace).unwrap();
+        assert_eq!(unreachable, vec![2, 3]);
+
+        // A larger workspace than the arms can reach still fails where they can't reach
+        let workspace = WorkspaceConfig {
+            radius: 1000.0,
+            z_max: 1000.0,
+            ..WorkspaceConfig::default()
+        };
+        let mut events = ActionGroups::new();
+        events.add_delta_action(line((0.0, 0.0, 100.0), (600.0, 0.0, 100.0)));
+        let unreachable = find_unreachable_motions(&events, &machine, &workspace).unwrap();
+        assert_eq!(unreachable, vec![1]);
+    }
+}
@@ -0,0 +1,61 @@
+//! Converts spline and particle data exported from Blender into toolpaths for the
+//! `zaphod-bot` light painting delta robot, along with preview data for the `delta-ui`.
+//!
+//! The [`Pipeline`] walks a folder of numbered frames and plans every collection in them,
+//! which is what the `total-perspective-vortex` binary does. Tools which already have
+//! Blender data in memory can use the individual steps instead:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use total_perspective_vortex::*;
+//!
+//! fn main() -> VortexResult<()> {
+//!     let config = VortexConfig::default();
+//!
+//!     let mut splines = load_collection(Path::new("0001/Lines"), &config)?;
+//!     order_collection(&mut splines, &config, None)?;
+//!     let events = plan_collection(&splines, &config)?;
+//!     let preview = generate_toolpath_preview(&events)?;
+//!
+//!     let files = CollectionFiles::new(Path::new("output"), "Lines");
+//!     write_toolpath(&files.toolpath, events)?;
+//!     write_viewer_data(&files, preview)
+//! }
+//! ```
+
+pub mod build_cache;
+pub mod calibration;
+pub mod color_difference;
+pub mod color_utils;
+pub mod config;
+pub mod delta_utils;
+pub mod error;
+pub mod export_data;
+pub mod export_types;
+pub mod fade_fitting;
+pub mod fade_space;
+pub mod flipbook;
+pub mod import_data;
+pub mod import_svg;
+pub mod import_types;
+pub mod kinematics;
+pub mod motion_profile;
+pub mod nurbs;
+pub mod ordering;
+pub mod particle_order;
+pub mod pipeline;
+pub mod preview;
+pub mod render;
+pub mod sequencer;
+pub mod speed_compensation;
+
+pub use calibration::{write_test_patches, LedProfile, TestPatches};
+pub use config::{FlipbookConfig, ProjectConfig, RenderConfig, VortexConfig};
+pub use error::{VortexError, VortexResult};
+pub use export_types::{ActionGroups, AnimationMetadata, FileMetadata, FrameMetadata};
+pub use flipbook::{write_flipbook, Flipbook};
+pub use import_types::BlenderData;
+pub use pipeline::*;
+pub use preview::{generate_toolpath_preview, ViewerData};
+pub use render::{render_animation, Camera, Exposure};
+pub use sequencer::generate_delta_toolpath;
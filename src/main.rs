@@ -1,242 +1,225 @@
-use std::path::{Path, PathBuf};
-
-extern crate walkdir;
-use walkdir::{DirEntry, WalkDir};
-
-pub mod import_data;
-pub mod import_types;
-use import_data::*;
-use import_types::*;
-
-pub mod export_data;
-pub mod export_types;
-use export_data::*;
-use export_types::*;
-
-pub mod sequencer;
-use sequencer::*;
-
-pub mod color_utils;
-pub mod delta_utils;
-
-use itertools::Itertools;
-use serde::Serialize;
-use std::fs;
-
-// Checks that a DirEntry isn't hidden, a __MACOSX folder, or a file
-fn is_frame_folder(entry: &DirEntry) -> bool {
-    if entry.file_type().is_dir() {
-        entry
-            .file_name()
-            .to_str()
-            .map(|s| !s.starts_with('.') && !s.starts_with("__"))
-            .unwrap_or(false)
-    } else {
-        false
-    }
-}
+use std::time::Duration;
 
-fn is_json_file(entry: &DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.ends_with(".json")) //&& !s.ends_with("toolpath.json")
-        .unwrap_or(false)
-}
+use clap::Parser;
 
-fn main() {
-    println!("Welcome to the Total Perspective Vortex!");
+use total_perspective_vortex::config::led_profile_settings;
+use total_perspective_vortex::export_types::AnimationMetadata;
+use total_perspective_vortex::*;
 
-    // Walk the folder structure looking for frame folders, then process them
-    let mut frame_meta: Vec<FrameMetadata> = WalkDir::new("./")
-        .min_depth(1)
-        .max_depth(1)
-        .into_iter()
-        .filter_entry(|e| is_frame_folder(e))
-        .filter_map(|v| v.ok())
-        .map(|x| process_frame_folder(&x))
-        .collect();
+mod cli;
+mod progress;
+mod watch;
+use cli::*;
+use progress::*;
 
-    // Sort the frames in ascending numeric order. Unstable sort means equal elements may move
-    frame_meta.sort_unstable_by_key(|x| x.frame_num);
+fn exit_with_error(error: &VortexError) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(2);
+}
 
-    let mut all_collection_names = vec![];
+fn main() {
+    let cli = Cli::parse();
 
-    for frame in &frame_meta {
-        for collection in &frame.collections {
-            all_collection_names.push(collection.name.clone());
-        }
+    // Test patches are planned on their own, without an animation
+    if let Command::Patches(patch_args) = &cli.command {
+        return write_patches(patch_args);
     }
-    let unique_collection_names: Vec<String> = all_collection_names.into_iter().unique().collect();
-
-    let summary = AnimationMetadata {
-        collections: unique_collection_names,
-        frames: frame_meta,
+    let args = cli
+        .command
+        .run_args()
+        .expect("every other command plans an animation");
+
+    let mode = match cli.command {
+        Command::Plan(_) | Command::Watch(_) | Command::Render(_) | Command::Flipbook(_) => {
+            OutputMode::Plan
+        }
+        Command::Preview(_) => OutputMode::Preview,
+        Command::Validate(_) | Command::Summary(_) => OutputMode::DryRun,
+        Command::Patches(_) => unreachable!("test patches are written before planning"),
     };
 
-    let overview_file =
-        serde_json::to_string_pretty(&summary).expect("Summary Serialisation Failed");
-    fs::write(Path::new("./summary.json"), overview_file).expect("Unable to write file");
-}
-
-// From a valid frame folder, find collections folders to process
-fn process_frame_folder(entry: &DirEntry) -> FrameMetadata {
-    let frame_folder_name = entry.file_name().to_string_lossy();
-    let frame_number = frame_folder_name.parse::<i32>().unwrap();
-    println!("\nProcessing Frame {}", frame_folder_name);
-
-    let exported_file_metadata: Vec<FileMetadata> = WalkDir::new(entry.path())
-        .min_depth(1)
-        .max_depth(1)
-        .into_iter()
-        .filter_entry(|e| e.file_type().is_dir())
-        .filter_map(|v| v.ok())
-        .map(|x| process_collection(&x))
-        .filter(|y| y.is_some())
-        .map(|z| z.unwrap())
-        .collect();
-
-    FrameMetadata {
-        frame_num: frame_number,
-        collections: exported_file_metadata,
+    // Command-line flags take priority over the project file
+    let mut project =
+        ProjectConfig::discover(args.config.as_deref()).unwrap_or_else(|e| exit_with_error(&e));
+    if let Some(path) = &args.led_profile {
+        let profile = led_profile_settings(path).unwrap_or_else(|e| exit_with_error(&e));
+        project
+            .force_settings(profile)
+            .unwrap_or_else(|e| exit_with_error(&e));
     }
-}
-
-#[derive(Serialize, Debug)]
-struct AnimationMetadata {
-    collections: Vec<String>,
-    frames: Vec<FrameMetadata>,
-}
-
-#[derive(Serialize, Debug)]
-struct FrameMetadata {
-    frame_num: i32,
-    collections: Vec<FileMetadata>,
-}
-
-#[derive(Serialize, Debug)]
-struct FileMetadata {
-    name: String,
-    toolpath_path: String,
-    duration: u32,
-    first_move: u32,
-    last_move: u32,
-    num_lights: u32,
-    viewer_vertices_path: String,
-    viewer_uv_path: String,
-}
+    project
+        .force_settings(args.tuning.to_settings())
+        .unwrap_or_else(|e| exit_with_error(&e));
+    if let Command::Render(render_args) = &cli.command {
+        project
+            .force_settings(render_args.to_settings())
+            .unwrap_or_else(|e| exit_with_error(&e));
+
+        // Catch bad camera settings before spending time planning
+        project.render().unwrap_or_else(|e| exit_with_error(&e));
+    }
+    if let Command::Flipbook(flipbook_args) = &cli.command {
+        project
+            .force_settings(flipbook_args.to_settings())
+            .unwrap_or_else(|e| exit_with_error(&e));
 
-// A collection is the deepest level folder. Contains json and (optional) uv files from Blender
-fn process_collection(entry: &DirEntry) -> Option<FileMetadata> {
-    // Parse all the json files in the current directory
-    let parsed_splines: Vec<BlenderData> = WalkDir::new(entry.path())
-        .min_depth(1)
-        .max_depth(1)
-        .into_iter()
-        .filter_entry(|e| is_json_file(e))
-        .filter_map(|v| v.ok())
-        .map(|x| load_blender_data(&x.path()))
-        .collect();
-
-    if parsed_splines.is_empty() {
-        return None;
+        project.render().unwrap_or_else(|e| exit_with_error(&e));
+        project.flipbook().unwrap_or_else(|e| exit_with_error(&e));
     }
 
-    // Take our spline+illumination data, and generate a tool-path
-    let planned_events = generate_delta_toolpath(&parsed_splines);
+    let output_root = args.output_root().unwrap_or_else(|e| exit_with_error(&e));
+    let mut pipeline =
+        Pipeline::new(project, &output_root, mode).unwrap_or_else(|e| exit_with_error(&e));
+    pipeline.jobs = args.jobs;
+    pipeline.force = args.force;
+    pipeline.progress = Box::new(ConsoleProgress::new());
+    ConsoleLogger::install();
 
-    if planned_events.delta.is_empty() {
-        return None;
+    println!("Welcome to the Total Perspective Vortex!");
+    if let Some(path) = &pipeline.project.path {
+        println!("Using settings from {}", path.display());
     }
 
-    // Generate additional exports for use in the UI as previz data
-    let viewer_preview = generate_viewer_data(&parsed_splines);
-
-    let duration: u32 = planned_events
-        .delta
-        .iter()
-        .map(|x| x.payload.duration)
-        .sum();
-
-    let first_move = planned_events
-        .delta
-        .first()
-        .expect("No first move")
-        .payload
-        .id;
-    let last_move = planned_events
-        .delta
-        .last()
-        .expect("No last move")
-        .payload
-        .id;
-
-    let num_lights = planned_events.light.len() as u32;
-    // Add header information
-    let output_data: DeltaEvents = DeltaEvents {
-        metadata: generate_header(String::from("VortexFile")),
-        actions: vec![planned_events],
-    };
+    let mut summary = pipeline.process_animation(&args.input);
 
-    // Put the output JSON in the parent folder alongside the other collection exports
-    let collection_name = entry
-        .path()
-        .file_name()
-        .expect("Couldn't get collection name")
-        .to_str()
-        .expect("Failed converting collection name to string")
-        .to_string();
-
-    let destination_folder = entry.path().parent().unwrap();
-
-    let delta_path = format_filename(
-        destination_folder,
-        collection_name.clone(),
-        "toolpath.json".to_string(),
-    );
+    match &cli.command {
+        Command::Plan(_) => {
+            pipeline
+                .write_summary(&summary)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            print_rebuilt(&summary);
+        }
+        Command::Summary(_) => print_summary(&summary),
+        Command::Validate(_) => {
+            if summary.failure_count() > 0 {
+                eprintln!(
+                    "\n{} collections failed validation",
+                    summary.failure_count()
+                );
+                std::process::exit(1);
+            }
+            println!("\nAll collections passed validation");
+        }
+        Command::Preview(_) => print_rebuilt(&summary),
+        Command::Watch(watch_args) => {
+            pipeline
+                .write_summary(&summary)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            print_rebuilt(&summary);
+
+            let debounce = Duration::from_millis(watch_args.debounce);
+            watch::watch(&pipeline, &args.input, &mut summary, debounce)
+                .unwrap_or_else(|e| exit_with_error(&e));
+        }
+        Command::Render(render_args) => {
+            pipeline
+                .write_summary(&summary)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            print_rebuilt(&summary);
+
+            let config = pipeline
+                .project
+                .render()
+                .unwrap_or_else(|e| exit_with_error(&e));
+            let rendered = render_animation(
+                &summary,
+                &pipeline.output_root,
+                &config,
+                render_args.per_collection,
+            )
+            .unwrap_or_else(|e| exit_with_error(&e));
+            println!(
+                "Rendered {} frames to {}",
+                rendered.len(),
+                pipeline.output_root.join("render").display()
+            );
+        }
+        Command::Flipbook(_) => {
+            pipeline
+                .write_summary(&summary)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            print_rebuilt(&summary);
+
+            let camera = pipeline
+                .project
+                .render()
+                .unwrap_or_else(|e| exit_with_error(&e));
+            let config = pipeline
+                .project
+                .flipbook()
+                .unwrap_or_else(|e| exit_with_error(&e));
+            let flipbook = write_flipbook(&summary, &pipeline.output_root, &camera, &config)
+                .unwrap_or_else(|e| exit_with_error(&e));
+            println!(
+                "Sketched {} frames, contact sheet {}, animation {}",
+                flipbook.frames.len(),
+                flipbook.contact_sheet.display(),
+                flipbook.animation.display()
+            );
+        }
+        Command::Patches(_) => {}
+    }
+}
 
-    let vertex_path = format_filename(
-        destination_folder,
-        collection_name.clone(),
-        "vertices.json".to_string(),
+fn write_patches(args: &PatchArgs) {
+    let config = ProjectConfig::discover(args.config.as_deref())
+        .and_then(|project| project.base())
+        .unwrap_or_else(|e| exit_with_error(&e));
+
+    let files = write_test_patches(&args.output, &config, args.levels, args.size)
+        .unwrap_or_else(|e| exit_with_error(&e));
+    println!(
+        "Wrote test patches to {}, with their drive levels in {}",
+        files.toolpath.display(),
+        files.patches.display()
     );
+}
 
-    let uv_path = format_filename(
-        destination_folder,
-        collection_name.clone(),
-        "uv.png".to_string(),
+fn print_rebuilt(summary: &AnimationMetadata) {
+    let reused = summary.reused_count();
+    println!(
+        "\n{} collections rebuilt, {} reused, {} failed",
+        summary.collection_count() - reused,
+        reused,
+        summary.failure_count()
     );
-
-    // Write to disk
-    export_toolpath(&delta_path.as_path(), output_data);
-    export_vertices(&vertex_path.as_path(), viewer_preview.0);
-    export_uv(&uv_path.as_path(), viewer_preview.1);
-
-    Some(FileMetadata {
-        name: collection_name,
-        toolpath_path: pathbuf_to_string(delta_path),
-        duration,
-        first_move,
-        last_move,
-        num_lights,
-        viewer_vertices_path: pathbuf_to_string(vertex_path),
-        viewer_uv_path: pathbuf_to_string(uv_path),
-    })
 }
 
-fn pathbuf_to_string(input: PathBuf) -> String {
-    input.to_str().unwrap().to_string()
-}
+fn print_summary(summary: &AnimationMetadata) {
+    println!(
+        "\n{:>6}  {:<24} {:>10} {:>8} {:>8}",
+        "Frame", "Collection", "Duration", "Moves", "Lights"
+    );
 
-// Takes a destination folder, the name of the collection, and the extension of the file
-// Returns a path to the location of the file, with a cleaner filename
-fn format_filename(destination: &Path, name: String, extension: String) -> PathBuf {
-    let mut collection_name = name.to_lowercase();
-    collection_name.retain(|c| !c.is_whitespace());
+    let mut total_duration = 0;
+    for frame in &summary.frames {
+        for collection in &frame.collections {
+            println!(
+                "{:>6}  {:<24} {:>8}ms {:>8} {:>8}",
+                frame.frame_num,
+                collection.name,
+                collection.duration,
+                collection.last_move - collection.first_move + 1,
+                collection.num_lights
+            );
+            total_duration += collection.duration;
+        }
+    }
 
-    let file_name = format!("{}_{}", collection_name, extension);
-    let path = Path::new(&file_name);
-    let parent_folder = destination;
+    for frame in &summary.frames {
+        for failure in &frame.failures {
+            println!(
+                "{:>6}  {:<24} failed: {}",
+                frame.frame_num, failure.name, failure.error
+            );
+        }
+    }
 
-    parent_folder.join(&path)
+    println!(
+        "\n{} frames, {} collections, {:.1}s of drawing, {} failed collections",
+        summary.frames.len(),
+        summary.collections.len(),
+        total_duration as f32 / 1000.0,
+        summary.failure_count()
+    );
 }
@@ -0,0 +1,500 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::PlannerConfig;
+use crate::delta_utils::*;
+use crate::error::*;
+use crate::export_types::*;
+use crate::import_types::*;
+
+// Points sampled along each move to estimate its length and the direction at either end
+const PROFILE_SAMPLES: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileKind {
+    Constant,    // every move at the movement speed, ignoring acceleration
+    Trapezoidal, // acceleration limited
+    SCurve,      // acceleration and jerk limited
+}
+
+// Calculates move durations from a velocity profile across a chain of consecutive moves.
+// Chains start and finish at rest, the speed through each junction is limited by the angle between the moves.
+#[derive(Debug, Clone)]
+pub struct MotionProfile {
+    kind: ProfileKind,
+    max_velocity: f32,       // mm/s
+    acceleration: f32,       // mm/s^2
+    jerk: f32,               // mm/s^3
+    junction_deviation: f32, // mm
+}
+
+// A change of speed, with time in ms
+#[derive(Debug, Clone, Copy, Default)]
+struct Ramp {
+    from: f32,
+    to: f32,
+    time: f32,
+    jerk_fraction: f32, // fraction of the ramp at either end where the acceleration changes, 0 for trapezoidal
+}
+
+impl Ramp {
+    // Speed in mm/s some time into the ramp
+    fn speed_at(&self, time: f32) -> f32 {
+        if self.time <= 0.0 {
+            return self.to;
+        }
+
+        // How far through the change of speed the ramp is, following the acceleration's shape
+        let u = (time / self.time).clamp(0.0, 1.0);
+        let p = self.jerk_fraction;
+        let progress = if p <= 0.0 {
+            u
+        } else if u < p {
+            u * u / (2.0 * p * (1.0 - p))
+        } else if u <= 1.0 - p {
+            (u - p / 2.0) / (1.0 - p)
+        } else {
+            1.0 - (1.0 - u).powi(2) / (2.0 * p * (1.0 - p))
+        };
+
+        self.from + (self.to - self.from) * progress
+    }
+}
+
+// How the speed changes through a move, speeding up to a peak and cruising before slowing down
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MoveVelocity {
+    accel: Ramp,
+    cruise_speed: f32, // mm/s
+    cruise_time: f32,  // ms
+    decel: Ramp,
+}
+
+impl MoveVelocity {
+    // Steady speed for the whole move
+    fn steady(speed: f32, time: f32) -> MoveVelocity {
+        MoveVelocity {
+            cruise_speed: speed,
+            cruise_time: time,
+            ..MoveVelocity::default()
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.accel.time + self.cruise_time + self.decel.time
+    }
+
+    // Speed in mm/s some time in ms into the move
+    pub fn speed_at(&self, time: f32) -> f32 {
+        if time < self.accel.time {
+            self.accel.speed_at(time)
+        } else if time <= self.accel.time + self.cruise_time {
+            self.cruise_speed
+        } else {
+            self.decel
+                .speed_at(time - self.accel.time - self.cruise_time)
+        }
+    }
+
+    // Times in ms into the move where it stops speeding up and starts slowing down
+    pub fn phase_times(&self) -> [f32; 2] {
+        [self.accel.time, self.accel.time + self.cruise_time]
+    }
+
+    // The same profile taking a different time, as durations are rounded and have a minimum
+    fn stretched(self, time: f32) -> MoveVelocity {
+        let natural = self.duration();
+        if natural <= 0.0 || time <= 0.0 {
+            return MoveVelocity::steady(0.0, time);
+        }
+
+        let rate = natural / time;
+        let ramp = |r: Ramp| Ramp {
+            from: r.from * rate,
+            to: r.to * rate,
+            time: r.time / rate,
+            jerk_fraction: r.jerk_fraction,
+        };
+
+        MoveVelocity {
+            accel: ramp(self.accel),
+            cruise_speed: self.cruise_speed * rate,
+            cruise_time: self.cruise_time / rate,
+            decel: ramp(self.decel),
+        }
+    }
+}
+
+// Length and direction at each end of a move
+struct MoveShape {
+    length: f32,
+    entry: BlenderPoint3,
+    exit: BlenderPoint3,
+}
+
+impl MotionProfile {
+    pub fn new(config: &PlannerConfig) -> MotionProfile {
+        MotionProfile {
+            kind: config.motion_profile,
+            max_velocity: config.movement_speed,
+            acceleration: config.acceleration,
+            jerk: config.jerk,
+            junction_deviation: config.junction_deviation,
+        }
+    }
+
+    // Set the duration of each move in a chain which starts and ends stationary.
+    // Returns how the speed changes through each move, over the duration it was given.
+    pub fn apply(&self, motions: &mut [Motion]) -> VortexResult<Vec<MoveVelocity>> {
+        let shapes = motions
+            .iter()
+            .map(move_shape)
+            .collect::<VortexResult<Vec<MoveShape>>>()?;
+
+        let speeds = self.junction_speeds(&shapes);
+
+        let mut velocities = vec![];
+        for (i, (motion, shape)) in motions.iter_mut().zip(&shapes).enumerate() {
+            let velocity = match self.kind {
+                ProfileKind::Constant => MoveVelocity::steady(
+                    self.max_velocity,
+                    constant_duration(motion, self.max_velocity)?,
+                ),
+                _ => self.move_velocity(shape.length, speeds[i], speeds[i + 1]),
+            };
+
+            motion.duration = velocity.duration().max(MIN_MOVE_DURATION_MS) as u32;
+            velocities.push(velocity.stretched(motion.duration as f32));
+        }
+
+        Ok(velocities)
+    }
+
+    // Duration of a straight transit between two points, starting and finishing at rest
+    pub fn transit_duration(&self, a: &BlenderPoint3, b: &BlenderPoint3) -> VortexResult<u32> {
+        if a == b {
+            return Ok(0); // no transit move is generated
+        }
+
+        let mut transit = Motion {
+            id: 0,
+            reference: MotionReferenceFrame::Absolute,
+            motion_type: MotionInterpolationType::Line,
+            duration: 0,
+            points: vec![(a.x, a.y, a.z), (b.x, b.y, b.z)],
+        };
+        self.apply(std::slice::from_mut(&mut transit))?;
+
+        Ok(transit.duration)
+    }
+
+    // Velocity at the start of each move and the end of the last one
+    fn junction_speeds(&self, shapes: &[MoveShape]) -> Vec<f32> {
+        let mut speeds = vec![0.0; shapes.len() + 1];
+
+        // Cornering limits at each junction
+        for i in 1..shapes.len() {
+            speeds[i] = self.cornering_speed(&shapes[i - 1].exit, &shapes[i].entry);
+        }
+
+        // Backwards pass, make sure each move can slow down enough for the next junction
+        for i in (0..shapes.len()).rev() {
+            let reachable = self.reachable_speed(speeds[i + 1], shapes[i].length);
+            speeds[i] = speeds[i].min(reachable);
+        }
+
+        // Forwards pass, make sure each move can speed up enough to reach the next junction
+        for i in 0..shapes.len() {
+            let reachable = self.reachable_speed(speeds[i], shapes[i].length);
+            speeds[i + 1] = speeds[i + 1].min(reachable);
+        }
+
+        speeds
+    }
+
+    // Junction deviation cornering, the speed at which a circle tangent to both moves and passing
+    // junction_deviation from the corner can be followed with the available acceleration
+    fn cornering_speed(&self, exit: &BlenderPoint3, entry: &BlenderPoint3) -> f32 {
+        let cos_theta = -(exit.x * entry.x + exit.y * entry.y + exit.z * entry.z);
+
+        if cos_theta > 0.999_999 {
+            return 0.0; // reversing direction
+        }
+        if cos_theta < -0.999_999 {
+            return self.max_velocity; // straight through
+        }
+
+        let sin_half_theta = ((1.0 - cos_theta) * 0.5).sqrt();
+        let speed = (self.acceleration * self.junction_deviation * sin_half_theta
+            / (1.0 - sin_half_theta))
+            .sqrt();
+
+        speed.min(self.max_velocity)
+    }
+
+    // Fastest speed which can be reached over a distance, starting at v0
+    fn reachable_speed(&self, v0: f32, distance: f32) -> f32 {
+        if self.kind == ProfileKind::Constant {
+            return self.max_velocity;
+        }
+
+        let (mut low, mut high) = (v0, self.max_velocity);
+        if self.ramp(v0, high).1 <= distance {
+            return high;
+        }
+
+        for _ in 0..32 {
+            let mid = (low + high) * 0.5;
+            if self.ramp(v0, mid).1 <= distance {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        low
+    }
+
+    // Time and distance to change between two speeds
+    fn ramp(&self, v_from: f32, v_to: f32) -> (f32, f32) {
+        let dv = (v_to - v_from).abs();
+
+        let time = match self.kind {
+            ProfileKind::SCurve if self.jerk > 0.0 => {
+                // Acceleration ramps up and down at the jerk limit, with a constant section if it saturates
+                if dv >= self.acceleration * self.acceleration / self.jerk {
+                    dv / self.acceleration + self.acceleration / self.jerk
+                } else {
+                    2.0 * (dv / self.jerk).sqrt()
+                }
+            }
+            _ => dv / self.acceleration,
+        };
+
+        // Both profiles are symmetric, so the average speed is the midpoint
+        (time, (v_from + v_to) * 0.5 * time)
+    }
+
+    // A ramp between two speeds, with its time in ms
+    fn ramp_between(&self, v_from: f32, v_to: f32) -> Ramp {
+        let dv = (v_to - v_from).abs();
+        let time = self.ramp(v_from, v_to).0;
+
+        // S-curves spend a jerk limited time at either end, or all of the ramp when it's short
+        let jerk_fraction = match self.kind {
+            ProfileKind::SCurve if self.jerk > 0.0 && time > 0.0 => {
+                if dv >= self.acceleration * self.acceleration / self.jerk {
+                    self.acceleration / self.jerk / time
+                } else {
+                    0.5
+                }
+            }
+            _ => 0.0,
+        };
+
+        Ramp {
+            from: v_from,
+            to: v_to,
+            time: time * 1000.0,
+            jerk_fraction,
+        }
+    }
+
+    // Travel a distance starting at v0 and finishing at v1, cruising as fast as possible in between
+    fn move_velocity(&self, distance: f32, v0: f32, v1: f32) -> MoveVelocity {
+        if distance <= 0.0 {
+            return MoveVelocity::default();
+        }
+
+        let floor = v0.max(v1);
+        let (mut low, mut high) = (floor, self.max_velocity.max(floor));
+
+        let fits = |peak: f32| self.ramp(v0, peak).1 + self.ramp(peak, v1).1 <= distance;
+
+        // Find the highest peak speed where the acceleration and deceleration fit in the move
+        if !fits(high) {
+            for _ in 0..32 {
+                let mid = (low + high) * 0.5;
+                if fits(mid) {
+                    low = mid;
+                } else {
+                    high = mid;
+                }
+            }
+            high = low;
+        }
+
+        let peak = high.max(f32::EPSILON);
+        let accel_distance = self.ramp(v0, peak).1;
+        let decel_distance = self.ramp(peak, v1).1;
+        let cruise = (distance - accel_distance - decel_distance).max(0.0);
+
+        MoveVelocity {
+            accel: self.ramp_between(v0, peak),
+            cruise_speed: peak,
+            cruise_time: cruise / peak * 1000.0,
+            decel: self.ramp_between(peak, v1),
+        }
+    }
+}
+
+fn move_shape(motion: &Motion) -> VortexResult<MoveShape> {
+    let samples = sample_motion(motion, PROFILE_SAMPLES)?;
+
+    // Bezier splines can curve tightly between samples, so they're measured more closely
+    let length = match motion.motion_type {
+        MotionInterpolationType::BezierQuadratic | MotionInterpolationType::BezierCubic => {
+            let points: Vec<BlenderPoint3> = motion.points.iter().map(|&p| p.into()).collect();
+            distance_bezier(&points)?
+        }
+        _ => samples.windows(2).map(|p| distance_3d(&p[0], &p[1])).sum(),
+    };
+
+    // Looking back from the end of the move gives the reversed exit direction
+    let mut exit = direction(samples.iter().rev());
+    exit.scale(-1.0);
+
+    Ok(MoveShape {
+        length,
+        entry: direction(samples.iter()),
+        exit,
+    })
+}
+
+// Constant speed durations, matching the planner's behaviour before motion profiles were added
+fn constant_duration(motion: &Motion, speed: f32) -> VortexResult<f32> {
+    let points: Vec<BlenderPoint3> = motion.points.iter().map(|&p| p.into()).collect();
+
+    match motion.motion_type {
+        // Measured along the curve. Transit control points lie on the line between the ends, so it's their straight length
+        MotionInterpolationType::BezierQuadratic | MotionInterpolationType::BezierCubic => {
+            let duration = (distance_bezier(&points)? / speed) * 1000.0;
+            Ok(duration.max(MIN_MOVE_DURATION_MS))
+        }
+        _ => calculate_duration(&points, speed),
+    }
+}
+
+// Direction from the first point to the next distinct point
+fn direction<'a, I: Iterator<Item = &'a BlenderPoint3>>(mut points: I) -> BlenderPoint3 {
+    let first = match points.next() {
+        Some(p) => *p,
+        None => return BlenderPoint3::default(),
+    };
+
+    points
+        .find(|p| distance_3d(&first, p) > f32::EPSILON)
+        .map(|p| {
+            let length = distance_3d(&first, p);
+            BlenderPoint3 {
+                x: (p.x - first.x) / length,
+                y: (p.y - first.y) / length,
+                z: (p.z - first.z) / length,
+            }
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(kind: ProfileKind) -> MotionProfile {
+        MotionProfile::new(&PlannerConfig {
+            motion_profile: kind,
+            movement_speed: 200.0,
+            acceleration: 2000.0,
+            jerk: 40000.0,
+            ..PlannerConfig::default()
+        })
+    }
+
+    fn line(from: (f32, f32, f32), to: (f32, f32, f32)) -> Motion {
+        Motion {
+            id: 0,
+            reference: MotionReferenceFrame::Absolute,
+            motion_type: MotionInterpolationType::Line,
+            duration: 0,
+            points: vec![from, to],
+        }
+    }
+
+    #[test]
+    fn constant_profile_runs_at_movement_speed() {
+        let mut moves = [line((0.0, 0.0, 0.0), (100.0, 0.0, 0.0))];
+        let velocities = profile(ProfileKind::Constant).apply(&mut moves).unwrap();
+
+        assert_eq!(moves[0].duration, 500);
+        assert!((velocities[0].speed_at(0.0) - 200.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn trapezoidal_profile_accelerates_cruises_and_decelerates() {
+        // 0.1s to reach 200 mm/s over 10mm at either end, cruising 80mm for 0.4s
+        let mut moves = [line((0.0, 0.0, 0.0), (100.0, 0.0, 0.0))];
+        let velocities = profile(ProfileKind::Trapezoidal).apply(&mut moves).unwrap();
+        let velocity = &velocities[0];
+
+        assert!((i64::from(moves[0].duration) - 600).abs() <= 1);
+        assert!(velocity.speed_at(0.0).abs() < 0.01);
+        assert!((velocity.speed_at(50.0) - 100.0).abs() < 1.0);
+        assert!((velocity.speed_at(300.0) - 200.0).abs() < 1.0);
+        assert!(velocity.speed_at(moves[0].duration as f32).abs() < 1.0);
+
+        let [accelerated, cruised] = velocity.phase_times();
+        assert!((accelerated - 100.0).abs() < 1.0);
+        assert!((cruised - 500.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn short_moves_never_reach_full_speed() {
+        // Peak speed is sqrt(acceleration * distance), reached halfway
+        let mut moves = [line((0.0, 0.0, 0.0), (4.0, 0.0, 0.0))];
+        let velocities = profile(ProfileKind::Trapezoidal).apply(&mut moves).unwrap();
+
+        let peak = (2000.0_f32 * 4.0).sqrt();
+        let expected = 2.0 * peak / 2000.0 * 1000.0;
+        assert!((moves[0].duration as f32 - expected).abs() <= 1.0);
+        assert!((velocities[0].speed_at(moves[0].duration as f32 / 2.0) - peak).abs() < 2.0);
+    }
+
+    #[test]
+    fn s_curve_takes_longer_than_trapezoidal() {
+        let mut trapezoidal = [line((0.0, 0.0, 0.0), (100.0, 0.0, 0.0))];
+        let mut s_curve = [line((0.0, 0.0, 0.0), (100.0, 0.0, 0.0))];
+        profile(ProfileKind::Trapezoidal)
+            .apply(&mut trapezoidal)
+            .unwrap();
+        let velocities = profile(ProfileKind::SCurve).apply(&mut s_curve).unwrap();
+
+        assert!(s_curve[0].duration > trapezoidal[0].duration);
+
+        // Jerk limiting starts the ramp more gently
+        let trapezoidal_start = 2000.0 * 0.005;
+        assert!(velocities[0].speed_at(5.0) < trapezoidal_start);
+    }
+
+    #[test]
+    fn corners_slow_the_effector_down() {
+        let mut straight = [
+            line((0.0, 0.0, 0.0), (50.0, 0.0, 0.0)),
+            line((50.0, 0.0, 0.0), (100.0, 0.0, 0.0)),
+        ];
+        let mut corner = [
+            line((0.0, 0.0, 0.0), (50.0, 0.0, 0.0)),
+            line((50.0, 0.0, 0.0), (50.0, 50.0, 0.0)),
+        ];
+        let profile = profile(ProfileKind::Trapezoidal);
+        let straight_velocities = profile.apply(&mut straight).unwrap();
+        let corner_velocities = profile.apply(&mut corner).unwrap();
+
+        // A straight junction doesn't slow down at all, so it matches a single 100mm move
+        let total: u32 = straight.iter().map(|m| m.duration).sum();
+        assert!((i64::from(total) - 600).abs() <= 2);
+        assert!((straight_velocities[1].speed_at(0.0) - 200.0).abs() < 1.0);
+
+        let cornered: u32 = corner.iter().map(|m| m.duration).sum();
+        assert!(cornered > total);
+        assert!(corner_velocities[1].speed_at(0.0) < 50.0);
+    }
+}
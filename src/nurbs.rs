@@ -0,0 +1,402 @@
+use crate::delta_utils::*;
+use crate::error::*;
+use crate::import_types::*;
+
+// Each knot span is halved until its bezier is within tolerance, at most this many times
+const MAX_SUBDIVISIONS: usize = 10;
+
+// Points along each bezier which are checked against the NURBS curve
+const ERROR_SAMPLES: usize = 8;
+
+// The knot vector Blender generates for a spline, used when the export doesn't include one.
+// Uniform knots don't reach the first and last points, endpoint knots are clamped so the curve starts and
+// finishes on them, and bezier knots make each span of 3 (order 4) or 2 (order 3) points act like a bezier.
+pub fn generate_knots(
+    points: usize,
+    order: usize,
+    use_endpoint: bool,
+    use_bezier: bool,
+) -> Vec<f32> {
+    let count = points + order;
+
+    match (use_bezier, order) {
+        (true, 4) => (0..count)
+            .map(|i| (0.34 + i as f32 / 3.0).floor())
+            .collect(),
+        (true, 3) => (0..count)
+            .map(|i| (0.6 + 0.5 * i.min(points).saturating_sub(order - 1) as f32).floor())
+            .collect(),
+        _ if use_endpoint => (0..count)
+            .map(|i| (i.min(points) + 1).saturating_sub(order) as f32)
+            .collect(),
+        _ => (0..count).map(|i| i as f32).collect(),
+    }
+}
+
+// Check a spline can be evaluated, with its knot vector already generated
+pub fn validate_nurbs(spline: &BlenderNURBS) -> VortexResult<()> {
+    if spline.order < 2 {
+        return Err(VortexError::UnsupportedOperation(format!(
+            "NURBS order must be at least 2, got {}",
+            spline.order
+        )));
+    }
+
+    if spline.points.len() < spline.order {
+        return Err(VortexError::EmptyGeometry(format!(
+            "order {} NURBS spline has {} points",
+            spline.order,
+            spline.points.len()
+        )));
+    }
+
+    if spline.knots.len() != spline.points.len() + spline.order {
+        return Err(VortexError::UnsupportedOperation(format!(
+            "NURBS spline with {} points and order {} needs {} knots, got {}",
+            spline.points.len(),
+            spline.order,
+            spline.points.len() + spline.order,
+            spline.knots.len()
+        )));
+    }
+
+    if spline.knots.windows(2).any(|k| k[1] < k[0]) {
+        return Err(VortexError::UnsupportedOperation(
+            "NURBS knots must not decrease".to_string(),
+        ));
+    }
+
+    if spline.points.iter().any(|p| p.w <= 0.0) {
+        return Err(VortexError::UnsupportedOperation(
+            "NURBS weights must be positive".to_string(),
+        ));
+    }
+
+    let degree = spline.order - 1;
+    if spline.knots[degree] >= spline.knots[spline.points.len()] {
+        return Err(VortexError::EmptyGeometry(
+            "NURBS knots don't leave any curve to draw".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// A rational B-spline with its control points in homogeneous co-ordinates (x*w, y*w, z*w, w)
+struct Curve {
+    points: Vec<[f64; 4]>,
+    knots: Vec<f64>,
+    degree: usize,
+}
+
+impl Curve {
+    fn new(spline: &BlenderNURBS) -> VortexResult<Curve> {
+        validate_nurbs(spline)?;
+
+        Ok(Curve {
+            points: spline
+                .points
+                .iter()
+                .map(|p| {
+                    let w = f64::from(p.w);
+                    [
+                        f64::from(p.x) * w,
+                        f64::from(p.y) * w,
+                        f64::from(p.z) * w,
+                        w,
+                    ]
+                })
+                .collect(),
+            knots: spline.knots.iter().map(|&k| f64::from(k)).collect(),
+            degree: spline.order - 1,
+        })
+    }
+
+    // Knot spans which contain part of the curve, as (span index, start, end)
+    fn spans(&self) -> Vec<(usize, f64, f64)> {
+        (self.degree..self.points.len())
+            .filter(|&k| self.knots[k] < self.knots[k + 1])
+            .map(|k| (k, self.knots[k], self.knots[k + 1]))
+            .collect()
+    }
+
+    // Evaluating within a given span gives that span's polynomial, so the ends of a span can be found
+    // even when the curve isn't smooth across the knot
+    fn point(&self, span: usize, u: f64) -> [f64; 3] {
+        let h = de_boor(&self.points, &self.knots, self.degree, span, u);
+        [h[0] / h[3], h[1] / h[3], h[2] / h[3]]
+    }
+
+    fn derivative(&self, span: usize, u: f64) -> [f64; 3] {
+        let h = de_boor(&self.points, &self.knots, self.degree, span, u);
+
+        // The derivative of a B-spline is a B-spline of one lower degree through scaled control point differences
+        let p = self.degree as f64;
+        let differences: Vec<[f64; 4]> = self
+            .points
+            .windows(2)
+            .enumerate()
+            .map(|(i, pair)| {
+                let span_width = self.knots[i + self.degree + 1] - self.knots[i + 1];
+                let scale = if span_width > 0.0 {
+                    p / span_width
+                } else {
+                    0.0
+                };
+                [
+                    (pair[1][0] - pair[0][0]) * scale,
+                    (pair[1][1] - pair[0][1]) * scale,
+                    (pair[1][2] - pair[0][2]) * scale,
+                    (pair[1][3] - pair[0][3]) * scale,
+                ]
+            })
+            .collect();
+        let dh = de_boor(
+            &differences,
+            &self.knots[1..self.knots.len() - 1],
+            self.degree - 1,
+            span - 1,
+            u,
+        );
+
+        // Quotient rule, C = A / w so C' = (A' - w' C) / w
+        let c = [h[0] / h[3], h[1] / h[3], h[2] / h[3]];
+        [
+            (dh[0] - dh[3] * c[0]) / h[3],
+            (dh[1] - dh[3] * c[1]) / h[3],
+            (dh[2] - dh[3] * c[2]) / h[3],
+        ]
+    }
+}
+
+fn de_boor(points: &[[f64; 4]], knots: &[f64], degree: usize, span: usize, u: f64) -> [f64; 4] {
+    let mut d: Vec<[f64; 4]> = (0..=degree).map(|j| points[j + span - degree]).collect();
+
+    for r in 1..=degree {
+        for j in (r..=degree).rev() {
+            let i = j + span - degree;
+            let width = knots[i + 1 + degree - r] - knots[i];
+            let alpha = if width > 0.0 {
+                (u - knots[i]) / width
+            } else {
+                0.0
+            };
+
+            let previous = d[j - 1];
+            for (value, before) in d[j].iter_mut().zip(previous.iter()) {
+                *value = (1.0 - alpha) * before + alpha * *value;
+            }
+        }
+    }
+
+    d[degree]
+}
+
+fn to_point(p: [f64; 3]) -> BlenderPoint3 {
+    BlenderPoint3 {
+        x: p[0] as f32,
+        y: p[1] as f32,
+        z: p[2] as f32,
+    }
+}
+
+// Where the curve starts and finishes
+pub fn nurbs_ends(spline: &BlenderNURBS) -> VortexResult<(BlenderPoint3, BlenderPoint3)> {
+    let curve = Curve::new(spline)?;
+    let spans = curve.spans();
+
+    match (spans.first(), spans.last()) {
+        (Some(&(first, start, _)), Some(&(last, _, end))) => Ok((
+            to_point(curve.point(first, start)),
+            to_point(curve.point(last, end)),
+        )),
+        _ => Err(VortexError::EmptyGeometry(
+            "NURBS spline has no knot spans".to_string(),
+        )),
+    }
+}
+
+// Convert the curve into cubic beziers which the firmware can draw, each within tolerance (in mm) of the curve.
+// Each knot span starts as one bezier matching the curve's position and direction at either end, which is
+// exact for non-rational cubic splines. Spans which are rational, or of a higher order, are split until they fit.
+pub fn nurbs_to_bezier(
+    spline: &BlenderNURBS,
+    tolerance: f32,
+) -> VortexResult<Vec<[BlenderPoint3; 4]>> {
+    let curve = Curve::new(spline)?;
+    let mut segments = vec![];
+
+    for (span, start, end) in curve.spans() {
+        fit_bezier(
+            &curve,
+            span,
+            start,
+            end,
+            f64::from(tolerance),
+            0,
+            &mut segments,
+        )?;
+    }
+
+    Ok(segments)
+}
+
+fn fit_bezier(
+    curve: &Curve,
+    span: usize,
+    start: f64,
+    end: f64,
+    tolerance: f64,
+    depth: usize,
+    segments: &mut Vec<[BlenderPoint3; 4]>,
+) -> VortexResult<()> {
+    let p0 = curve.point(span, start);
+    let p3 = curve.point(span, end);
+    let d0 = curve.derivative(span, start);
+    let d3 = curve.derivative(span, end);
+    let third = (end - start) / 3.0;
+
+    let bezier = [
+        to_point(p0),
+        to_point([
+            p0[0] + d0[0] * third,
+            p0[1] + d0[1] * third,
+            p0[2] + d0[2] * third,
+        ]),
+        to_point([
+            p3[0] - d3[0] * third,
+            p3[1] - d3[1] * third,
+            p3[2] - d3[2] * third,
+        ]),
+        to_point(p3),
+    ];
+
+    let mut error: f64 = 0.0;
+    for i in 1..ERROR_SAMPLES {
+        let t = i as f64 / ERROR_SAMPLES as f64;
+        let on_bezier = interpolate_bezier_point(&bezier, t as f32)?;
+        let on_curve = to_point(curve.point(span, start + (end - start) * t));
+        error = error.max(f64::from(distance_3d(&on_bezier, &on_curve)));
+    }
+
+    if error > tolerance && depth < MAX_SUBDIVISIONS {
+        let middle = (start + end) / 2.0;
+        fit_bezier(curve, span, start, middle, tolerance, depth + 1, segments)?;
+        fit_bezier(curve, span, middle, end, tolerance, depth + 1, segments)?;
+    } else {
+        segments.push(bezier);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nurbs(points: &[(f32, f32, f32, f32)], order: usize) -> BlenderNURBS {
+        BlenderNURBS {
+            curve_length: 0.0,
+            points: points
+                .iter()
+                .map(|&(x, y, z, w)| BlenderPoint4 { x, y, z, w })
+                .collect(),
+            cyclic: false,
+            uv_path: String::new(),
+            order,
+            knots: generate_knots(points.len(), order, true, false),
+            use_endpoint: true,
+            use_bezier: false,
+            color: vec![],
+        }
+    }
+
+    fn close(a: &BlenderPoint3, b: &BlenderPoint3) -> bool {
+        distance_3d(a, b) < 1e-4
+    }
+
+    #[test]
+    fn endpoint_knots_are_clamped() {
+        assert_eq!(
+            generate_knots(4, 4, true, false),
+            vec![0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0]
+        );
+        assert_eq!(
+            generate_knots(5, 3, true, false),
+            vec![0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 3.0, 3.0]
+        );
+        assert_eq!(
+            generate_knots(3, 2, false, false),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0]
+        );
+    }
+
+    #[test]
+    fn clamped_cubic_is_its_own_bezier() {
+        let spline = nurbs(
+            &[
+                (0.0, 0.0, 0.0, 1.0),
+                (10.0, 20.0, 0.0, 1.0),
+                (30.0, 20.0, 5.0, 1.0),
+                (40.0, 0.0, 5.0, 1.0),
+            ],
+            4,
+        );
+
+        let segments = nurbs_to_bezier(&spline, 0.01).unwrap();
+
+        assert_eq!(segments.len(), 1);
+        for (point, control) in segments[0].iter().zip(&spline.points) {
+            assert!(close(point, &control.into_bp3()));
+        }
+    }
+
+    #[test]
+    fn rational_quarter_circle_is_within_tolerance() {
+        let radius = 50.0;
+        let spline = nurbs(
+            &[
+                (radius, 0.0, 0.0, 1.0),
+                (radius, radius, 0.0, std::f32::consts::FRAC_1_SQRT_2),
+                (0.0, radius, 0.0, 1.0),
+            ],
+            3,
+        );
+        let tolerance = 0.01;
+
+        let segments = nurbs_to_bezier(&spline, tolerance).unwrap();
+
+        assert!(segments.len() > 1);
+        assert!(close(&segments[0][0], &spline.points[0].into_bp3()));
+        assert!(close(
+            &segments[segments.len() - 1][3],
+            &spline.points[2].into_bp3()
+        ));
+
+        for (a, b) in segments.iter().zip(segments.iter().skip(1)) {
+            assert!(close(&a[3], &b[0]));
+        }
+        for segment in &segments {
+            for i in 0..=10 {
+                let p = interpolate_bezier_point(segment, i as f32 / 10.0).unwrap();
+                assert!((p.x.hypot(p.y) - radius).abs() < tolerance * 1.5);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_splines_are_rejected() {
+        let mut spline = nurbs(&[(0.0, 0.0, 0.0, 1.0), (1.0, 0.0, 0.0, 1.0)], 4);
+        assert!(nurbs_to_bezier(&spline, 0.01).is_err());
+
+        spline = nurbs(
+            &[
+                (0.0, 0.0, 0.0, 1.0),
+                (1.0, 0.0, 0.0, 0.0),
+                (2.0, 0.0, 0.0, 1.0),
+            ],
+            3,
+        );
+        assert!(nurbs_to_bezier(&spline, 0.01).is_err());
+    }
+}
@@ -0,0 +1,629 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::PlannerConfig;
+use crate::delta_utils::*;
+use crate::error::*;
+use crate::import_types::*;
+use crate::motion_profile::*;
+use crate::nurbs::nurbs_ends;
+
+// Improvement passes stop once a pass saves less than this many mm, or after MAX_PASSES
+const MIN_IMPROVEMENT: f32 = 0.01;
+const MAX_PASSES: usize = 100;
+
+// Longest run of consecutive splines Or-opt will try to move elsewhere in the order
+const OR_OPT_SEGMENT: usize = 3;
+
+// Transit totals before and after the splines in a collection were reordered.
+// Following the previous frame's order can cost time, in which case the savings are negative.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OrderingReport {
+    pub transit_distance: f32, // mm, after reordering
+    pub transit_distance_saved: f32,
+    pub transit_duration: u32, // ms, after reordering
+    pub transit_duration_saved: i64,
+}
+
+// The order a collection was drawn in, so the next frame can be drawn in a consistent order
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CollectionOrder {
+    // Where each spline started and finished, and every particle trail, in drawing order
+    strokes: Vec<Ends>,
+    particles: Vec<BlenderParticle>,
+}
+
+impl CollectionOrder {
+    // Record the order of splines which have already been ordered and reversed
+    pub fn from_splines(splines: &[BlenderData]) -> CollectionOrder {
+        let strokes = splines
+            .iter()
+            .filter_map(spline_ends)
+            .map(|edge| edge.forward)
+            .collect();
+
+        let particles = splines
+            .iter()
+            .filter_map(|spline| match spline {
+                BlenderData::Particles(p) => Some(p.particles.iter().copied()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+
+        CollectionOrder { strokes, particles }
+    }
+
+    pub fn particles(&self) -> &[BlenderParticle] {
+        &self.particles
+    }
+}
+
+// Where the effector starts and finishes drawing a spline
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+struct Ends {
+    entry: BlenderPoint3,
+    exit: BlenderPoint3,
+}
+
+// Splines can be drawn in either direction, particle systems are always drawn in their sorted order
+#[derive(Debug, Copy, Clone)]
+struct Edge {
+    forward: Ends,
+    reverse: Option<Ends>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Stroke {
+    index: usize,
+    reversed: bool,
+}
+
+impl Stroke {
+    fn flipped(self) -> Stroke {
+        Stroke {
+            index: self.index,
+            reversed: !self.reversed,
+        }
+    }
+}
+
+struct Tour<'a> {
+    edges: &'a [Edge],
+    strokes: Vec<Stroke>,
+}
+
+impl<'a> Tour<'a> {
+    fn ends(&self, stroke: Stroke) -> Ends {
+        let edge = &self.edges[stroke.index];
+        match (stroke.reversed, edge.reverse) {
+            (true, Some(reverse)) => reverse,
+            _ => edge.forward,
+        }
+    }
+
+    fn reversible(&self, stroke: Stroke) -> bool {
+        self.edges[stroke.index].reverse.is_some()
+    }
+
+    // Transit distance between two consecutive strokes, nothing before the first or after the last
+    fn gap(&self, from: Option<Stroke>, to: Option<Stroke>) -> f32 {
+        match (from, to) {
+            (Some(from), Some(to)) => distance_3d(&self.ends(from).exit, &self.ends(to).entry),
+            _ => 0.0,
+        }
+    }
+
+    fn distance(&self) -> f32 {
+        self.strokes
+            .windows(2)
+            .map(|pair| self.gap(Some(pair[0]), Some(pair[1])))
+            .sum()
+    }
+
+    fn duration(&self, profile: &MotionProfile) -> VortexResult<u32> {
+        self.strokes
+            .windows(2)
+            .map(|pair| {
+                profile.transit_duration(&self.ends(pair[0]).exit, &self.ends(pair[1]).entry)
+            })
+            .sum()
+    }
+
+    // Start from the first spline, then repeatedly draw whichever remaining spline starts closest
+    fn nearest_neighbour(edges: &'a [Edge]) -> Tour<'a> {
+        let mut tour = Tour {
+            edges,
+            strokes: vec![],
+        };
+        let mut remaining: Vec<usize> = (0..edges.len()).collect();
+
+        while !remaining.is_empty() {
+            let previous = tour.strokes.last().copied();
+
+            let mut best: Option<(usize, Stroke, f32)> = None;
+            for (position, &index) in remaining.iter().enumerate() {
+                for &reversed in &[false, true] {
+                    let candidate = Stroke { index, reversed };
+                    if reversed && !tour.reversible(candidate) {
+                        continue;
+                    }
+
+                    let distance = tour.gap(previous, Some(candidate));
+                    if best.is_none_or(|b| distance < b.2) {
+                        best = Some((position, candidate, distance));
+                    }
+                }
+            }
+
+            if let Some((position, stroke, _)) = best {
+                remaining.remove(position);
+                tour.strokes.push(stroke);
+            }
+        }
+
+        tour
+    }
+
+    // Draw each spline in the position and direction of whichever previous spline it most resembles.
+    // Splines which match the same previous spline are ordered by how closely they match.
+    fn follow_previous(edges: &'a [Edge], previous: &[Ends]) -> Tour<'a> {
+        let mut tour = Tour {
+            edges,
+            strokes: vec![],
+        };
+
+        let mut ranked: Vec<(usize, f32, Stroke)> = vec![];
+        for index in 0..edges.len() {
+            let mut best: Option<(usize, f32, Stroke)> = None;
+
+            for &reversed in &[false, true] {
+                let candidate = Stroke { index, reversed };
+                if reversed && !tour.reversible(candidate) {
+                    continue;
+                }
+
+                let ends = tour.ends(candidate);
+                for (rank, earlier) in previous.iter().enumerate() {
+                    let difference = distance_3d(&ends.entry, &earlier.entry)
+                        + distance_3d(&ends.exit, &earlier.exit);
+                    if best.is_none_or(|b| difference < b.1) {
+                        best = Some((rank, difference, candidate));
+                    }
+                }
+            }
+
+            if let Some(best) = best {
+                ranked.push(best);
+            }
+        }
+
+        ranked.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        tour.strokes = ranked.into_iter().map(|(_, _, stroke)| stroke).collect();
+
+        tour
+    }
+
+    // Reverse the run of strokes between i and j (inclusive), which also flips the direction of each one
+    fn two_opt(&mut self) -> bool {
+        let n = self.strokes.len();
+        let mut improved = false;
+
+        for i in 0..n {
+            let before = if i > 0 {
+                Some(self.strokes[i - 1])
+            } else {
+                None
+            };
+
+            for j in i..n {
+                if !self.reversible(self.strokes[j]) {
+                    break; // every stroke in the run has to be reversible
+                }
+                let after = self.strokes.get(j + 1).copied();
+
+                let current = self.gap(before, Some(self.strokes[i]))
+                    + self.gap(Some(self.strokes[j]), after);
+                let reversed = self.gap(before, Some(self.strokes[j].flipped()))
+                    + self.gap(Some(self.strokes[i].flipped()), after);
+
+                if reversed < current - MIN_IMPROVEMENT {
+                    self.strokes[i..=j].reverse();
+                    for stroke in &mut self.strokes[i..=j] {
+                        *stroke = stroke.flipped();
+                    }
+                    improved = true;
+                }
+            }
+        }
+
+        improved
+    }
+
+    // Move a short run of strokes to a better position in the order, optionally reversing it
+    fn or_opt(&mut self) -> bool {
+        let n = self.strokes.len();
+        let mut improved = false;
+
+        for length in 1..=OR_OPT_SEGMENT.min(n.saturating_sub(1)) {
+            let mut i = 0;
+            while i + length <= self.strokes.len() {
+                if let Some(strokes) = self.best_move(i, length) {
+                    self.strokes = strokes;
+                    improved = true;
+                }
+                i += 1;
+            }
+        }
+
+        improved
+    }
+
+    // The order after moving strokes[i..i + length] somewhere better, if there is anywhere better
+    fn best_move(&self, i: usize, length: usize) -> Option<Vec<Stroke>> {
+        let segment = &self.strokes[i..i + length];
+        let mut rest = self.strokes[..i].to_vec();
+        rest.extend_from_slice(&self.strokes[i + length..]);
+
+        let first = segment[0];
+        let last = segment[length - 1];
+        let before = if i > 0 {
+            Some(self.strokes[i - 1])
+        } else {
+            None
+        };
+        let after = self.strokes.get(i + length).copied();
+
+        // Distance saved by taking the segment out and joining its neighbours
+        let removed =
+            self.gap(before, Some(first)) + self.gap(Some(last), after) - self.gap(before, after);
+
+        let mut reversed_segment: Vec<Stroke> = segment.iter().rev().map(|s| s.flipped()).collect();
+        let can_reverse = segment.iter().all(|&s| self.reversible(s));
+        if !can_reverse {
+            reversed_segment.clear();
+        }
+
+        let mut best: Option<(usize, bool, f32)> = None;
+        for slot in 0..=rest.len() {
+            let previous = if slot > 0 { Some(rest[slot - 1]) } else { None };
+            let next = rest.get(slot).copied();
+            let joined = self.gap(previous, next);
+
+            for (reversed, candidate) in [(false, segment), (true, &reversed_segment[..])].iter() {
+                if candidate.is_empty() || (slot == i && !reversed) {
+                    continue;
+                }
+
+                let inserted = self.gap(previous, Some(candidate[0]))
+                    + self.gap(Some(candidate[candidate.len() - 1]), next)
+                    - joined;
+
+                let saving = removed - inserted;
+                if saving > MIN_IMPROVEMENT && best.is_none_or(|b| saving > b.2) {
+                    best = Some((slot, *reversed, saving));
+                }
+            }
+        }
+
+        best.map(|(slot, reversed, _)| {
+            let moved = if reversed {
+                reversed_segment
+            } else {
+                segment.to_vec()
+            };
+
+            rest.splice(slot..slot, moved);
+            rest
+        })
+    }
+}
+
+// Points the sequencer transits to and from, in the direction the spline's points are stored
+fn spline_ends(spline: &BlenderData) -> Option<Edge> {
+    match spline {
+        BlenderData::PolySpline(s) => {
+            let first = s.points.first()?.into_bp3();
+            let last = s.points.last()?.into_bp3();
+
+            Some(Edge {
+                forward: Ends {
+                    entry: first,
+                    exit: last,
+                },
+                reverse: Some(Ends {
+                    entry: last,
+                    exit: first,
+                }),
+            })
+        }
+        BlenderData::NURBSSpline(s) => {
+            // Unless the knots are clamped, the curve doesn't reach the first and last control points
+            let (first, last) = nurbs_ends(s).ok()?;
+
+            Some(Edge {
+                forward: Ends {
+                    entry: first,
+                    exit: last,
+                },
+                reverse: Some(Ends {
+                    entry: last,
+                    exit: first,
+                }),
+            })
+        }
+        BlenderData::BezierSpline(s) => {
+            let first = s.points.first()?.co;
+            let last = s.points.last()?.co;
+
+            Some(Edge {
+                forward: Ends {
+                    entry: first,
+                    exit: last,
+                },
+                reverse: Some(Ends {
+                    entry: last,
+                    exit: first,
+                }),
+            })
+        }
+        BlenderData::Particles(p) => Some(Edge {
+            forward: Ends {
+                entry: p.particles.first()?.prev_location,
+                exit: p.particles.last()?.location,
+            },
+            reverse: None,
+        }),
+    }
+}
+
+fn reverse_spline(spline: &mut BlenderData) {
+    match spline {
+        BlenderData::PolySpline(s) => {
+            s.points.reverse();
+            s.color.reverse();
+        }
+        BlenderData::NURBSSpline(s) => s.reverse(),
+        BlenderData::BezierSpline(s) => s.reverse(),
+        BlenderData::Particles(_) => {}
+    }
+}
+
+// Reorder and reverse the splines in a collection to reduce the distance travelled between them.
+// The order is left alone if it can't be improved, or a spline has no points (which fails later when planning).
+// With temporal coherence the previous frame's order is followed instead, even when it's slower or
+// the order isn't being optimised.
+pub fn optimise_spline_order(
+    splines: &mut Vec<BlenderData>,
+    config: &PlannerConfig,
+    previous: Option<&CollectionOrder>,
+) -> VortexResult<OrderingReport> {
+    let profile = MotionProfile::new(config);
+
+    let edges: Vec<Edge> = match splines.iter().map(spline_ends).collect() {
+        Some(edges) => edges,
+        None => return Ok(OrderingReport::default()),
+    };
+
+    let original = Tour {
+        edges: &edges,
+        strokes: (0..edges.len())
+            .map(|index| Stroke {
+                index,
+                reversed: false,
+            })
+            .collect(),
+    };
+    let original_distance = original.distance();
+    let original_duration = original.duration(&profile)?;
+
+    let unchanged = OrderingReport {
+        transit_distance: original_distance,
+        transit_distance_saved: 0.0,
+        transit_duration: original_duration,
+        transit_duration_saved: 0,
+    };
+
+    // The previous frame's order is followed even when splines otherwise keep their exported order
+    let previous = previous
+        .filter(|p| config.temporal_coherence && !p.strokes.is_empty())
+        .map(|p| &p.strokes);
+    if splines.len() < 2 || (previous.is_none() && !config.optimise_order) {
+        return Ok(unchanged);
+    }
+
+    let tour = match previous {
+        Some(strokes) => Tour::follow_previous(&edges, strokes),
+        None => {
+            let mut tour = Tour::nearest_neighbour(&edges);
+            for _ in 0..MAX_PASSES {
+                let reversed = tour.two_opt();
+                let moved = tour.or_opt();
+                if !reversed && !moved {
+                    break;
+                }
+            }
+            tour
+        }
+    };
+
+    // Shorter transits can still take longer when they are split into many short moves
+    let distance = tour.distance();
+    let duration = tour.duration(&profile)?;
+    if previous.is_none() && (distance >= original_distance || duration > original_duration) {
+        return Ok(unchanged);
+    }
+
+    let mut unordered: Vec<Option<BlenderData>> = splines.drain(..).map(Some).collect();
+    for stroke in &tour.strokes {
+        if let Some(mut spline) = unordered[stroke.index].take() {
+            if stroke.reversed {
+                reverse_spline(&mut spline);
+            }
+            splines.push(spline);
+        }
+    }
+
+    Ok(OrderingReport {
+        transit_distance: distance,
+        transit_distance_saved: original_distance - distance,
+        transit_duration: duration,
+        transit_duration_saved: i64::from(original_duration) - i64::from(duration),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colorsys::Hsl;
+
+    // A straight poly spline, with a different colour at each end so reversals can be seen
+    fn line(from: (f32, f32), to: (f32, f32)) -> BlenderData {
+        let point = |(x, y): (f32, f32)| BlenderPoint4 {
+            x,
+            y,
+            z: 50.0,
+            w: 1.0,
+        };
+
+        BlenderData::PolySpline(BlenderPoly {
+            curve_length: 0.0,
+            points: vec![point(from), point(to)],
+            cyclic: false,
+            uv_path: String::new(),
+            color: vec![
+                Hsl::new(0.0, 100.0, 50.0, None),
+                Hsl::new(240.0, 100.0, 50.0, None),
+            ],
+        })
+    }
+
+    // Short strokes scattered around in a repeatable pattern
+    fn strokes(count: usize, seed: usize) -> Vec<BlenderData> {
+        (0..count)
+            .map(|i| {
+                let k = i + seed * count;
+                let (x, y) = ((k * 37 % 101) as f32, (k * 59 % 89) as f32);
+                let (dx, dy) = ((k * 13 % 11) as f32, (k * 7 % 5) as f32 - 2.0);
+                line((x, y), (x + dx + 1.0, y + dy))
+            })
+            .collect()
+    }
+
+    fn ends(spline: &BlenderData) -> ((f32, f32), (f32, f32)) {
+        match spline {
+            BlenderData::PolySpline(s) => {
+                let (first, last) = (s.points[0], s.points[s.points.len() - 1]);
+                ((first.x, first.y), (last.x, last.y))
+            }
+            _ => unreachable!("only poly splines are ordered in these tests"),
+        }
+    }
+
+    fn transit(splines: &[BlenderData]) -> f32 {
+        splines
+            .windows(2)
+            .map(|pair| {
+                let (exit, entry) = (ends(&pair[0]).1, ends(&pair[1]).0);
+                (entry.0 - exit.0).hypot(entry.1 - exit.1)
+            })
+            .sum()
+    }
+
+    #[test]
+    fn optimised_tours_are_never_longer_than_nearest_neighbour() {
+        let config = PlannerConfig::default();
+
+        for seed in 0..10 {
+            let mut splines = strokes(25, seed);
+            let edges: Vec<Edge> = splines.iter().filter_map(spline_ends).collect();
+            let nearest = Tour::nearest_neighbour(&edges).distance();
+
+            let report = optimise_spline_order(&mut splines, &config, None).unwrap();
+
+            assert!(report.transit_distance <= nearest + 1e-3);
+            assert!((report.transit_distance - transit(&splines)).abs() < 1e-2);
+            assert!(report.transit_distance_saved >= 0.0);
+        }
+    }
+
+    #[test]
+    fn reversed_splines_keep_their_points_and_colours_together() {
+        let original = strokes(25, 3);
+        let mut splines = original.clone();
+        optimise_spline_order(&mut splines, &PlannerConfig::default(), None).unwrap();
+
+        assert_eq!(splines.len(), original.len());
+        let mut reversals = 0;
+        for spline in &splines {
+            let (entry, exit) = ends(spline);
+            let source = original
+                .iter()
+                .map(ends)
+                .find(|&(a, b)| (a, b) == (entry, exit) || (b, a) == (entry, exit))
+                .expect("every spline comes from the original collection");
+
+            // The red end is wherever the original spline started
+            let colors = match spline {
+                BlenderData::PolySpline(s) => &s.color,
+                _ => unreachable!(),
+            };
+            let reversed = source != (entry, exit);
+            let first_hue = colors[0].get_hue();
+            assert_eq!(first_hue, if reversed { 240.0 } else { 0.0 });
+            reversals += usize::from(reversed);
+        }
+        assert!(reversals > 0);
+    }
+
+    #[test]
+    fn splines_keep_their_order_when_it_is_not_optimised() {
+        let original = strokes(10, 1);
+        let mut splines = original.clone();
+        let config = PlannerConfig {
+            optimise_order: false,
+            ..PlannerConfig::default()
+        };
+
+        let report = optimise_spline_order(&mut splines, &config, None).unwrap();
+
+        assert_eq!(
+            splines.iter().map(ends).collect::<Vec<_>>(),
+            original.iter().map(ends).collect::<Vec<_>>()
+        );
+        assert_eq!(report.transit_distance_saved, 0.0);
+    }
+
+    #[test]
+    fn following_the_previous_frame_starts_where_it_started() {
+        let config = PlannerConfig {
+            temporal_coherence: true,
+            ..PlannerConfig::default()
+        };
+
+        let mut first_frame = strokes(12, 2);
+        optimise_spline_order(&mut first_frame, &config, None).unwrap();
+        let previous = CollectionOrder::from_splines(&first_frame);
+
+        // The next frame has moved a little, and was exported in a different order
+        let mut next_frame: Vec<BlenderData> = first_frame
+            .iter()
+            .rev()
+            .map(|spline| {
+                let ((x0, y0), (x1, y1)) = ends(spline);
+                line((x0 + 0.5, y0 - 0.5), (x1 + 0.5, y1 - 0.5))
+            })
+            .collect();
+        optimise_spline_order(&mut next_frame, &config, Some(&previous)).unwrap();
+
+        let start = ends(&next_frame[0]).0;
+        let previous_start = previous.strokes[0].entry;
+        assert!((start.0 - previous_start.x).hypot(start.1 - previous_start.y) < 1.0);
+
+        // Every stroke follows in the same order and direction as before
+        for (spline, earlier) in next_frame.iter().zip(&first_frame) {
+            let ((x0, y0), _) = ends(spline);
+            let ((x1, y1), _) = ends(earlier);
+            assert!((x0 - x1).hypot(y0 - y1) < 1.0);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::delta_utils::*;
+use crate::import_types::*;
+
+// Improvement passes stop once a pass saves less than this many mm, or after MAX_PASSES
+const MIN_IMPROVEMENT: f32 = 0.01;
+const MAX_PASSES: usize = 100;
+
+// Longest run of consecutive trails Or-opt will try to move elsewhere in the order
+const OR_OPT_SEGMENT: usize = 3;
+
+// Number of random perturbations tried by the Lin-Kernighan style search
+const KICKS: usize = 30;
+
+// A particle continues a trail from the previous frame when it starts within this many mm of where that trail ended
+const TEMPORAL_MATCH_DISTANCE: f32 = 1.0;
+
+// How the trails in a particle system are ordered to reduce transit between them.
+// Every strategy starts from a random particle, unless a seed is set the order changes on each run.
+// Non-deterministic pathing through the particle cloud 'fuzzes' any artifacts influenced by transit moves.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParticleOrder {
+    Greedy,       // chain of nearest next trails
+    TwoOpt,       // greedy, then improved with 2-opt and Or-opt moves
+    LinKernighan, // 2-opt and Or-opt, with random double-bridge kicks to escape local minima
+    Temporal,     // follow the previous frame's order, inserting new particles where they fit best
+}
+
+// Trails are drawn from prev_location to location, so the transit runs from one location to the next prev_location
+fn transit(a: &BlenderParticle, b: &BlenderParticle) -> f32 {
+    distance_3d(&a.location, &b.prev_location)
+}
+
+// Total transit distance of an order
+fn order_distance(particles: &[BlenderParticle], order: &[usize]) -> f32 {
+    order
+        .windows(2)
+        .map(|pair| transit(&particles[pair[0]], &particles[pair[1]]))
+        .sum()
+}
+
+// Transit distance between two positions in the order, nothing before the first or after the last
+fn gap(particles: &[BlenderParticle], from: Option<&usize>, to: Option<&usize>) -> f32 {
+    match (from, to) {
+        (Some(&from), Some(&to)) => transit(&particles[from], &particles[to]),
+        _ => 0.0,
+    }
+}
+
+fn greedy(particles: &[BlenderParticle], start: usize) -> Vec<usize> {
+    let mut order = vec![start];
+    let mut remaining: Vec<usize> = (0..particles.len()).filter(|&i| i != start).collect();
+
+    while let Some(&last) = order.last() {
+        // Take the closest trail from this search pass, and move it into the order
+        let closest = remaining
+            .iter()
+            .enumerate()
+            .map(|(position, &i)| (position, transit(&particles[last], &particles[i])))
+            .fold(None, |best: Option<(usize, f32)>, candidate| match best {
+                Some(b) if b.1 <= candidate.1 => Some(b),
+                _ => Some(candidate),
+            });
+
+        match closest {
+            Some((position, _)) => order.push(remaining.remove(position)),
+            None => break,
+        }
+    }
+
+    order
+}
+
+// Reverse the run of trails between i and j (inclusive). Trails keep their own direction, so the
+// transits inside the run change too. These are summed in both directions as j grows, so each gain
+// comes from the four edges at the ends of the run without walking it again.
+fn two_opt(particles: &[BlenderParticle], order: &mut [usize]) -> bool {
+    let n = order.len();
+    let mut improved = false;
+
+    for i in 0..n {
+        let before = if i > 0 { Some(order[i - 1]) } else { None };
+        let mut inner_forward = 0.0;
+        let mut inner_backward = 0.0;
+
+        for j in i + 1..n {
+            inner_forward += transit(&particles[order[j - 1]], &particles[order[j]]);
+            inner_backward += transit(&particles[order[j]], &particles[order[j - 1]]);
+            let after = order.get(j + 1);
+
+            let current = gap(particles, before.as_ref(), Some(&order[i]))
+                + inner_forward
+                + gap(particles, Some(&order[j]), after);
+            let reversed = gap(particles, before.as_ref(), Some(&order[j]))
+                + inner_backward
+                + gap(particles, Some(&order[i]), after);
+
+            if reversed < current - MIN_IMPROVEMENT {
+                order[i..=j].reverse();
+                std::mem::swap(&mut inner_forward, &mut inner_backward);
+                improved = true;
+            }
+        }
+    }
+
+    improved
+}
+
+// Move a short run of trails to a better position in the order
+fn or_opt(particles: &[BlenderParticle], order: &mut [usize]) -> bool {
+    let n = order.len();
+    let mut improved = false;
+
+    for length in 1..=OR_OPT_SEGMENT.min(n.saturating_sub(1)) {
+        let mut i = 0;
+        while i + length <= n {
+            let before = if i > 0 { order.get(i - 1) } else { None };
+            let after = order.get(i + length);
+            let first = order[i];
+            let last = order[i + length - 1];
+
+            // Distance saved by taking the run out and joining its neighbours
+            let removed = gap(particles, before, Some(&first)) + gap(particles, Some(&last), after)
+                - gap(particles, before, after);
+
+            // The run can go in front of any trail outside it, or at the end, but not back where it was
+            let best = (0..i)
+                .chain(i + length + 1..=n)
+                .map(|slot| {
+                    let previous = if slot > 0 { order.get(slot - 1) } else { None };
+                    let next = order.get(slot);
+                    let inserted = gap(particles, previous, Some(&first))
+                        + gap(particles, Some(&last), next)
+                        - gap(particles, previous, next);
+
+                    (slot, removed - inserted)
+                })
+                .fold(None, |best: Option<(usize, f32)>, candidate| match best {
+                    Some(b) if b.1 >= candidate.1 => Some(b),
+                    _ => Some(candidate),
+                });
+
+            if let Some((slot, saving)) = best {
+                if saving > MIN_IMPROVEMENT {
+                    // Rotating the trails between the run and its new slot moves it in place
+                    if slot < i {
+                        order[slot..i + length].rotate_right(length);
+                    } else {
+                        order[i..slot].rotate_left(length);
+                    }
+                    improved = true;
+                }
+            }
+
+            i += 1;
+        }
+    }
+
+    improved
+}
+
+fn local_search(particles: &[BlenderParticle], order: &mut [usize]) {
+    for _ in 0..MAX_PASSES {
+        let reversed = two_opt(particles, order);
+        let moved = or_opt(particles, order);
+        if !reversed && !moved {
+            break;
+        }
+    }
+}
+
+// Repeatedly perturb the best order with a double-bridge move, which 2-opt can't undo in one step,
+// then search locally from there. The best order found is kept.
+fn lin_kernighan(particles: &[BlenderParticle], order: &mut Vec<usize>, rng: &mut StdRng) {
+    local_search(particles, order);
+    if order.len() < 8 {
+        return;
+    }
+
+    let mut best_distance = order_distance(particles, order);
+    for _ in 0..KICKS {
+        let n = order.len();
+        let a = rng.gen_range(1, n - 2);
+        let b = rng.gen_range(a + 1, n - 1);
+        let c = rng.gen_range(b + 1, n);
+
+        let mut candidate = order[..a].to_vec();
+        candidate.extend_from_slice(&order[b..c]);
+        candidate.extend_from_slice(&order[a..b]);
+        candidate.extend_from_slice(&order[c..]);
+        local_search(particles, &mut candidate);
+
+        let distance = order_distance(particles, &candidate);
+        if distance < best_distance - MIN_IMPROVEMENT {
+            best_distance = distance;
+            *order = candidate;
+        }
+    }
+}
+
+// Order particles by the position of the trail they continue in the previous frame.
+// Returns None when no particle continues a previous trail.
+fn temporal(particles: &[BlenderParticle], previous: &[BlenderParticle]) -> Option<Vec<usize>> {
+    let mut continued: Vec<(usize, usize)> = vec![];
+    let mut born: Vec<usize> = vec![];
+
+    for (i, particle) in particles.iter().enumerate() {
+        let matched = previous
+            .iter()
+            .enumerate()
+            .map(|(rank, p)| (rank, distance_3d(&particle.prev_location, &p.location)))
+            .filter(|(_, distance)| *distance <= TEMPORAL_MATCH_DISTANCE)
+            .fold(None, |best: Option<(usize, f32)>, candidate| match best {
+                Some(b) if b.1 <= candidate.1 => Some(b),
+                _ => Some(candidate),
+            });
+
+        match matched {
+            Some((rank, _)) => continued.push((rank, i)),
+            None => born.push(i),
+        }
+    }
+
+    if continued.is_empty() {
+        return None;
+    }
+
+    continued.sort_by_key(|&(rank, _)| rank);
+    let mut order: Vec<usize> = continued.into_iter().map(|(_, i)| i).collect();
+
+    // New particles go wherever they add the least transit
+    for i in born {
+        let slot = (0..=order.len())
+            .map(|slot| {
+                let previous = if slot > 0 { order.get(slot - 1) } else { None };
+                let next = order.get(slot);
+                let added = gap(particles, previous, Some(&i)) + gap(particles, Some(&i), next)
+                    - gap(particles, previous, next);
+                (slot, added)
+            })
+            .fold(None, |best: Option<(usize, f32)>, candidate| match best {
+                Some(b) if b.1 <= candidate.1 => Some(b),
+                _ => Some(candidate),
+            })
+            .map(|(slot, _)| slot)
+            .unwrap_or(order.len());
+
+        order.insert(slot, i);
+    }
+
+    Some(order)
+}
+
+// Order the trails in a particle system. When the previous frame's ordered particles are given,
+// trails which continue from them keep the same order, otherwise the chosen strategy is used.
+// The temporal strategy uses two_opt when there's no previous frame to follow.
+pub fn order_particles(
+    particles: &[BlenderParticle],
+    strategy: ParticleOrder,
+    seed: Option<u64>,
+    previous: Option<&[BlenderParticle]>,
+) -> Vec<BlenderParticle> {
+    if particles.is_empty() {
+        return vec![];
+    }
+
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let order = match previous.and_then(|previous| temporal(particles, previous)) {
+        Some(order) => order,
+        None => {
+            let mut order = greedy(particles, rng.gen_range(0, particles.len()));
+
+            match strategy {
+                ParticleOrder::Greedy => {}
+                ParticleOrder::TwoOpt | ParticleOrder::Temporal => {
+                    local_search(particles, &mut order)
+                }
+                ParticleOrder::LinKernighan => lin_kernighan(particles, &mut order, &mut rng),
+            }
+
+            order
+        }
+    };
+
+    order.into_iter().map(|i| particles[i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32) -> BlenderPoint3 {
+        BlenderPoint3 { x, y, z: 0.0 }
+    }
+
+    // Short trails scattered around in a repeatable pattern
+    fn particles(count: usize) -> Vec<BlenderParticle> {
+        let rotation = BlenderPoint4 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            w: 1.0,
+        };
+
+        (0..count)
+            .map(|i| {
+                let (x, y) = ((i * 37 % 101) as f32, (i * 59 % 89) as f32);
+                BlenderParticle {
+                    prev_location: point(x, y),
+                    location: point(x + 1.0, y + 0.5),
+                    velocity: BlenderPoint3::default(),
+                    prev_velocity: BlenderPoint3::default(),
+                    rotation,
+                    prev_rotation: rotation,
+                }
+            })
+            .collect()
+    }
+
+    fn total_transit(order: &[BlenderParticle]) -> f32 {
+        order
+            .windows(2)
+            .map(|pair| transit(&pair[0], &pair[1]))
+            .sum()
+    }
+
+    fn is_permutation(order: &[BlenderParticle], particles: &[BlenderParticle]) -> bool {
+        order.len() == particles.len()
+            && particles
+                .iter()
+                .all(|p| order.iter().filter(|&o| o == p).count() == 1)
+    }
+
+    const STRATEGIES: [ParticleOrder; 4] = [
+        ParticleOrder::Greedy,
+        ParticleOrder::TwoOpt,
+        ParticleOrder::LinKernighan,
+        ParticleOrder::Temporal,
+    ];
+
+    #[test]
+    fn seeded_orders_are_repeatable() {
+        let particles = particles(60);
+
+        for &strategy in &STRATEGIES {
+            let first = order_particles(&particles, strategy, Some(42), None);
+            let second = order_particles(&particles, strategy, Some(42), None);
+
+            assert_eq!(first, second, "{:?}", strategy);
+            assert!(is_permutation(&first, &particles), "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn local_search_improves_on_greedy() {
+        let particles = particles(60);
+
+        for seed in 0..5 {
+            let greedy = order_particles(&particles, ParticleOrder::Greedy, Some(seed), None);
+            let two_opt = order_particles(&particles, ParticleOrder::TwoOpt, Some(seed), None);
+            let lin_kernighan =
+                order_particles(&particles, ParticleOrder::LinKernighan, Some(seed), None);
+
+            assert!(total_transit(&two_opt) <= total_transit(&greedy) + MIN_IMPROVEMENT);
+            assert!(total_transit(&lin_kernighan) <= total_transit(&two_opt) + MIN_IMPROVEMENT);
+        }
+    }
+
+    #[test]
+    fn continued_trails_keep_the_previous_order() {
+        let previous = particles(20);
+
+        // Every trail carries on from where it finished, listed in a different order
+        let mut next: Vec<BlenderParticle> = previous
+            .iter()
+            .map(|p| BlenderParticle {
+                prev_location: p.location,
+                location: point(p.location.x + 1.0, p.location.y),
+                ..*p
+            })
+            .collect();
+        next.reverse();
+
+        let ordered = order_particles(&next, ParticleOrder::Greedy, Some(1), Some(&previous));
+
+        for (continued, earlier) in ordered.iter().zip(&previous) {
+            assert_eq!(continued.prev_location, earlier.location);
+        }
+    }
+
+    #[test]
+    fn empty_systems_have_no_order() {
+        assert!(order_particles(&[], ParticleOrder::TwoOpt, Some(1), None).is_empty());
+    }
+}
@@ -0,0 +1,891 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::build_cache::*;
+use crate::config::*;
+use crate::error::*;
+use crate::export_data::*;
+use crate::export_types::*;
+use crate::import_data::*;
+use crate::import_svg::*;
+use crate::import_types::*;
+use crate::kinematics::*;
+use crate::ordering::*;
+use crate::particle_order::*;
+use crate::preview::*;
+use crate::sequencer::*;
+
+// Checks that a DirEntry is a folder with a numeric name, ignoring hidden and __MACOSX folders
+fn is_frame_folder(entry: &DirEntry) -> bool {
+    entry.file_type().is_dir() && frame_number(entry.path()).is_some()
+}
+
+fn is_json_file(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|s| s.ends_with(".json")) //&& !s.ends_with("toolpath.json")
+        .unwrap_or(false)
+}
+
+fn is_svg_file(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|s| s.ends_with(".svg"))
+        .unwrap_or(false)
+}
+
+/// The frame number of a frame folder, which must have a numeric name.
+pub fn frame_number(folder: &Path) -> Option<i32> {
+    folder
+        .file_name()?
+        .to_str()
+        .filter(|s| !s.starts_with('.') && !s.starts_with("__"))?
+        .parse::<i32>()
+        .ok()
+}
+
+/// Load every Blender JSON export in a collection folder, along with the UV maps they reference.
+///
+/// SVG drawings in the folder are loaded too, each stroke becomes a poly spline on the `svg.z` plane.
+pub fn load_collection(folder: &Path, config: &VortexConfig) -> VortexResult<Vec<BlenderData>> {
+    // Sorted so splines are always loaded in the same order, whatever order the filesystem lists them in
+    let loaded: Vec<Vec<BlenderData>> = WalkDir::new(folder)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| is_json_file(e) || is_svg_file(e))
+        .filter_map(|v| v.ok())
+        .map(|x| {
+            if is_svg_file(&x) {
+                load_svg(x.path(), &config.svg, &config.workspace)
+            } else {
+                load_blender_data(x.path(), &config.import, &config.workspace).map(|d| vec![d])
+            }
+        })
+        .collect::<VortexResult<_>>()?;
+
+    Ok(loaded.into_iter().flatten().collect())
+}
+
+/// Apply the import transforms to Blender data which is already in memory.
+///
+/// Splines which don't have colours assigned load their UV map relative to `uv_folder`, failing if it's missing,
+/// or use placeholder lighting without a `uv_folder`.
+pub fn prepare_collection(
+    splines: Vec<BlenderData>,
+    uv_folder: Option<&Path>,
+    config: &VortexConfig,
+) -> VortexResult<Vec<BlenderData>> {
+    splines
+        .into_iter()
+        .map(|s| prepare_blender_data(s, uv_folder, &config.import, &config.workspace))
+        .collect()
+}
+
+/// Order the trails in each particle system, then reorder and reverse the collection's splines
+/// to reduce the transit moves between them.
+///
+/// `previous` is the order the collection was drawn in the frame before, which is followed when
+/// `planner.temporal_coherence` is enabled or particles use the temporal order.
+/// Splines keep their order when `planner.optimise_order` is disabled, but the transit totals are still reported.
+pub fn order_collection(
+    splines: &mut Vec<BlenderData>,
+    config: &VortexConfig,
+    previous: Option<&CollectionOrder>,
+) -> VortexResult<OrderingReport> {
+    let planner = &config.planner;
+    let previous_particles = previous
+        .filter(|_| planner.temporal_coherence || planner.particle_order == ParticleOrder::Temporal)
+        .map(|p| p.particles());
+
+    for spline in splines.iter_mut() {
+        if let BlenderData::Particles(p) = spline {
+            p.particles = order_particles(
+                &p.particles,
+                planner.particle_order,
+                planner.particle_seed,
+                previous_particles,
+            );
+        }
+    }
+
+    optimise_spline_order(splines, planner, previous)
+}
+
+/// Plan the movement and lighting events for a collection's splines.
+///
+/// Fails if any planned move passes through a point the machine can't reach.
+pub fn plan_collection(
+    splines: &[BlenderData],
+    config: &VortexConfig,
+) -> VortexResult<ActionGroups> {
+    let planned_events = generate_delta_toolpath(splines, &config.planner)?;
+
+    let illegal_moves =
+        find_unreachable_motions(&planned_events, &config.machine, &config.workspace)?;
+    if !illegal_moves.is_empty() {
+        return Err(VortexError::OutOfWorkspace {
+            motion_ids: illegal_moves,
+        });
+    }
+
+    Ok(planned_events)
+}
+
+/// Where the generated files for a collection are written.
+#[derive(Debug, Clone)]
+pub struct CollectionFiles {
+    pub toolpath: PathBuf,
+    pub vertices: PathBuf,
+    pub uv: PathBuf,
+    pub transits: PathBuf,
+    pub build: PathBuf,  // what the files were generated from, see build_cache
+    pub render: PathBuf, // long exposure photo, only written by the render command
+}
+
+impl CollectionFiles {
+    pub fn new(destination: &Path, collection_name: &str) -> CollectionFiles {
+        CollectionFiles {
+            toolpath: format_filename(destination, collection_name, "toolpath.json"),
+            vertices: format_filename(destination, collection_name, "vertices.json"),
+            uv: format_filename(destination, collection_name, "uv.png"),
+            transits: format_filename(destination, collection_name, "transits.json"),
+            build: format_filename(destination, collection_name, "build.json"),
+            render: format_filename(destination, collection_name, "render.png"),
+        }
+    }
+}
+
+/// Write a planned toolpath in the delta-ui event format.
+pub fn write_toolpath(path: &Path, events: ActionGroups) -> VortexResult<()> {
+    // Add header information
+    let output_data: DeltaEvents = DeltaEvents {
+        metadata: generate_header(String::from("VortexFile")),
+        actions: vec![events],
+    };
+
+    export_toolpath(path, output_data)
+}
+
+/// Write the delta-ui preview vertices and UV map.
+pub fn write_viewer_data(files: &CollectionFiles, preview: ViewerData) -> VortexResult<()> {
+    export_vertices(files.vertices.as_path(), preview.vertices)?;
+    export_vertices(files.transits.as_path(), preview.transits)?;
+    export_uv(files.uv.as_path(), preview.colors)
+}
+
+/// Which of the generated files should be written to disk.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputMode {
+    Plan,    // toolpaths and preview data
+    Preview, // only preview data
+    DryRun,  // nothing, used to check or summarise an animation
+}
+
+impl OutputMode {
+    fn writes_toolpath(self) -> bool {
+        self == OutputMode::Plan
+    }
+
+    fn writes_preview(self) -> bool {
+        self == OutputMode::Plan || self == OutputMode::Preview
+    }
+
+    // Whether the files from an earlier build cover everything this mode writes
+    fn satisfied_by(self, record: &BuildRecord, files: &CollectionFiles) -> bool {
+        let toolpath = !self.writes_toolpath() || (record.toolpath && files.toolpath.is_file());
+        let preview = !self.writes_preview()
+            || (record.preview
+                && files.vertices.is_file()
+                && files.uv.is_file()
+                && files.transits.is_file());
+
+        toolpath && preview
+    }
+}
+
+/// Receives progress updates while an animation is processed.
+///
+/// Collections are processed concurrently, so updates can arrive from any worker thread.
+pub trait ProgressReporter: Sync {
+    fn started(&self, _collections: usize) {}
+    fn collection_finished(
+        &self,
+        _frame: i32,
+        _collection: &str,
+        _outcome: &VortexResult<Option<FileMetadata>>,
+    ) {
+    }
+}
+
+/// Doesn't report anything, the default for a new pipeline.
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {}
+
+// A frame folder and its collection folders, both sorted so the summary is always in the same order
+struct FrameFolder {
+    frame_number: i32,
+    collections: Vec<PathBuf>,
+}
+
+type CollectionOutcome = VortexResult<Option<FileMetadata>>;
+
+/// Plans a folder structure of frames and collections exported from Blender.
+pub struct Pipeline {
+    pub output_root: PathBuf,
+    pub project: ProjectConfig,
+    pub mode: OutputMode,
+    pub jobs: usize, // worker threads, 0 uses one per CPU
+    pub force: bool, // rebuild every collection, even when its inputs haven't changed
+    pub progress: Box<dyn ProgressReporter>,
+    config: VortexConfig, // project settings before per-frame/collection overrides
+}
+
+impl Pipeline {
+    pub fn new(
+        project: ProjectConfig,
+        output_root: &Path,
+        mode: OutputMode,
+    ) -> VortexResult<Pipeline> {
+        let config = project.base()?;
+        config.validate()?;
+
+        Ok(Pipeline {
+            output_root: output_root.to_path_buf(),
+            project,
+            mode,
+            jobs: 0,
+            force: false,
+            progress: Box::new(NoProgress),
+            config,
+        })
+    }
+
+    /// Settings before any per-frame or per-collection overrides are applied.
+    pub fn base_config(&self) -> &VortexConfig {
+        &self.config
+    }
+
+    /// Settings for one collection in a frame, after overrides are applied.
+    pub fn config_for(&self, frame: i32, collection: &str) -> VortexResult<VortexConfig> {
+        let config = self.project.resolve(frame, collection)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Process every numbered frame folder in the input folder, on `jobs` worker threads.
+    pub fn process_animation(&self, input_root: &Path) -> AnimationMetadata {
+        let frames = find_frames(input_root);
+        self.progress
+            .started(frames.iter().map(|f| f.collections.len()).sum());
+
+        let frame_meta = match ThreadPoolBuilder::new().num_threads(self.jobs).build() {
+            Ok(pool) => pool.install(|| self.process_frames(&frames)),
+            Err(_) => self.process_frames(&frames),
+        };
+
+        let unique_collection_names = unique_collection_names(&frame_meta);
+
+        AnimationMetadata {
+            config_path: self.project.path.as_ref().map(|p| p.display().to_string()),
+            settings: self.config.clone(),
+            collections: unique_collection_names,
+            frames: frame_meta,
+        }
+    }
+
+    // Collections with the same name form a track through the animation. Tracks which follow the previous
+    // frame's order are planned one frame after another, every other collection is planned independently.
+    fn process_frames(&self, frames: &[FrameFolder]) -> Vec<FrameMetadata> {
+        let mut tracks: Vec<(String, Vec<(usize, usize)>)> = vec![];
+        for (frame_index, frame) in frames.iter().enumerate() {
+            for (collection_index, folder) in frame.collections.iter().enumerate() {
+                let name = collection_name(folder);
+                let position = match tracks.iter().position(|(n, _)| *n == name) {
+                    Some(position) => position,
+                    None => {
+                        tracks.push((name, vec![]));
+                        tracks.len() - 1
+                    }
+                };
+                tracks[position].1.push((frame_index, collection_index));
+            }
+        }
+
+        let mut outcomes: HashMap<(usize, usize), CollectionOutcome> = tracks
+            .par_iter()
+            .flat_map_iter(|(name, entries)| {
+                let sequential = entries.iter().any(|&(frame_index, _)| {
+                    self.config_for(frames[frame_index].frame_number, name)
+                        .map(|c| c.planner.follows_previous_frame())
+                        .unwrap_or(false)
+                });
+
+                if sequential {
+                    self.process_track(frames, entries)
+                } else {
+                    entries
+                        .par_iter()
+                        .map(|&entry| (entry, self.run_collection(frames, entry, None)))
+                        .collect()
+                }
+            })
+            .collect();
+
+        frames
+            .iter()
+            .enumerate()
+            .map(|(frame_index, frame)| {
+                let collections = (0..frame.collections.len()).map(|collection_index| {
+                    let outcome = outcomes
+                        .remove(&(frame_index, collection_index))
+                        .unwrap_or(Ok(None));
+                    (
+                        collection_name(&frame.collections[collection_index]),
+                        outcome,
+                    )
+                });
+                frame_metadata(frame.frame_number, collections)
+            })
+            .collect()
+    }
+
+    // Plan a collection in every frame it appears in, in order, following the frame before when it was planned
+    fn process_track(
+        &self,
+        frames: &[FrameFolder],
+        entries: &[(usize, usize)],
+    ) -> Vec<((usize, usize), CollectionOutcome)> {
+        let mut previous: Option<(usize, CollectionOrder)> = None;
+        let mut outcomes = vec![];
+
+        for &entry in entries {
+            let previous_order = previous
+                .as_ref()
+                .filter(|(frame_index, _)| frame_index + 1 == entry.0)
+                .map(|(_, order)| order);
+
+            let outcome = self.run_collection(frames, entry, previous_order);
+            previous = match &outcome {
+                Ok(Some(metadata)) => Some((entry.0, metadata.order.clone())),
+                _ => None,
+            };
+            outcomes.push((entry, outcome));
+        }
+
+        outcomes
+    }
+
+    fn run_collection(
+        &self,
+        frames: &[FrameFolder],
+        (frame_index, collection_index): (usize, usize),
+        previous: Option<&CollectionOrder>,
+    ) -> CollectionOutcome {
+        let frame = &frames[frame_index];
+        let folder = &frame.collections[collection_index];
+
+        let outcome = self.process_collection(folder, frame.frame_number, previous);
+        self.progress
+            .collection_finished(frame.frame_number, &collection_name(folder), &outcome);
+
+        outcome
+    }
+
+    /// Process the collection folders inside a frame folder, in parallel.
+    ///
+    /// `previous` is the metadata of the frame before, collections can follow the order they were drawn in there.
+    /// A collection which fails is reported in the metadata, the rest of the frame is still processed.
+    pub fn process_frame_folder(
+        &self,
+        folder: &Path,
+        frame_number: i32,
+        previous: Option<&FrameMetadata>,
+    ) -> FrameMetadata {
+        let collections: Vec<(String, CollectionOutcome)> = collection_folders(folder)
+            .par_iter()
+            .map(|collection| {
+                let name = collection_name(collection);
+                let previous_order = previous
+                    .and_then(|frame| frame.collections.iter().find(|c| c.name == name))
+                    .map(|c| &c.order);
+
+                let outcome = self.process_collection(collection, frame_number, previous_order);
+                self.progress
+                    .collection_finished(frame_number, &name, &outcome);
+
+                (name, outcome)
+            })
+            .collect();
+
+        frame_metadata(frame_number, collections)
+    }
+
+    /// A collection is the deepest level folder. Contains json and (optional) uv files from Blender.
+    ///
+    /// `previous` is the order this collection was drawn in the frame before, if it was.
+    /// Returns `None` when the collection doesn't contain anything to draw.
+    ///
+    /// When files are written, a hash of the inputs and settings is recorded next to them.
+    /// Collections which haven't changed since are reused rather than planned again, unless `force` is set.
+    pub fn process_collection(
+        &self,
+        folder: &Path,
+        frame_number: i32,
+        previous: Option<&CollectionOrder>,
+    ) -> VortexResult<Option<FileMetadata>> {
+        let collection_name = collection_name(folder);
+        let config = self.config_for(frame_number, &collection_name)?;
+        let previous = previous.filter(|_| config.planner.follows_previous_frame());
+
+        // Generated files are written into a matching frame folder in the output root
+        let destination_folder = match folder.parent().and_then(|p| p.file_name()) {
+            Some(frame_folder) => self.output_root.join(frame_folder),
+            None => self.output_root.clone(),
+        };
+        let files = CollectionFiles::new(&destination_folder, &collection_name);
+
+        let writes_files = self.mode.writes_toolpath() || self.mode.writes_preview();
+        let hash = if writes_files {
+            Some(collection_hash(folder, &config, previous)?)
+        } else {
+            None
+        };
+
+        if let Some(hash) = &hash {
+            let reusable = BuildRecord::load(&files.build)
+                .filter(|record| !self.force && record.hash == *hash)
+                .filter(|record| self.mode.satisfied_by(record, &files));
+            if let Some(record) = reusable {
+                return Ok(Some(record.into_metadata()));
+            }
+        }
+
+        let mut parsed_splines = load_collection(folder, &config)?;
+        if parsed_splines.is_empty() {
+            return Ok(None);
+        }
+
+        let ordering = order_collection(&mut parsed_splines, &config, previous)?;
+        let order = CollectionOrder::from_splines(&parsed_splines);
+
+        // Take our spline+illumination data, and generate a tool-path
+        let planned_events = plan_collection(&parsed_splines, &config)?;
+
+        // Particle systems can have every particle outside the workspace, so nothing is drawn
+        let (first_move, last_move) =
+            match (planned_events.delta.first(), planned_events.delta.last()) {
+                (Some(first), Some(last)) => (first.payload.id, last.payload.id),
+                _ => return Ok(None),
+            };
+
+        // Generate additional exports for use in the UI as previz data, replaying the planned moves and fades
+        let viewer_preview = generate_toolpath_preview(&planned_events)?;
+
+        let duration: u32 = planned_events
+            .delta
+            .iter()
+            .map(|x| x.payload.duration)
+            .sum();
+
+        let num_lights = planned_events.light.len() as u32;
+        let num_moves = planned_events.delta.len() as u32;
+        let num_moves_unsimplified = num_moves + planned_events.get_merged_moves();
+        let lighting = planned_events.get_lighting_report();
+
+        // Write to disk
+        if writes_files {
+            fs::create_dir_all(&destination_folder)
+                .map_err(|e| VortexError::io(&destination_folder, e))?;
+        }
+
+        if self.mode.writes_toolpath() {
+            write_toolpath(&files.toolpath, planned_events)?;
+        }
+
+        if self.mode.writes_preview() {
+            write_viewer_data(&files, viewer_preview)?;
+        }
+
+        let metadata = FileMetadata {
+            name: collection_name,
+            toolpath_path: pathbuf_to_string(files.toolpath),
+            duration,
+            first_move,
+            last_move,
+            num_lights,
+            num_moves,
+            num_moves_unsimplified,
+            ordering,
+            lighting,
+            viewer_vertices_path: pathbuf_to_string(files.vertices),
+            viewer_uv_path: pathbuf_to_string(files.uv),
+            viewer_transit_path: pathbuf_to_string(files.transits),
+            settings: if config != self.config {
+                Some(config)
+            } else {
+                None
+            },
+            reused: false,
+            order,
+        };
+
+        if let Some(hash) = hash {
+            let record = BuildRecord {
+                hash,
+                toolpath: self.mode.writes_toolpath(),
+                preview: self.mode.writes_preview(),
+                metadata: metadata.clone(),
+                order: metadata.order.clone(),
+            };
+            record.write(&files.build)?;
+        }
+
+        Ok(Some(metadata))
+    }
+
+    /// Plan a collection folder again after its files changed, and replace its entry in the summary.
+    ///
+    /// A collection which was deleted, or no longer has anything to draw, is removed from the summary.
+    /// When the collection follows the previous frame's order, it's replanned in the following frames
+    /// until the build cache shows their input hasn't changed.
+    /// Returns the number of collections which were planned.
+    pub fn replan_collection(&self, summary: &mut AnimationMetadata, folder: &Path) -> usize {
+        let name = collection_name(folder);
+        let frame_folder = match folder.parent() {
+            Some(frame_folder) => frame_folder,
+            None => return 0,
+        };
+        let frame_number = match frame_number(frame_folder) {
+            Some(frame_number) => frame_number,
+            None => return 0,
+        };
+        let frames = frame_folder.parent().map(find_frames).unwrap_or_default();
+
+        let mut planned = 0;
+        let mut next = Some((frame_number, folder.to_path_buf()));
+
+        while let Some((frame_number, folder)) = next.take() {
+            // Frames are adjacent by their sorted position, as when the animation was planned,
+            // so stepped frame numbers still follow each other. A deleted frame leaves a gap.
+            let (before, after) =
+                match frames.binary_search_by_key(&frame_number, |f| f.frame_number) {
+                    Ok(position) => (position.checked_sub(1), position + 1),
+                    Err(position) => (position.checked_sub(1), position),
+                };
+
+            let previous = before
+                .map(|position| frames[position].frame_number)
+                .and_then(|previous_number| {
+                    summary
+                        .frames
+                        .iter()
+                        .find(|f| f.frame_num == previous_number)
+                })
+                .and_then(|f| f.collections.iter().find(|c| c.name == name))
+                .map(|c| c.order.clone());
+
+            let outcome = if folder.is_dir() {
+                planned += 1;
+                let outcome = self.process_collection(&folder, frame_number, previous.as_ref());
+                self.progress
+                    .collection_finished(frame_number, &name, &outcome);
+                outcome
+            } else {
+                Ok(None)
+            };
+
+            // A reused collection has the same order as before, so later frames are unaffected
+            let changed = !matches!(&outcome, Ok(Some(metadata)) if metadata.reused);
+            update_summary(summary, frame_number, &name, outcome);
+
+            next = frames
+                .get(after)
+                .filter(|_| changed)
+                .and_then(|f| {
+                    let folder = f.collections.iter().find(|c| collection_name(c) == name)?;
+                    Some((f.frame_number, folder.clone()))
+                })
+                .filter(|(next_number, _)| {
+                    self.config_for(*next_number, &name)
+                        .map(|c| c.planner.follows_previous_frame())
+                        .unwrap_or(false)
+                });
+        }
+
+        planned
+    }
+
+    /// Write the summary.json into the output folder, replacing the previous one atomically.
+    pub fn write_summary(&self, summary: &AnimationMetadata) -> VortexResult<PathBuf> {
+        let summary_path = self.output_root.join("summary.json");
+
+        fs::create_dir_all(&self.output_root).map_err(|e| VortexError::io(&self.output_root, e))?;
+
+        // Written alongside then renamed over the old summary, so the UI never reads a partial file
+        let partial_path = self.output_root.join("summary.json.partial");
+        export_json(&partial_path, summary)?;
+        fs::rename(&partial_path, &summary_path).map_err(|e| VortexError::io(&summary_path, e))?;
+
+        Ok(summary_path)
+    }
+}
+
+fn unique_collection_names(frames: &[FrameMetadata]) -> Vec<String> {
+    frames
+        .iter()
+        .flat_map(|frame| frame.collections.iter().map(|c| c.name.clone()))
+        .unique()
+        .collect()
+}
+
+// Replace a collection's entry in the summary, adding or removing its frame as needed
+fn update_summary(
+    summary: &mut AnimationMetadata,
+    frame_number: i32,
+    name: &str,
+    outcome: CollectionOutcome,
+) {
+    let position = match summary
+        .frames
+        .binary_search_by_key(&frame_number, |f| f.frame_num)
+    {
+        Ok(position) => position,
+        Err(position) => {
+            summary
+                .frames
+                .insert(position, frame_metadata(frame_number, vec![]));
+            position
+        }
+    };
+
+    let frame = &mut summary.frames[position];
+    frame.collections.retain(|c| c.name != name);
+    frame.failures.retain(|f| f.name != name);
+
+    let updated = frame_metadata(frame_number, vec![(name.to_string(), outcome)]);
+    frame.collections.extend(updated.collections);
+    frame.failures.extend(updated.failures);
+    frame.collections.sort_by(|a, b| a.name.cmp(&b.name));
+    frame.failures.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if frame.collections.is_empty() && frame.failures.is_empty() {
+        summary.frames.remove(position);
+    }
+    summary.collections = unique_collection_names(&summary.frames);
+}
+
+// Numbered frame folders in ascending order
+fn find_frames(input_root: &Path) -> Vec<FrameFolder> {
+    let mut frames: Vec<FrameFolder> = WalkDir::new(input_root)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_entry(is_frame_folder)
+        .filter_map(|v| v.ok())
+        .filter_map(|x| {
+            Some(FrameFolder {
+                frame_number: frame_number(x.path())?,
+                collections: collection_folders(x.path()),
+            })
+        })
+        .collect();
+
+    frames.sort_by_key(|f| f.frame_number);
+    frames
+}
+
+fn collection_folders(frame_folder: &Path) -> Vec<PathBuf> {
+    WalkDir::new(frame_folder)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.file_type().is_dir())
+        .filter_map(|v| v.ok())
+        .map(|e| e.into_path())
+        .collect()
+}
+
+fn collection_name(folder: &Path) -> String {
+    folder
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// Collections which failed are listed separately from those which were planned
+fn frame_metadata<I>(frame_number: i32, outcomes: I) -> FrameMetadata
+where
+    I: IntoIterator<Item = (String, CollectionOutcome)>,
+{
+    let mut exported_file_metadata: Vec<FileMetadata> = vec![];
+    let mut failures: Vec<FailureMetadata> = vec![];
+
+    for (name, outcome) in outcomes {
+        match outcome {
+            Ok(Some(metadata)) => exported_file_metadata.push(metadata),
+            Ok(None) => {}
+            Err(error) => failures.push(FailureMetadata {
+                name,
+                error: error.to_string(),
+            }),
+        }
+    }
+
+    FrameMetadata {
+        frame_num: frame_number,
+        collections: exported_file_metadata,
+        failures,
+    }
+}
+
+fn pathbuf_to_string(input: PathBuf) -> String {
+    input.to_string_lossy().to_string()
+}
+
+// Takes a destination folder, the name of the collection, and the extension of the file
+// Returns a path to the location of the file, with a cleaner filename
+fn format_filename(destination: &Path, name: &str, extension: &str) -> PathBuf {
+    let mut collection_name = name.to_lowercase();
+    collection_name.retain(|c| !c.is_whitespace());
+
+    let file_name = format!("{}_{}", collection_name, extension);
+    let path = Path::new(&file_name);
+    let parent_folder = destination;
+
+    parent_folder.join(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty folder for a test to write into, cleared of anything left from an earlier run
+    fn scratch_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("vortex-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn write_strokes(folder: &Path, offset: f32) {
+        fs::create_dir_all(folder).unwrap();
+        let strokes: String = (0..4)
+            .map(|i| {
+                let y = i as f32 * 10.0 + offset;
+                format!(
+                    r#"<line x1="0" y1="{y}" x2="30" y2="{y}" stroke="red"/>"#,
+                    y = y
+                )
+            })
+            .collect();
+        fs::write(
+            folder.join("strokes.svg"),
+            format!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg">{}</svg>"#,
+                strokes
+            ),
+        )
+        .unwrap();
+    }
+
+    fn temporal_pipeline(output_root: &Path) -> Pipeline {
+        let mut project = ProjectConfig::default();
+        project
+            .force_settings(toml::from_str("planner.temporal_coherence = true").unwrap())
+            .unwrap();
+
+        Pipeline::new(project, output_root, OutputMode::Plan).unwrap()
+    }
+
+    #[test]
+    fn replanning_follows_frames_by_position_when_numbers_are_stepped() {
+        let root = scratch_folder("stepped-frames");
+        let (input, output) = (root.join("input"), root.join("output"));
+        for frame in &["0010", "0020", "0030"] {
+            write_strokes(&input.join(frame).join("Lines"), 0.0);
+        }
+
+        let pipeline = temporal_pipeline(&output);
+        let mut summary = pipeline.process_animation(&input);
+        assert_eq!(summary.failure_count(), 0);
+
+        // Changing the middle frame replans it after 0010, then the frame after it
+        write_strokes(&input.join("0020").join("Lines"), 5.0);
+        let planned = pipeline.replan_collection(&mut summary, &input.join("0020").join("Lines"));
+        assert_eq!(planned, 2);
+
+        // The replanned frame matches what planning the whole animation does
+        let replanned = pipeline.process_animation(&input);
+        assert_eq!(replanned.reused_count(), 3);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unchanged_collections_reuse_their_build() {
+        let root = scratch_folder("reuse");
+        let (input, output) = (root.join("input"), root.join("output"));
+        let folder = input.join("0001").join("Lines");
+        write_strokes(&folder, 0.0);
+
+        let mut pipeline = temporal_pipeline(&output);
+        let planned = pipeline
+            .process_collection(&folder, 1, None)
+            .unwrap()
+            .unwrap();
+        assert!(!planned.reused);
+
+        let reused = pipeline
+            .process_collection(&folder, 1, None)
+            .unwrap()
+            .unwrap();
+        assert!(reused.reused);
+        assert_eq!(reused.duration, planned.duration);
+        assert_eq!(reused.num_moves, planned.num_moves);
+
+        // Changed inputs, missing outputs and forced builds are planned again
+        write_strokes(&folder, 5.0);
+        assert!(
+            !pipeline
+                .process_collection(&folder, 1, None)
+                .unwrap()
+                .unwrap()
+                .reused
+        );
+
+        let files = CollectionFiles::new(&output.join("0001"), "Lines");
+        fs::remove_file(&files.toolpath).unwrap();
+        assert!(
+            !pipeline
+                .process_collection(&folder, 1, None)
+                .unwrap()
+                .unwrap()
+                .reused
+        );
+
+        pipeline.force = true;
+        assert!(
+            !pipeline
+                .process_collection(&folder, 1, None)
+                .unwrap()
+                .unwrap()
+                .reused
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+use colorsys::Hsl;
+
+use crate::delta_utils::*;
+use crate::error::*;
+use crate::export_types::*;
+use crate::import_types::*;
+
+/// Preview data for the delta-ui.
+///
+/// Vertices are pairs making up line segments, with one LED colour for each vertex.
+/// Moves made with the LED off, like transits between splines, are kept in their own layer as
+/// uncoloured line segments.
+#[derive(Debug, Clone, Default)]
+pub struct ViewerData {
+    pub vertices: Vec<(f32, f32, f32)>,
+    pub colors: Vec<Hsl>,
+    pub transits: Vec<(f32, f32, f32)>,
+}
+
+// A planned fade, placed on the toolpath's timeline
+struct LightSpan {
+    start: f32,
+    end: f32,
+    from: (f32, f32, f32),
+    to: (f32, f32, f32),
+}
+
+// A straight piece of the effector's path, with times in ms since the toolpath started.
+// Colours are the LED's at either end, or None when the LED is off.
+pub(crate) struct PathSegment {
+    pub from: BlenderPoint3,
+    pub to: BlenderPoint3,
+    pub start: f32,
+    pub end: f32,
+    pub colors: Option<(Hsl, Hsl)>,
+}
+
+/// Preview exactly what a planned toolpath will do, rather than the splines it was planned from.
+///
+/// Moves are replayed from home and sampled into line segments, each vertex coloured by whichever
+/// fade the LED is running as the effector passes it. Segments drawn with the LED off, like transits,
+/// delays and unlit gaps between fades, go in the transit layer instead.
+pub fn generate_toolpath_preview(events: &ActionGroups) -> VortexResult<ViewerData> {
+    let mut preview = ViewerData::default();
+
+    for segment in replay_toolpath(events)? {
+        let vertices = [segment.from, segment.to].map(|v| (v.x, v.y, v.z));
+
+        match segment.colors {
+            Some((from, to)) => {
+                preview.vertices.extend(vertices);
+                preview.colors.push(from);
+                preview.colors.push(to);
+            }
+            None => preview.transits.extend(vertices),
+        }
+    }
+
+    Ok(preview)
+}
+
+// Follow the effector from home through every move, with the LED colour along the way
+pub(crate) fn replay_toolpath(events: &ActionGroups) -> VortexResult<Vec<PathSegment>> {
+    let mut segments = vec![];
+
+    // When each move starts, so fades can be lined up with the move they're synchronised to
+    let mut move_starts = vec![];
+    let mut time = 0.0;
+    for action in &events.delta {
+        move_starts.push(time);
+        time += action.payload.duration as f32;
+    }
+
+    let lights = light_spans(events, &move_starts);
+
+    let mut position = BlenderPoint3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    for (action, start) in events.delta.iter().zip(move_starts) {
+        let motion = &action.payload;
+        let samples = sample_motion(motion, position)?;
+        if let Some(&end) = samples.last() {
+            position = end;
+        }
+
+        // Assume the effector covers each move at an even pace, as the lighting is planned that way
+        let lengths: Vec<f32> = samples
+            .windows(2)
+            .map(|pair| distance_3d(&pair[0], &pair[1]))
+            .collect();
+        let total: f32 = lengths.iter().sum();
+        let duration = motion.duration as f32;
+
+        let mut travelled = 0.0;
+        for (pair, length) in samples.windows(2).zip(lengths) {
+            let time_at = |distance: f32| {
+                if total > f32::EPSILON {
+                    start + duration * distance / total
+                } else {
+                    start
+                }
+            };
+            let (a, b) = (time_at(travelled), time_at(travelled + length));
+            travelled += length;
+
+            // Delays hold the effector still
+            if length <= f32::EPSILON {
+                continue;
+            }
+
+            let colors = color_at(&lights, (a + b) / 2.0).map(|middle| {
+                (
+                    color_at(&lights, a).unwrap_or_else(|| middle.clone()),
+                    color_at(&lights, b).unwrap_or(middle),
+                )
+            });
+
+            segments.push(PathSegment {
+                from: pair[0],
+                to: pair[1],
+                start: a,
+                end: b,
+                colors,
+            });
+        }
+    }
+
+    Ok(segments)
+}
+
+// Fades run one after another from the start of the move they're synchronised to
+fn light_spans(events: &ActionGroups, move_starts: &[f32]) -> Vec<LightSpan> {
+    let mut spans = vec![];
+    let mut group: Option<(u32, f32)> = None;
+
+    for action in &events.light {
+        let fade = &action.payload;
+
+        let start = match group {
+            Some((id, end)) if id == fade.id => end,
+            _ => match (fade.id as usize)
+                .checked_sub(1)
+                .and_then(|i| move_starts.get(i))
+            {
+                Some(&start) => start,
+                None => {
+                    log::warn!("Fade waits for move {} which isn't planned", fade.id);
+                    continue;
+                }
+            },
+        };
+        let end = start + fade.duration as f32;
+        group = Some((fade.id, end));
+
+        let from = match fade.points.first() {
+            Some(&from) => from,
+            None => continue,
+        };
+        let to = match fade.animation_type {
+            LightAnimationType::LinearFade => fade.points.last().copied().unwrap_or(from),
+            LightAnimationType::ConstantOn => from,
+        };
+
+        spans.push(LightSpan {
+            start,
+            end,
+            from,
+            to,
+        });
+    }
+
+    spans
+}
+
+// The LED's colour at a time, or None when it's off
+fn color_at(lights: &[LightSpan], time: f32) -> Option<Hsl> {
+    let span = lights
+        .iter()
+        .rev()
+        .find(|span| span.start <= time && time <= span.end)?;
+
+    let weight = if span.end > span.start {
+        (time - span.start) / (span.end - span.start)
+    } else {
+        1.0
+    };
+    let lerp = |a: f32, b: f32| a + (b - a) * weight;
+
+    // Fades run in the LED's HSL space, with each component in [0, 1]
+    let (h, s, l) = (
+        lerp(span.from.0, span.to.0),
+        lerp(span.from.1, span.to.1),
+        lerp(span.from.2, span.to.2),
+    );
+
+    if l <= 0.0 {
+        return None;
+    }
+
+    Some(Hsl::new(
+        f64::from(h) * 360.0,
+        f64::from(s) * 100.0,
+        f64::from(l) * 100.0,
+        Option::from(1.0),
+    ))
+}
+
+// Points along a move from the effector's current position, close enough together to draw as lines
+fn sample_motion(motion: &Motion, from: BlenderPoint3) -> VortexResult<Vec<BlenderPoint3>> {
+    let mut points: Vec<BlenderPoint3> = motion
+        .points
+        .iter()
+        .map(|&p| BlenderPoint3::from(p))
+        .collect();
+
+    if motion.reference == MotionReferenceFrame::Relative {
+        for point in &mut points {
+            point.x += from.x;
+            point.y += from.y;
+            point.z += from.z;
+        }
+    }
+
+    let (spline_type, geometry) = match motion.motion_type {
+        MotionInterpolationType::PointTransit => (1, vec![from, last_point(&points)?]),
+        MotionInterpolationType::Line => (1, points),
+        MotionInterpolationType::CatmullSpline => (2, points),
+        MotionInterpolationType::BezierCubic => (4, points),
+        MotionInterpolationType::BezierQuadratic => {
+            // Elevate to a cubic with the same shape
+            if points.len() != 3 {
+                return Err(VortexError::UnsupportedOperation(format!(
+                    "Quadratic bezier move {} has {} points",
+                    motion.id,
+                    points.len()
+                )));
+            }
+            let (a, c, b) = (points[0], points[1], points[2]);
+            let toward = |p: BlenderPoint3| BlenderPoint3 {
+                x: p.x + (c.x - p.x) * 2.0 / 3.0,
+                y: p.y + (c.y - p.y) * 2.0 / 3.0,
+                z: p.z + (c.z - p.z) * 2.0 / 3.0,
+            };
+            (4, vec![a, toward(a), toward(b), b])
+        }
+    };
+
+    // Line segments come back as pairs of vertices, join them back into a path
+    let vertices = vertex_from_spline(spline_type, &geometry)?;
+    let mut samples: Vec<BlenderPoint3> = vertices
+        .iter()
+        .step_by(2)
+        .map(|&v| BlenderPoint3::from(v))
+        .collect();
+    if let Some(&end) = vertices.last() {
+        samples.push(BlenderPoint3::from(end));
+    }
+
+    Ok(samples)
+}
+
+fn last_point(points: &[BlenderPoint3]) -> VortexResult<BlenderPoint3> {
+    points
+        .last()
+        .copied()
+        .ok_or_else(|| VortexError::EmptyGeometry("Move has no points".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catmull_moves_are_previewed_between_their_inner_points() {
+        let mut events = ActionGroups::new();
+        events.add_delta_action(Motion {
+            id: 0,
+            reference: MotionReferenceFrame::Absolute,
+            motion_type: MotionInterpolationType::CatmullSpline,
+            duration: 1000,
+            points: vec![
+                (-10.0, 0.0, 0.0),
+                (0.0, 0.0, 0.0),
+                (10.0, 10.0, 0.0),
+                (20.0, 10.0, 0.0),
+            ],
+        });
+        events.add_light_action(Fade {
+            animation_type: LightAnimationType::ConstantOn,
+            id: 0,
+            duration: 1000,
+            points: vec![(0.0, 1.0, 0.5), (0.0, 1.0, 0.5)],
+        });
+
+        let preview = generate_toolpath_preview(&events).unwrap();
+
+        assert!(preview.vertices.len() > 2);
+        assert_eq!(preview.colors.len(), preview.vertices.len());
+        assert_eq!(preview.vertices[0], (0.0, 0.0, 0.0));
+        assert_eq!(
+            preview.vertices[preview.vertices.len() - 1],
+            (10.0, 10.0, 0.0)
+        );
+
+        // The segment curves rather than cutting straight across
+        assert!(preview.vertices.iter().any(|v| (v.0 - v.1).abs() > 0.1));
+    }
+}
@@ -0,0 +1,140 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use total_perspective_vortex::*;
+
+// Counts collections as they finish on the worker threads. On a terminal a single status line is redrawn,
+// otherwise each collection gets its own line so logs stay readable.
+pub struct ConsoleProgress {
+    state: Mutex<ProgressState>,
+    interactive: bool,
+}
+
+struct ProgressState {
+    total: usize,
+    finished: usize,
+    failed: usize,
+    started: Instant,
+}
+
+impl ConsoleProgress {
+    pub fn new() -> ConsoleProgress {
+        ConsoleProgress {
+            state: Mutex::new(ProgressState {
+                total: 0,
+                finished: 0,
+                failed: 0,
+                started: Instant::now(),
+            }),
+            interactive: io::stderr().is_terminal(),
+        }
+    }
+}
+
+impl ProgressReporter for ConsoleProgress {
+    fn started(&self, collections: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.total = collections;
+            state.finished = 0;
+            state.failed = 0;
+            state.started = Instant::now();
+        }
+    }
+
+    fn collection_finished(
+        &self,
+        frame: i32,
+        collection: &str,
+        outcome: &VortexResult<Option<FileMetadata>>,
+    ) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.finished += 1;
+
+        let mut stderr = io::stderr().lock();
+        if let Err(error) = outcome {
+            state.failed += 1;
+            let _ = writeln!(
+                stderr,
+                "{}Frame {} {} failed: {}",
+                clear_line(self.interactive),
+                frame,
+                collection,
+                error
+            );
+        }
+
+        let status = format!(
+            "[{}/{}] frame {} {}, {} failed, {:.1}s",
+            state.finished,
+            state.total,
+            frame,
+            collection,
+            state.failed,
+            state.started.elapsed().as_secs_f32()
+        );
+
+        if self.interactive {
+            let _ = write!(stderr, "\r\x1b[K{}", status);
+            if state.finished == state.total {
+                let _ = writeln!(stderr);
+            }
+        } else {
+            let _ = writeln!(stderr, "{}", status);
+        }
+        let _ = stderr.flush();
+    }
+}
+
+fn clear_line(interactive: bool) -> &'static str {
+    if interactive {
+        "\r\x1b[K"
+    } else {
+        ""
+    }
+}
+
+// Prints warnings from the planner above the progress line
+pub struct ConsoleLogger {
+    interactive: bool,
+}
+
+impl ConsoleLogger {
+    pub fn install() {
+        let logger = ConsoleLogger {
+            interactive: io::stderr().is_terminal(),
+        };
+
+        if log::set_boxed_logger(Box::new(logger)).is_ok() {
+            log::set_max_level(LevelFilter::Warn);
+        }
+    }
+}
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let level = match record.level() {
+                Level::Error => "error",
+                _ => "warning",
+            };
+            eprintln!(
+                "{}{}: {}",
+                clear_line(self.interactive),
+                level,
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,353 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use colorsys::{Hsl, Rgb};
+use image::{ImageBuffer, Rgb as Pixel, RgbImage};
+use rayon::prelude::*;
+
+use crate::config::RenderConfig;
+use crate::error::*;
+use crate::export_types::*;
+use crate::import_data::load_toolpath;
+use crate::import_types::*;
+use crate::pipeline::CollectionFiles;
+use crate::preview::replay_toolpath;
+
+// Segments are split into steps no longer than this many pixels, so lines are exposed evenly
+const STEP_PIXELS: f32 = 0.5;
+
+// Points closer to the camera than this many mm, or behind it, aren't drawn
+const NEAR_PLANE: f32 = 1.0;
+
+/// A pinhole camera looking at the machine, with Z up.
+pub struct Camera {
+    position: [f32; 3],
+    right: [f32; 3],
+    up: [f32; 3],
+    forward: [f32; 3],
+    focal_length: f32, // px
+    center: (f32, f32),
+}
+
+impl Camera {
+    pub fn new(config: &RenderConfig) -> Camera {
+        let forward = normalize(sub(config.camera_target, config.camera_position));
+
+        // Looking straight up or down, any horizontal direction will do for the image's right
+        let mut right = cross(forward, [0.0, 0.0, 1.0]);
+        if length(right) < 1e-6 {
+            right = [1.0, 0.0, 0.0];
+        }
+        let right = normalize(right);
+        let up = cross(right, forward);
+
+        let half_fov = (config.field_of_view / 2.0).to_radians();
+
+        Camera {
+            position: config.camera_position,
+            right,
+            up,
+            forward,
+            focal_length: config.height as f32 / 2.0 / half_fov.tan(),
+            center: (config.width as f32 / 2.0, config.height as f32 / 2.0),
+        }
+    }
+
+    // Where a point lands on the image in pixels, if it's in front of the camera
+    pub(crate) fn project(&self, point: BlenderPoint3) -> Option<(f32, f32)> {
+        let relative = sub([point.x, point.y, point.z], self.position);
+        let depth = dot(relative, self.forward);
+        if depth < NEAR_PLANE {
+            return None;
+        }
+
+        let x = dot(relative, self.right) / depth;
+        let y = dot(relative, self.up) / depth;
+
+        Some((
+            self.center.0 + x * self.focal_length,
+            self.center.1 - y * self.focal_length,
+        ))
+    }
+}
+
+/// Light gathered by the camera's sensor, in linear RGB.
+pub struct Exposure {
+    width: usize,
+    height: usize,
+    pixels: Vec<[f32; 3]>,
+}
+
+impl Exposure {
+    pub fn new(config: &RenderConfig) -> Exposure {
+        let width = config.width as usize;
+        let height = config.height as usize;
+
+        Exposure {
+            width,
+            height,
+            pixels: vec![[0.0; 3]; width * height],
+        }
+    }
+
+    /// Replay a planned toolpath, adding the light from the LED everywhere it passes while lit.
+    pub fn expose(
+        &mut self,
+        events: &ActionGroups,
+        camera: &Camera,
+        config: &RenderConfig,
+    ) -> VortexResult<()> {
+        for segment in replay_toolpath(events)? {
+            let (from_color, to_color) = match &segment.colors {
+                Some((from, to)) => (linear_rgb(from), linear_rgb(to)),
+                None => continue,
+            };
+
+            let (a, b) = match (camera.project(segment.from), camera.project(segment.to)) {
+                (Some(a), Some(b)) => (a, b),
+                _ => continue,
+            };
+
+            // Slower moves leave more light on each pixel they cross
+            let pixels = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+            let steps = (pixels / STEP_PIXELS).ceil().clamp(1.0, 100_000.0) as usize;
+            let energy = (segment.end - segment.start) * config.exposure / steps as f32;
+
+            for step in 0..steps {
+                let t = (step as f32 + 0.5) / steps as f32;
+                let point = BlenderPoint3 {
+                    x: segment.from.x + (segment.to.x - segment.from.x) * t,
+                    y: segment.from.y + (segment.to.y - segment.from.y) * t,
+                    z: segment.from.z + (segment.to.z - segment.from.z) * t,
+                };
+
+                if let Some(position) = camera.project(point) {
+                    let color = [0, 1, 2]
+                        .map(|c| (from_color[c] + (to_color[c] - from_color[c]) * t) * energy);
+                    self.splat(position, color);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Combine another exposure of the same size into this one, like a double exposure.
+    pub fn add(&mut self, other: &Exposure) {
+        for (pixel, light) in self.pixels.iter_mut().zip(&other.pixels) {
+            for c in 0..3 {
+                pixel[c] += light[c];
+            }
+        }
+    }
+
+    /// Develop the exposure into an 8 bit image, with bloom around the brightest light.
+    pub fn develop(&self, config: &RenderConfig) -> RgbImage {
+        let mut pixels = self.pixels.clone();
+
+        if config.bloom_strength > 0.0 && config.bloom_radius > 0.0 {
+            let bloom = gaussian_blur(&self.pixels, self.width, self.height, config.bloom_radius);
+            for (pixel, glow) in pixels.iter_mut().zip(bloom) {
+                for c in 0..3 {
+                    pixel[c] += glow[c] * config.bloom_strength;
+                }
+            }
+        }
+
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let light = pixels[y as usize * self.width + x as usize];
+
+            // Highlights roll off like film rather than clipping
+            Pixel(light.map(|v| (encode_srgb(1.0 - (-v).exp()) * 255.0).round() as u8))
+        })
+    }
+
+    // Spread light over the four pixels nearest a point on the image
+    fn splat(&mut self, position: (f32, f32), color: [f32; 3]) {
+        let x = position.0 - 0.5;
+        let y = position.1 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        for (dx, dy, weight) in [
+            (0, 0, (1.0 - fx) * (1.0 - fy)),
+            (1, 0, fx * (1.0 - fy)),
+            (0, 1, (1.0 - fx) * fy),
+            (1, 1, fx * fy),
+        ] {
+            let px = x0 as i64 + dx;
+            let py = y0 as i64 + dy;
+            if px < 0 || py < 0 || px >= self.width as i64 || py >= self.height as i64 {
+                continue;
+            }
+
+            let pixel = &mut self.pixels[py as usize * self.width + px as usize];
+            for c in 0..3 {
+                pixel[c] += color[c] * weight;
+            }
+        }
+    }
+}
+
+fn write_png(path: &Path, image: &RgbImage) -> VortexResult<()> {
+    if let Some(folder) = path.parent() {
+        fs::create_dir_all(folder).map_err(|e| VortexError::io(folder, e))?;
+    }
+
+    image.save(path).map_err(|e| VortexError::Image {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+/// Render every frame of a planned animation as a long exposure photo.
+///
+/// Each frame's collections are exposed onto the same photo, written to `render/<frame>.png` in the
+/// output folder. With `per_collection`, each collection is also written next to its toolpath.
+/// Returns the paths of the frame renders.
+pub fn render_animation(
+    summary: &AnimationMetadata,
+    output_root: &Path,
+    config: &RenderConfig,
+    per_collection: bool,
+) -> VortexResult<Vec<PathBuf>> {
+    config.validate()?;
+    let camera = Camera::new(config);
+
+    summary
+        .frames
+        .par_iter()
+        .filter(|frame| !frame.collections.is_empty())
+        .map(|frame| {
+            let mut photo = Exposure::new(config);
+
+            for collection in &frame.collections {
+                let toolpath_path = Path::new(&collection.toolpath_path);
+                let toolpath = load_toolpath(toolpath_path)?;
+
+                let mut exposure = Exposure::new(config);
+                for events in &toolpath.actions {
+                    exposure.expose(events, &camera, config)?;
+                }
+
+                if per_collection {
+                    let destination = toolpath_path.parent().unwrap_or(output_root);
+                    let files = CollectionFiles::new(destination, &collection.name);
+                    write_png(&files.render, &exposure.develop(config))?;
+                }
+
+                photo.add(&exposure);
+            }
+
+            let path = output_root
+                .join("render")
+                .join(format!("{:04}.png", frame.frame_num));
+            write_png(&path, &photo.develop(config))?;
+
+            Ok(path)
+        })
+        .collect()
+}
+
+// LED colours are sRGB, light adds up linearly
+fn linear_rgb(color: &Hsl) -> [f32; 3] {
+    let rgb = Rgb::from(color);
+
+    [rgb.get_red(), rgb.get_green(), rgb.get_blue()].map(|c| ((c / 255.0) as f32).powf(2.2))
+}
+
+fn encode_srgb(value: f32) -> f32 {
+    value.clamp(0.0, 1.0).powf(1.0 / 2.2)
+}
+
+// Three box blurs approximate a gaussian, and running sums keep each one cheap for wide bloom
+fn gaussian_blur(pixels: &[[f32; 3]], width: usize, height: usize, sigma: f32) -> Vec<[f32; 3]> {
+    let box_width = (12.0 * sigma * sigma / 3.0 + 1.0).sqrt();
+    let radius = ((box_width - 1.0) / 2.0).round().max(1.0) as usize;
+
+    let mut blurred = pixels.to_vec();
+    for _ in 0..3 {
+        blurred = box_blur(&blurred, width, height, radius, true);
+        blurred = box_blur(&blurred, width, height, radius, false);
+    }
+
+    blurred
+}
+
+// Average each pixel with those within radius of it across (or down) the image, edges are treated as dark
+fn box_blur(
+    input: &[[f32; 3]],
+    width: usize,
+    height: usize,
+    radius: usize,
+    horizontal: bool,
+) -> Vec<[f32; 3]> {
+    let mut output = vec![[0.0; 3]; input.len()];
+    let (lines, line_length) = if horizontal {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let index = |line: usize, i: usize| {
+        if horizontal {
+            line * width + i
+        } else {
+            i * width + line
+        }
+    };
+    let scale = 1.0 / (2 * radius + 1) as f32;
+
+    for line in 0..lines {
+        let mut sum = [0.0; 3];
+        for i in 0..radius.min(line_length) {
+            let sample = input[index(line, i)];
+            for c in 0..3 {
+                sum[c] += sample[c];
+            }
+        }
+
+        for i in 0..line_length {
+            if i + radius < line_length {
+                let entering = input[index(line, i + radius)];
+                for c in 0..3 {
+                    sum[c] += entering[c];
+                }
+            }
+            if i > radius {
+                let leaving = input[index(line, i - radius - 1)];
+                for c in 0..3 {
+                    sum[c] -= leaving[c];
+                }
+            }
+
+            output[index(line, i)] = sum.map(|v| v * scale);
+        }
+    }
+
+    output
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn length(a: [f32; 3]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: [f32; 3]) -> [f32; 3] {
+    let l = length(a);
+    [a[0] / l, a[1] / l, a[2] / l]
+}
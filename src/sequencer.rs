@@ -1,60 +1,74 @@
 use crate::color_utils::*;
+use crate::config::PlannerConfig;
 use crate::delta_utils::*;
+use crate::error::*;
+use crate::fade_fitting::fit_gradient;
+use crate::fade_space::{firmware_keyframes, Keyframe};
 
 use crate::export_types::*;
 use crate::import_types::*;
+use crate::motion_profile::*;
+use crate::nurbs::nurbs_to_bezier;
+use crate::speed_compensation::*;
 
 use colorsys::Hsl;
 
-const MOVEMENT_SPEED: f32 = 200.0;
-const CLUSTER_THRESHOLD: f64 = 300.0;
-const POINT_DELAY_MS: u32 = 10;
-
-const TRANSIT_SHAPING_FACTOR: f32 = 0.01; //between 0 and 1
-
 // Generate a move between A and B
-fn move_between(a: BlenderPoint3, b: BlenderPoint3, speed: f32) -> Option<Motion> {
+fn move_between(
+    a: BlenderPoint3,
+    b: BlenderPoint3,
+    config: &PlannerConfig,
+) -> VortexResult<Option<Motion>> {
     if a != b {
         // Generate transit move to B instead of requiring a start from home
         if a.is_home() {
-            Some(Motion {
+            Ok(Some(Motion {
                 id: 0,
                 reference: MotionReferenceFrame::Absolute,
                 motion_type: MotionInterpolationType::PointTransit,
                 duration: 500,
                 points: vec![(b.x, b.y, b.z)],
-            })
+            }))
         } else {
-            let duration = calculate_duration(&[a, b], speed).unwrap() as u32;
-
             // Create a bezier curve with control points ON the line between a and b.
             // Control points near the start/end points will create a non-constant velocity line
             // Provides a ease-in-out velocity profile, rather than the constant velocity achieved with a line
-            let control_a = interpolate_line_point(&a, &b, TRANSIT_SHAPING_FACTOR).unwrap();
-            let control_b = interpolate_line_point(&b, &a, TRANSIT_SHAPING_FACTOR).unwrap();
+            let control_a = interpolate_line_point(&a, &b, config.transit_shaping_factor)?;
+            let control_b = interpolate_line_point(&b, &a, config.transit_shaping_factor)?;
 
-            let points: Vec<(f32, f32, f32)> = vec![a, control_a, control_b, b]
+            let points: Vec<(f32, f32, f32)> = [a, control_a, control_b, b]
                 .iter()
                 .map(|bpoint| (bpoint.x, bpoint.y, bpoint.z))
                 .collect();
 
-            Some(Motion {
+            // Transits start and finish at rest
+            let mut transit = Motion {
                 id: 0,
                 reference: MotionReferenceFrame::Absolute,
                 motion_type: MotionInterpolationType::BezierCubic,
-                duration,
+                duration: 0,
                 points,
-            })
+            };
+            MotionProfile::new(config).apply(std::slice::from_mut(&mut transit))?;
+
+            Ok(Some(transit))
         }
     } else {
-        None
+        Ok(None)
     }
 }
 
-fn add_starting_move(events: &mut ActionGroups, a: BlenderPoint3, b: BlenderPoint3) {
-    if let Some(transit) = move_between(a, b, MOVEMENT_SPEED) {
+fn add_starting_move(
+    events: &mut ActionGroups,
+    a: BlenderPoint3,
+    b: BlenderPoint3,
+    config: &PlannerConfig,
+) -> VortexResult<()> {
+    if let Some(transit) = move_between(a, b, config)? {
         events.add_delta_action(transit);
     }
+
+    Ok(())
 }
 
 fn add_delay(events: &mut ActionGroups, time: u32) {
@@ -68,45 +82,167 @@ fn add_delay(events: &mut ActionGroups, time: u32) {
     });
 }
 
-// Generates lighting 'fade' events between the (expanding until visually different) edges of the colour vector slice
-fn generate_visually_distinct_fade<'a>(
+// Maps positions along a spline's colour gradient to the time since the spline started drawing.
+// Breakpoints pair the distance along the spline with the time the effector passes that point.
+struct LightingTimeline {
+    distances: Vec<f32>,
+    times: Vec<f32>,
+    velocities: Vec<MoveVelocity>, // how the speed changes through each move
+}
+
+impl LightingTimeline {
+    // Colours follow the original polyline's length, even when some of its points were merged away
+    fn along_polyline(
+        points: &[BlenderPoint3],
+        kept: &[usize],
+        moves: &[Motion],
+        velocities: Vec<MoveVelocity>,
+    ) -> LightingTimeline {
+        let lengths: Vec<f32> = kept
+            .windows(2)
+            .map(|pair| {
+                points[pair[0]..=pair[1]]
+                    .windows(2)
+                    .map(|p| distance_3d(&p[0], &p[1]))
+                    .sum()
+            })
+            .collect();
+
+        LightingTimeline::along_moves(&lengths, moves, velocities)
+    }
+
+    // Colours follow the length of each move, rather than the time spent on it
+    fn along_moves(
+        lengths: &[f32],
+        moves: &[Motion],
+        velocities: Vec<MoveVelocity>,
+    ) -> LightingTimeline {
+        let mut distances = vec![0.0];
+        let mut times = vec![0.0];
+
+        for (length, motion) in lengths.iter().zip(moves) {
+            distances.push(distances[distances.len() - 1] + length);
+            times.push(times[times.len() - 1] + motion.duration as f32);
+        }
+
+        LightingTimeline {
+            distances,
+            times,
+            velocities,
+        }
+    }
+
+    // How fast the effector draws each move, from when the move starts
+    fn speed_spans(&self) -> Vec<SpeedSpan> {
+        self.times
+            .iter()
+            .zip(&self.velocities)
+            .map(|(&start, &velocity)| SpeedSpan { start, velocity })
+            .collect()
+    }
+
+    // Time in ms when the colour at index i of a gradient with the given number of steps is reached
+    fn colour_time(&self, i: usize, steps: usize) -> u32 {
+        if steps == 0 {
+            return 0;
+        }
+
+        let total_distance = self.distances[self.distances.len() - 1];
+        let total_time = self.times[self.times.len() - 1];
+        let fraction = i as f32 / steps as f32;
+
+        if total_distance <= f32::EPSILON {
+            return (fraction * total_time).round() as u32;
+        }
+
+        // Interpolate within the move which passes this distance
+        let distance = fraction * total_distance;
+        let segment = self
+            .distances
+            .windows(2)
+            .position(|d| distance <= d[1])
+            .unwrap_or(self.distances.len() - 2);
+
+        let span = self.distances[segment + 1] - self.distances[segment];
+        let weight = if span > f32::EPSILON {
+            (distance - self.distances[segment]) / span
+        } else {
+            1.0
+        };
+        let time = self.times[segment] + (self.times[segment + 1] - self.times[segment]) * weight;
+
+        time.round() as u32
+    }
+}
+
+// Generate lighting events matching the UV for a spline's movement
+fn add_spline_lighting(
     events: &mut ActionGroups,
-    i: usize,
-    steps: usize,
-    duration: f32,
-    start_colour: (usize, &'a Hsl),
-    next_colour: (usize, &'a Hsl),
-) -> (usize, &'a Hsl) {
-    if distance_hsl(start_colour.1, next_colour.1).abs() > CLUSTER_THRESHOLD
-        || steps < 3
-        || i == steps && i != 0
-    {
-        // Calculate the duration of the interval between selected points
-        let step_difference = i - start_colour.0;
-        let fade_duration = step_difference as f32 * duration;
-
-        // Grab and format [0,1] the colours into the delta-compatible tuple
-        let cluster_start = delta_led_from_hsl(start_colour.1);
-        let cluster_end = delta_led_from_hsl(next_colour.1);
-
-        // Add the event to the lighting events pool
+    colors: &[Hsl],
+    timeline: &LightingTimeline,
+    config: &PlannerConfig,
+) -> VortexResult<()> {
+    if colors.is_empty() {
+        return Err(VortexError::EmptyGeometry(
+            "Spline has no lighting colours".to_string(),
+        ));
+    }
+    let lighting_steps = colors.len() - 1;
+    let times: Vec<u32> = (0..colors.len())
+        .map(|i| timeline.colour_time(i, lighting_steps))
+        .collect();
+
+    // Gentle gradients need far fewer fades than there are colours, as long as the LED stays close to the UVs
+    let fit = fit_gradient(colors, &times, config);
+    events.add_color_errors(&fit.errors);
+
+    // A single colour can't fade, the LED is just set to it
+    if fit.breakpoints.len() < 2 {
         events.add_light_action(Fade {
             animation_type: LightAnimationType::LinearFade,
             id: 1,
-            duration: fade_duration as u32, //todo round correctly
-            points: vec![cluster_start, cluster_end],
+            duration: 0,
+            points: vec![delta_led_from_hsl(&config.led_color(&colors[0])); 2],
         });
+        return Ok(());
+    }
+
+    let spans = timeline.speed_spans();
 
-        // Set the 'end' of the fade to be the start of the next comparison
-        return next_colour;
+    // The firmware can only fade its HSL tuple, so each fade may need a few keyframes to blend as intended
+    for pair in fit.breakpoints.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+
+        let mut keyframes = firmware_keyframes(
+            &colors[start],
+            &colors[end],
+            times[end] - times[start],
+            config,
+        );
+        if config.speed_compensation {
+            keyframes = compensate_speed(keyframes, times[start], &spans, config);
+        }
+
+        for keyframe in keyframes {
+            events.add_light_action(Fade {
+                animation_type: LightAnimationType::LinearFade,
+                id: 1,
+                duration: keyframe.duration,
+                points: vec![keyframe.from, keyframe.to],
+            });
+        }
     }
 
-    return start_colour;
+    Ok(())
 }
 
 // A delta-ready toolpath file has sets of events grouped by device (delta, led light, cameras etc).
-pub fn generate_delta_toolpath(input: &[BlenderData]) -> ActionGroups {
+pub fn generate_delta_toolpath(
+    input: &[BlenderData],
+    config: &PlannerConfig,
+) -> VortexResult<ActionGroups> {
     let mut event_set = ActionGroups::new();
+    let profile = MotionProfile::new(config);
 
     let mut last_point: BlenderPoint3 = BlenderPoint3 {
         x: 0.0,
@@ -119,23 +255,31 @@ pub fn generate_delta_toolpath(input: &[BlenderData]) -> ActionGroups {
         match &spline_to_process {
             BlenderData::PolySpline(spline) => {
                 // Generate a move from the end of the last spline to the start of the next spline
-                add_starting_move(&mut event_set, last_point, spline.points[0].into_bp3());
+                add_starting_move(
+                    &mut event_set,
+                    last_point,
+                    first_point(&spline.points)?,
+                    config,
+                )?;
                 event_set.reset_barrier_id();
 
+                // Dense exports have many tiny near-collinear lines, merge them within tolerance before planning
+                let points: Vec<BlenderPoint3> =
+                    spline.points.iter().map(|p| p.into_bp3()).collect();
+                let kept = simplify_polyline(&points, config.simplify_tolerance);
+                event_set.add_merged_moves((points.len() - kept.len()) as u32);
+
                 // Polysplines are a chain of lines, a line consists of a pair of BlenderPoint co-ordinates
-                for geometry in spline
-                    .points
-                    .windows(BlenderPoly::get_recommended_window_size())
-                {
-                    let geom: [BlenderPoint3; 2] = [geometry[0].into_bp3(), geometry[1].into_bp3()];
-                    let duration = calculate_duration(&geom, MOVEMENT_SPEED).unwrap() as u32;
-                    last_point = BlenderPoly::get_end_point(geometry).into_bp3();
+                let mut drawing_moves = vec![];
+                for pair in kept.windows(2) {
+                    let geom: [BlenderPoint3; 2] = [points[pair[0]], points[pair[1]]];
+                    last_point = geom[1];
 
-                    event_set.add_delta_action(Motion {
+                    drawing_moves.push(Motion {
                         id: 0,
                         reference: MotionReferenceFrame::Absolute,
                         motion_type: MotionInterpolationType::Line, // polysplines are linear moves
-                        duration,
+                        duration: 0,
                         points: geom
                             .iter()
                             .map(|bpoint| (bpoint.x, bpoint.y, bpoint.z))
@@ -143,50 +287,80 @@ pub fn generate_delta_toolpath(input: &[BlenderData]) -> ActionGroups {
                     });
                 }
 
-                // Generate lighting events matching the UV for this movement
-                let lighting_steps = spline.color.len() - 1;
-                let step_duration =
-                    event_set.get_movement_duration() as f32 / lighting_steps as f32;
-
-                // Keep track of the colour at the start of a given cluster
-                let mut start_colour: (usize, &Hsl) = (0, &spline.color[0]);
-
-                // Run through the gradient and generate planner fades between visually distinct colours
-                // this effectively 'de-dupes' the command set for gentle gradients
-                for (i, next_colour) in spline.color.iter().enumerate() {
-                    start_colour = generate_visually_distinct_fade(
-                        &mut event_set,
-                        i,
-                        lighting_steps,
-                        step_duration,
-                        start_colour,
-                        (i, next_colour),
-                    );
+                // The durations depend on how fast the effector can corner between each line
+                let velocities = profile.apply(&mut drawing_moves)?;
+
+                // The UV colours are spread along the spline's length, find when each part of it is drawn
+                let timeline =
+                    LightingTimeline::along_polyline(&points, &kept, &drawing_moves, velocities);
+                for motion in drawing_moves {
+                    event_set.add_delta_action(motion);
                 }
+
+                add_spline_lighting(&mut event_set, &spline.color, &timeline, config)?;
             }
             BlenderData::NURBSSpline(spline) => {
+                // The firmware can't evaluate NURBS, so the curve is converted into cubic beziers within tolerance
+                let segments = nurbs_to_bezier(spline, config.curve_tolerance)?;
+                let start = segments.first().map(|s| s[0]).ok_or_else(|| {
+                    VortexError::EmptyGeometry("NURBS spline has no segments".to_string())
+                })?;
+
                 // Generate a move from the end of the last spline to the start of the next spline
-                add_starting_move(&mut event_set, last_point, spline.points[0].into_bp3());
+                add_starting_move(&mut event_set, last_point, start, config)?;
                 event_set.reset_barrier_id();
 
-                // NURBSSplines are a list of points representing a catmull-rom spline. Generate movements representing the minimal spline segments.
-                for geometry in spline
+                let mut drawing_moves = vec![];
+                let mut lengths = vec![];
+                for geom in segments {
+                    last_point = geom[3];
+                    lengths.push(distance_bezier(&geom)?);
+
+                    drawing_moves.push(Motion {
+                        id: 0,
+                        reference: MotionReferenceFrame::Absolute,
+                        motion_type: MotionInterpolationType::BezierCubic,
+                        duration: 0,
+                        points: geom
+                            .iter()
+                            .map(|bpoint| (bpoint.x, bpoint.y, bpoint.z))
+                            .collect(),
+                    });
+                }
+
+                let velocities = profile.apply(&mut drawing_moves)?;
+
+                let timeline = LightingTimeline::along_moves(&lengths, &drawing_moves, velocities);
+                for motion in drawing_moves {
+                    event_set.add_delta_action(motion);
+                }
+
+                add_spline_lighting(&mut event_set, &spline.color, &timeline, config)?;
+            }
+            BlenderData::BezierSpline(spline) => {
+                // Generate a move from the end of the last spline to the start of the next spline
+                let start = spline.points.first().map(|p| p.co).ok_or_else(|| {
+                    VortexError::EmptyGeometry("Spline has no points".to_string())
+                })?;
+                add_starting_move(&mut event_set, last_point, start, config)?;
+                event_set.reset_barrier_id();
+
+                // Each pair of points is a cubic bezier, drawn natively rather than approximated with lines
+                let mut drawing_moves = vec![];
+                let mut lengths = vec![];
+                for pair in spline
                     .points
-                    .windows(BlenderNURBS::get_recommended_window_size())
+                    .windows(BlenderBezier::get_recommended_window_size())
                 {
-                    let geom: [BlenderPoint3; 4] = [
-                        geometry[0].into_bp3(),
-                        geometry[1].into_bp3(),
-                        geometry[2].into_bp3(),
-                        geometry[3].into_bp3(),
-                    ];
-
-                    last_point = BlenderNURBS::get_end_point(geometry).into_bp3();
-                    event_set.add_delta_action(Motion {
+                    let geom = BlenderBezier::segment(pair)?;
+                    last_point = BlenderBezier::get_end_point(pair)?.co;
+                    lengths.push(distance_bezier(&geom)?);
+
+                    drawing_moves.push(Motion {
                         id: 0,
                         reference: MotionReferenceFrame::Absolute,
-                        motion_type: MotionInterpolationType::CatmullSpline,
-                        duration: calculate_duration(&geom, MOVEMENT_SPEED).unwrap() as u32,
+                        motion_type: MotionInterpolationType::BezierCubic,
+                        duration: 0,
                         points: geom
                             .iter()
                             .map(|bpoint| (bpoint.x, bpoint.y, bpoint.z))
@@ -194,101 +368,91 @@ pub fn generate_delta_toolpath(input: &[BlenderData]) -> ActionGroups {
                     });
                 }
 
-                // Generate lighting events matching the UV for this movement
-                let lighting_steps = spline.color.len() - 1;
-                let step_duration =
-                    event_set.get_movement_duration() as f32 / lighting_steps as f32;
-
-                // Keep track of the colour at the start of a given cluster
-                let mut start_colour: (usize, &Hsl) = (0, &spline.color[0]);
-
-                // Run through the gradient and generate planner fades between visually distinct colours
-                // this effectively 'de-dupes' the command set for gentle gradients
-                for (i, next_colour) in spline.color.iter().enumerate() {
-                    start_colour = generate_visually_distinct_fade(
-                        &mut event_set,
-                        i,
-                        lighting_steps,
-                        step_duration,
-                        start_colour,
-                        (i, next_colour),
-                    );
+                let velocities = profile.apply(&mut drawing_moves)?;
+
+                // Segments can be very different lengths, so the UV colours follow the curve's length
+                let timeline = LightingTimeline::along_moves(&lengths, &drawing_moves, velocities);
+                for motion in drawing_moves {
+                    event_set.add_delta_action(motion);
                 }
+
+                add_spline_lighting(&mut event_set, &spline.color, &timeline, config)?;
             }
             BlenderData::Particles(p) => {
                 // Create a movement for each particle between last and current locations with the specified 'global' colour
                 for particle in &p.particles {
                     // Move to the particle's start point
-                    add_starting_move(&mut event_set, last_point, particle.prev_location);
+                    add_starting_move(&mut event_set, last_point, particle.prev_location, config)?;
 
-                    // Execute a line over the length of the particle's trail
+                    // Execute a line over the length of the particle's trail, starting and finishing at rest
                     let p_line = [particle.prev_location, particle.location];
-                    let move_duration = calculate_duration(&p_line, MOVEMENT_SPEED).unwrap() as u32;
-
-                    last_point = particle.location; //retain this for use in the next loop's transit start
-
-                    add_delay(&mut event_set, POINT_DELAY_MS);
-
-                    event_set.reset_barrier_id();
-
-                    event_set.add_delta_action(Motion {
+                    let mut trail = Motion {
                         id: 0,
                         reference: MotionReferenceFrame::Absolute,
                         motion_type: MotionInterpolationType::Line, // particle trails are linear moves
-                        duration: move_duration,
+                        duration: 0,
                         points: p_line.iter().map(|p| (p.x, p.y, p.z)).collect(),
-                    });
-
-                    let p_color = p.color.iter().map(|c| delta_led_from_hsl(c)).collect();
-                    event_set.add_light_action(Fade {
-                        animation_type: LightAnimationType::ConstantOn,
-                        id: 0,
-                        duration: move_duration,
-                        points: p_color,
-                    });
-                }
-            }
-        }
-    }
+                    };
+                    let velocities = profile.apply(std::slice::from_mut(&mut trail))?;
+                    let move_duration = trail.duration;
 
-    event_set
-}
+                    last_point = particle.location; //retain this for use in the next loop's transit start
 
-// The viewer preview data consists of line segments and a UV map
-pub fn generate_viewer_data(input: &[BlenderData]) -> (Vec<(f32, f32, f32)>, Vec<Hsl>) {
-    let mut poly_points = vec![];
-    let mut uv_colors = vec![];
+                    add_delay(&mut event_set, config.point_delay_ms);
 
-    // Apply transformations to the parsed data
-    for spline_to_process in input {
-        match &spline_to_process {
-            BlenderData::PolySpline(s) => {
-                // Calculate movements to follow the line/spline
-                for geometry in s.points.windows(BlenderPoly::get_recommended_window_size()) {
-                    let geom: [BlenderPoint3; 2] = [geometry[0].into_bp3(), geometry[1].into_bp3()];
+                    event_set.reset_barrier_id();
 
-                    poly_points.extend(vertex_from_spline(1, &geom));
+                    event_set.add_delta_action(trail);
+
+                    let p_color: Vec<(f32, f32, f32)> = p
+                        .color
+                        .iter()
+                        .map(|c| delta_led_from_hsl(&config.led_color(c)))
+                        .collect();
+
+                    // Trails start and finish at rest, so they fade in and out as the effector speeds up and slows down
+                    match p_color.first() {
+                        Some(&led) if config.speed_compensation => {
+                            let trail_light = Keyframe {
+                                from: led,
+                                to: led,
+                                duration: move_duration,
+                            };
+                            let spans: Vec<SpeedSpan> = velocities
+                                .into_iter()
+                                .map(|velocity| SpeedSpan {
+                                    start: 0.0,
+                                    velocity,
+                                })
+                                .collect();
+
+                            for keyframe in compensate_speed(vec![trail_light], 0, &spans, config) {
+                                event_set.add_light_action(Fade {
+                                    animation_type: LightAnimationType::LinearFade,
+                                    id: 0,
+                                    duration: keyframe.duration,
+                                    points: vec![keyframe.from, keyframe.to],
+                                });
+                            }
+                        }
+                        _ => event_set.add_light_action(Fade {
+                            animation_type: LightAnimationType::ConstantOn,
+                            id: 0,
+                            duration: move_duration,
+                            points: p_color,
+                        }),
+                    }
                 }
-
-                uv_colors.extend(s.color.clone());
-            }
-            BlenderData::NURBSSpline(s) => {
-                println!("NURBS unavailable in preview...");
-                poly_points.push((0.0, 0.0, 0.0));
-                poly_points.push((0.0, 0.0, 0.0));
-                uv_colors.push(Hsl::new(0.0, 0.0, 50.0, Option::from(1.0)));
-                uv_colors.push(Hsl::new(0.0, 0.0, 50.0, Option::from(1.0)));
-            }
-            BlenderData::Particles(p) => {
-                println!("Particles unavailable in preview...");
-                poly_points.push((0.0, 0.0, 0.0));
-                poly_points.push((0.0, 0.0, 0.0));
-
-                uv_colors.push(Hsl::new(0.0, 0.0, 50.0, Option::from(1.0)));
-                uv_colors.push(Hsl::new(0.0, 0.0, 50.0, Option::from(1.0)));
             }
         }
     }
 
-    (poly_points, uv_colors)
+    Ok(event_set)
+}
+
+fn first_point(points: &[BlenderPoint4]) -> VortexResult<BlenderPoint3> {
+    points
+        .first()
+        .map(|p| p.into_bp3())
+        .ok_or_else(|| VortexError::EmptyGeometry("Spline has no points".to_string()))
 }
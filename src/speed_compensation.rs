@@ -0,0 +1,311 @@
+use colorsys::{Hsl, Rgb};
+
+use crate::config::PlannerConfig;
+use crate::fade_space::Keyframe;
+use crate::motion_profile::MoveVelocity;
+
+// The drive gamma assumed without an LED profile, close to sRGB
+const DEFAULT_GAMMA: f64 = 2.2;
+
+// How many points along a keyframe are checked against the intended dimming
+const KEYFRAME_SAMPLES: usize = 4;
+
+// Keyframes are halved at most this many times between changes in how the effector accelerates
+const MAX_SPLITS: u32 = 4;
+
+// A move's speed through the spline, starting a time in ms after the spline started
+pub(crate) struct SpeedSpan {
+    pub start: f32,
+    pub velocity: MoveVelocity,
+}
+
+// In a long exposure a stroke looks as bright as the LED's light divided by how fast it's moving,
+// so keyframes are dimmed wherever the effector is slower than the reference speed.
+// Keyframes are split wherever the effector changes between speeding up, cruising and slowing down,
+// then halved until the firmware's fade follows the dimming within tolerance.
+pub(crate) fn compensate_speed(
+    keyframes: Vec<Keyframe>,
+    start: u32,
+    spans: &[SpeedSpan],
+    config: &PlannerConfig,
+) -> Vec<Keyframe> {
+    let mut cuts: Vec<u32> = spans
+        .iter()
+        .flat_map(|span| {
+            let [accelerated, cruised] = span.velocity.phase_times();
+            [
+                span.start + accelerated,
+                span.start + cruised,
+                span.start + span.velocity.duration(),
+            ]
+        })
+        .map(|time| time.round() as u32)
+        .collect();
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let mut compensated = vec![];
+    let mut time = start;
+
+    for keyframe in keyframes {
+        let end = time + keyframe.duration;
+        let at = |t: u32| {
+            if keyframe.duration > 0 {
+                f64::from(t - time) / f64::from(keyframe.duration)
+            } else {
+                0.0
+            }
+        };
+
+        let mut piece_start = time;
+        for piece_end in cuts
+            .iter()
+            .copied()
+            .filter(|&cut| time < cut && cut < end)
+            .chain(std::iter::once(end))
+        {
+            let piece = Keyframe {
+                from: lerp_tuple(keyframe.from, keyframe.to, at(piece_start)),
+                to: lerp_tuple(keyframe.from, keyframe.to, at(piece_end)),
+                duration: piece_end - piece_start,
+            };
+            compensated.extend(dim_keyframe(piece, piece_start, spans, config));
+            piece_start = piece_end;
+        }
+
+        time = end;
+    }
+
+    compensated
+}
+
+// Dim a keyframe where the effector is accelerating smoothly, halving it while the firmware's
+// linear fade strays too far from the dimming between its ends
+fn dim_keyframe(
+    keyframe: Keyframe,
+    start: u32,
+    spans: &[SpeedSpan],
+    config: &PlannerConfig,
+) -> Vec<Keyframe> {
+    let duration = f64::from(keyframe.duration);
+    let intended = |t: f64| {
+        let speed = speed_at(spans, start as f32 + (t * duration) as f32);
+        dim(
+            lerp_tuple(keyframe.from, keyframe.to, t),
+            brightness_scale(speed, config),
+            config,
+        )
+    };
+
+    let mut fractions = vec![0.0];
+
+    // Pieces still to check, leftmost last, with how many times they've been halved
+    let mut pending = vec![(1.0, 0)];
+    while let Some((t1, splits)) = pending.pop() {
+        let t0 = fractions[fractions.len() - 1];
+        let (from, to) = (intended(t0), intended(t1));
+
+        let strays = (1..KEYFRAME_SAMPLES).any(|k| {
+            let f = k as f64 / KEYFRAME_SAMPLES as f64;
+            let wanted = hsl_from_tuple(intended(t0 + (t1 - t0) * f));
+            let firmware = hsl_from_tuple(lerp_tuple(from, to, f));
+            config.color_metric.distance(&wanted, &firmware) > config.cluster_threshold
+        });
+
+        // Pieces can't be shorter than the firmware's 1 ms timing
+        let too_short = (t1 - t0) * duration < 2.0;
+
+        if strays && !too_short && splits < MAX_SPLITS {
+            pending.push((t1, splits + 1));
+            pending.push(((t0 + t1) / 2.0, splits + 1));
+        } else {
+            fractions.push(t1);
+        }
+    }
+
+    let time_at = |t: f64| (t * duration).round() as u32;
+    fractions
+        .windows(2)
+        .map(|pair| Keyframe {
+            from: intended(pair[0]),
+            to: intended(pair[1]),
+            duration: time_at(pair[1]) - time_at(pair[0]),
+        })
+        .collect()
+}
+
+// The effector's speed in mm/s some time in ms after the spline started
+fn speed_at(spans: &[SpeedSpan], time: f32) -> f32 {
+    spans
+        .iter()
+        .find(|span| time <= span.start + span.velocity.duration())
+        .or_else(|| spans.last())
+        .map(|span| span.velocity.speed_at(time - span.start))
+        .unwrap_or(0.0)
+}
+
+// How much of the LED's light to use for a stroke drawn at a speed, it can't be made brighter
+fn brightness_scale(speed: f32, config: &PlannerConfig) -> f64 {
+    f64::from(speed / config.reference_speed).clamp(0.0, 1.0)
+}
+
+// Scale the LED's light by dimming each channel's drive level by its own gamma, so the light's
+// colour is unchanged even though the drive levels' hue can shift
+fn dim(led: (f32, f32, f32), scale: f64, config: &PlannerConfig) -> (f32, f32, f32) {
+    if scale >= 1.0 {
+        return led;
+    }
+
+    let (gamma, cutoff) = match &config.led_profile {
+        Some(profile) => (profile.gamma, profile.min_brightness),
+        None => ([DEFAULT_GAMMA; 3], 0.0),
+    };
+
+    let rgb = Rgb::from(&hsl_from_tuple(led));
+    let channels = [rgb.get_red(), rgb.get_green(), rgb.get_blue()];
+    let drive = [0, 1, 2].map(|c| channels[c] / 255.0 * scale.powf(1.0 / gamma[c]));
+
+    // Dimmed below the LED's cutoff, it's switched off.
+    // It keeps its hue and saturation, so fades to and from off don't change colour.
+    if drive.iter().all(|&d| d < cutoff || d <= f64::EPSILON) {
+        return (led.0, led.1, 0.0);
+    }
+
+    let dimmed = Hsl::from(&Rgb::new(
+        drive[0] * 255.0,
+        drive[1] * 255.0,
+        drive[2] * 255.0,
+        None,
+    ));
+    let (saturation, lightness) = (
+        dimmed.get_saturation() / 100.0,
+        dimmed.get_lightness() / 100.0,
+    );
+
+    // Greys keep their hue, and hues stay on the same side of red so fades don't sweep the colour wheel.
+    // Greys and white can pick up a hue from the channels' gammas, which is taken as it is.
+    let achromatic = led.1 <= f32::EPSILON || led.2 >= 1.0 - f32::EPSILON;
+    let hue = if saturation <= f64::EPSILON {
+        f64::from(led.0)
+    } else if achromatic {
+        dimmed.get_hue() / 360.0
+    } else {
+        let hue = dimmed.get_hue() / 360.0;
+        let from_original = |h: &f64| (h - f64::from(led.0)).abs();
+        [hue - 1.0, hue, hue + 1.0]
+            .iter()
+            .copied()
+            .min_by(|a, b| from_original(a).total_cmp(&from_original(b)))
+            .unwrap_or(hue)
+            .clamp(0.0, 1.0)
+    };
+
+    (hue as f32, saturation as f32, lightness as f32)
+}
+
+fn hsl_from_tuple(led: (f32, f32, f32)) -> Hsl {
+    Hsl::new(
+        f64::from(led.0) * 360.0,
+        f64::from(led.1) * 100.0,
+        f64::from(led.2) * 100.0,
+        None,
+    )
+}
+
+// The firmware's interpolation between two LED tuples
+fn lerp_tuple(from: (f32, f32, f32), to: (f32, f32, f32), t: f64) -> (f32, f32, f32) {
+    let t = t as f32;
+    (
+        from.0 + (to.0 - from.0) * t,
+        from.1 + (to.1 - from.1) * t,
+        from.2 + (to.2 - from.2) * t,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::LedProfile;
+    use crate::export_types::*;
+    use crate::motion_profile::MotionProfile;
+
+    fn drive_levels(led: (f32, f32, f32)) -> [f64; 3] {
+        let rgb = Rgb::from(&hsl_from_tuple(led));
+        [rgb.get_red(), rgb.get_green(), rgb.get_blue()].map(|c| c / 255.0)
+    }
+
+    #[test]
+    fn each_channel_is_dimmed_by_its_own_gamma() {
+        let config = PlannerConfig {
+            led_profile: Some(LedProfile {
+                gamma: [1.0, 2.0, 4.0],
+                ..LedProfile::default()
+            }),
+            ..PlannerConfig::default()
+        };
+
+        let white = (0.0, 0.0, 1.0);
+        let dimmed = drive_levels(dim(white, 0.25, &config));
+
+        for (level, expected) in dimmed.iter().zip(&[0.25, 0.5, 0.25_f64.powf(0.25)]) {
+            assert!((level - expected).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn full_speed_is_left_alone() {
+        let config = PlannerConfig::default();
+        let red = (0.0, 1.0, 0.5);
+
+        assert_eq!(dim(red, brightness_scale(400.0, &config), &config), red);
+        assert_eq!(dim(red, 1.0, &config), red);
+    }
+
+    #[test]
+    fn strokes_are_dimmed_while_speeding_up_and_slowing_down() {
+        let config = PlannerConfig {
+            speed_compensation: true,
+            ..PlannerConfig::default()
+        };
+        let mut stroke = [Motion {
+            id: 0,
+            reference: MotionReferenceFrame::Absolute,
+            motion_type: MotionInterpolationType::Line,
+            duration: 0,
+            points: vec![(0.0, 0.0, 0.0), (100.0, 0.0, 0.0)],
+        }];
+        let velocities = MotionProfile::new(&config).apply(&mut stroke).unwrap();
+        let spans: Vec<SpeedSpan> = velocities
+            .into_iter()
+            .map(|velocity| SpeedSpan {
+                start: 0.0,
+                velocity,
+            })
+            .collect();
+
+        let red = (0.0, 1.0, 0.5);
+        let keyframes = compensate_speed(
+            vec![Keyframe {
+                from: red,
+                to: red,
+                duration: stroke[0].duration,
+            }],
+            0,
+            &spans,
+            &config,
+        );
+
+        // Off at rest, full colour while cruising, with the timing unchanged
+        assert!(keyframes.len() > 3);
+        assert_eq!(keyframes[0].from.2, 0.0);
+        assert_eq!(keyframes[keyframes.len() - 1].to.2, 0.0);
+        assert!(keyframes.iter().any(|k| k.from == red && k.to == red));
+        let total: u32 = keyframes.iter().map(|k| k.duration).sum();
+        assert_eq!(total, stroke[0].duration);
+
+        // Neighbouring keyframes join up
+        for pair in keyframes.windows(2) {
+            assert_eq!(pair[0].to, pair[1].from);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use total_perspective_vortex::export_types::AnimationMetadata;
+use total_perspective_vortex::*;
+
+// Watches the frame and collection folders, replanning collections as Blender re-exports them.
+// The export script writes several files per collection, so changes are collected until the
+// folder tree has been quiet for the debounce period, then each affected collection is replanned once.
+pub fn watch(
+    pipeline: &Pipeline,
+    input_root: &Path,
+    summary: &mut AnimationMetadata,
+    debounce: Duration,
+) -> VortexResult<()> {
+    let root = fs::canonicalize(input_root).map_err(|e| VortexError::io(input_root, e))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(|e| watch_error(&root, e))?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(|e| watch_error(&root, e))?;
+
+    println!(
+        "\nWatching {} for changes, press Ctrl+C to stop",
+        input_root.display()
+    );
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut event = match receiver.recv() {
+            Ok(event) => event,
+            Err(_) => return Ok(()),
+        };
+
+        // Keep collecting until no more changes arrive within the debounce period
+        loop {
+            match event {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    for path in &event.paths {
+                        changed.extend(affected_collections(&root, path, summary));
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("{}", watch_error(&root, e)),
+            }
+
+            event = match receiver.recv_timeout(debounce) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+        }
+
+        if changed.is_empty() {
+            continue;
+        }
+
+        // Earlier frames first, so collections which follow the previous frame see its new order
+        pipeline.progress.started(changed.len());
+        let planned: usize = changed
+            .iter()
+            .map(|(_, folder)| pipeline.replan_collection(summary, folder))
+            .sum();
+
+        let summary_path = pipeline.write_summary(summary)?;
+        println!(
+            "\nReplanned {} collections, updated {}",
+            planned,
+            summary_path.display()
+        );
+    }
+}
+
+// The collection folders a changed path belongs to, sorted by frame number.
+// A frame folder which was added or removed affects every collection in it. Anything else,
+// like the toolpaths written next to the collections when the output folder is the input folder, is ignored.
+fn affected_collections(
+    root: &Path,
+    path: &Path,
+    summary: &AnimationMetadata,
+) -> Vec<(i32, PathBuf)> {
+    let relative: Vec<PathBuf> = match path.strip_prefix(root) {
+        Ok(relative) => relative
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(PathBuf::from(name)),
+                _ => None,
+            })
+            .collect(),
+        Err(_) => return vec![],
+    };
+
+    let frame_folder = match relative.first() {
+        Some(name) => root.join(name),
+        None => return vec![],
+    };
+    let frame = match frame_number(&frame_folder) {
+        Some(frame) => frame,
+        None => return vec![],
+    };
+
+    match relative.get(1) {
+        // Something inside a collection folder
+        Some(name) if relative.len() > 2 => vec![(frame, frame_folder.join(name))],
+
+        // A collection folder itself, or a file written next to them
+        Some(name) => {
+            let folder = frame_folder.join(name);
+            let planned = summary_collections(summary, frame).any(|c| Path::new(&c) == name);
+            if folder.is_dir() || planned {
+                vec![(frame, folder)]
+            } else {
+                vec![]
+            }
+        }
+
+        // A frame folder, both the collections in it now and any which were planned before
+        None => {
+            let mut folders: BTreeSet<PathBuf> = summary_collections(summary, frame)
+                .map(|name| frame_folder.join(name))
+                .collect();
+
+            if let Ok(entries) = fs::read_dir(&frame_folder) {
+                folders.extend(
+                    entries
+                        .filter_map(|e| e.ok())
+                        .map(|e| e.path())
+                        .filter(|p| p.is_dir()),
+                );
+            }
+
+            folders.into_iter().map(|folder| (frame, folder)).collect()
+        }
+    }
+}
+
+// Names of the collections planned or failed in a frame, according to the summary
+fn summary_collections(
+    summary: &AnimationMetadata,
+    frame: i32,
+) -> impl Iterator<Item = String> + '_ {
+    summary
+        .frames
+        .iter()
+        .filter(move |f| f.frame_num == frame)
+        .flat_map(|f| {
+            f.collections
+                .iter()
+                .map(|c| c.name.clone())
+                .chain(f.failures.iter().map(|failure| failure.name.clone()))
+        })
+}
+
+fn watch_error(root: &Path, error: notify::Error) -> VortexError {
+    VortexError::Config(format!("Couldn't watch {}: {}", root.display(), error))
+}